@@ -0,0 +1,12 @@
+use std::path::PathBuf;
+use crate::protocol::negotiation::AlgorithmPreferences;
+use crate::protocol::transport::RekeyLimits;
+
+/// Client settings for a connection
+#[derive(Debug, Clone, Default)]
+pub struct ClientConfig {
+    pub algorithms: AlgorithmPreferences,
+    pub rekey: RekeyLimits,
+    /// Private key tried before the keys in ~/.ssh_edu, e.g. ~/.ssh/id_ed25519
+    pub identity: Option<PathBuf>,
+}
@@ -1,15 +1,42 @@
 use std::net::TcpStream;
-use std::io::{self, Write};
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
 use anyhow::{Result, Context};
-use crate::protocol::version::{send_version_string, receive_version_string, negotiate_version};
-use crate::protocol::key_exchange::client_key_exchange;
-use crate::protocol::server_auth::receive_and_verify_host_key;
-use crate::protocol::client_auth::{send_auth_request, AuthMethod};
-use crate::protocol::session::{Session, negotiate_algorithms};
+use crate::protocol::version::{
+    send_version_string, receive_version_string, negotiate_version, CLIENT_VERSION,
+};
+use crate::protocol::key_exchange::{KexContext, VersionStrings};
+use crate::crypto::keys::{KeyAlgorithm, KeyPair, KeyRole};
+use crate::protocol::client_auth::{
+    public_key_method, send_auth_request, send_public_key_auth, AuthMethod, AuthOutcome,
+};
+use crate::protocol::messages::{
+    SSH_DISCONNECT_BY_APPLICATION, SSH_DISCONNECT_KEY_EXCHANGE_FAILED,
+    SSH_DISCONNECT_NO_MORE_AUTH_METHODS_AVAILABLE, SSH_DISCONNECT_PROTOCOL_ERROR,
+    SSH_DISCONNECT_PROTOCOL_VERSION_NOT_SUPPORTED, SSH_DISCONNECT_SERVICE_NOT_AVAILABLE,
+};
+use crate::protocol::server_auth::{known_hosts_path, prefer_known_host_keys};
+use crate::protocol::service::{request_service, USERAUTH_SERVICE};
+use crate::protocol::session::Session;
+use crate::client::config::ClientConfig;
+use crate::protocol::transport::{PeerDisconnected, Transport};
 use crate::utils::stream::ReadWrite;
 
-/// Connect to SSH server
+/// Connect to SSH server with default settings
 pub fn connect(host: &str, port: u16, username: &str) -> Result<()> {
+    connect_with_config(host, port, username, &ClientConfig::default())
+}
+
+/// Connect to SSH server with the given settings
+pub fn connect_with_config(
+    host: &str,
+    port: u16,
+    username: &str,
+    config: &ClientConfig,
+) -> Result<()> {
+    config.algorithms.validate()
+        .context("Invalid algorithm configuration")?;
+
     let address = format!("{}:{}", host, port);
     println!("Connecting to {}...", address);
     
@@ -24,33 +51,35 @@ pub fn connect(host: &str, port: u16, username: &str) -> Result<()> {
     println!("\n=== Phase 2: Protocol Version Exchange ===");
     let server_version = receive_version_string(&mut *stream_ref)?;
     send_version_string(&mut *stream_ref, false)?;
-    let client_version = "SSH-2.0-EduSSH-Client-1.0";
-    negotiate_version(client_version, &server_version)?;
-    
-    // Phase 3: Key Exchange
-    println!("\n=== Phase 3: Key Exchange ===");
-    let session_keys = client_key_exchange(&mut *stream_ref)?;
+    let mut transport = Transport::new(stream_ref);
+    negotiate_version(CLIENT_VERSION, &server_version)
+        .or_else(|e| transport.abort(SSH_DISCONNECT_PROTOCOL_VERSION_NOT_SUPPORTED, e))?;
+    let versions = VersionStrings {
+        client: CLIENT_VERSION.to_string(),
+        server: server_version,
+    };
     
-    // Phase 4: Server Authentication
-    println!("\n=== Phase 4: Server Authentication ===");
-    receive_and_verify_host_key(&mut *stream_ref, host)?;
+    // Phase 3 & 4: Key Exchange and Server Authentication (the host key is
+    // verified through its signature over the exchange hash)
+    println!("\n=== Phase 3 & 4: Key Exchange and Server Authentication ===");
+    let mut preferences = config.algorithms.clone();
+    let known_hosts = known_hosts_path()?;
+    prefer_known_host_keys(&known_hosts, host, &mut preferences.host_key)?;
+    let context = KexContext::client(versions, preferences, host, known_hosts);
+    transport.configure_key_exchange(context, config.rekey);
+    let kex = transport.key_exchange()
+        .or_else(|e| transport.abort(SSH_DISCONNECT_KEY_EXCHANGE_FAILED, e))?;
     
     // Phase 5: Client Authentication
     println!("\n=== Phase 5: Client Authentication ===");
-    // Try password authentication first (simplified)
-    println!("Attempting password authentication...");
-    print!("Password: ");
-    io::stdout().flush()?;
-    let mut password = String::new();
-    io::stdin().read_line(&mut password)?;
-    let password = password.trim().to_string();
-    
-    send_auth_request(&mut *stream_ref, username, &AuthMethod::Password(password))?;
+    request_service(&mut transport, USERAUTH_SERVICE)
+        .or_else(|e| transport.abort(SSH_DISCONNECT_SERVICE_NOT_AVAILABLE, e))?;
+    authenticate(&mut transport, &kex.session_id, &kex.host_key, username, config)
+        .or_else(|e| transport.abort(SSH_DISCONNECT_NO_MORE_AUTH_METHODS_AVAILABLE, e))?;
     
     // Phase 6: Session Establishment
     println!("\n=== Phase 6: Session Establishment ===");
-    negotiate_algorithms()?;
-    let mut session = Session::new(session_keys)?;
+    let mut session = Session::new(&kex.algorithms);
     
     println!("\n=== SSH Session Established ===");
     println!("Connected to {} as {}", host, username);
@@ -71,24 +100,135 @@ pub fn connect(host: &str, port: u16, username: &str) -> Result<()> {
         }
         
         if input == "exit" {
+            transport.disconnect(SSH_DISCONNECT_BY_APPLICATION, "User closed the session")?;
             break;
         }
         
-        session.send_encrypted(&mut *stream_ref, input.as_bytes())?;
+        session.send_data(&mut transport, input.as_bytes())?;
         
-        match session.receive_encrypted(&mut *stream_ref) {
+        match session.receive_data(&mut transport) {
             Ok(response) => {
                 let message = String::from_utf8_lossy(&response);
                 println!("{}", message);
             }
             Err(e) => {
-                println!("Error receiving response: {}", e);
+                if let Some(PeerDisconnected(disconnect)) = e.downcast_ref::<PeerDisconnected>() {
+                    println!("Server disconnected: {}", disconnect);
+                } else {
+                    println!("Error receiving response: {}", e);
+                    let _ = transport.disconnect(SSH_DISCONNECT_PROTOCOL_ERROR, &e.to_string());
+                }
                 break;
             }
         }
     }
     
+    session.print_stats(&transport);
     println!("Disconnected");
     Ok(())
 }
 
+/// Password prompts before giving up, as in OpenSSH
+const PASSWORD_PROMPTS: u32 = 3;
+
+/// Authenticate as `username` (client side). A `none` request asks which
+/// methods the server accepts; then, as long as the server lists them, the
+/// user keys are tried in turn and then a password is asked for. The server
+/// may require several methods, reporting each completed one as a partial
+/// success.
+fn authenticate(
+    transport: &mut Transport,
+    session_id: &[u8],
+    host_key: &[u8],
+    username: &str,
+    config: &ClientConfig,
+) -> Result<()> {
+    let mut key_paths = user_key_paths(config)?.into_iter();
+    let mut password_prompts = 0;
+    let mut outcome = send_auth_request(transport, username, &AuthMethod::None)?;
+    loop {
+        let failure = match outcome {
+            AuthOutcome::Success => return Ok(()),
+            AuthOutcome::Failure(failure) => failure,
+        };
+        let can_continue = |method: &str| failure.methods.iter().any(|name| name == method);
+
+        // Use the next user key the server may accept, else a password
+        let user_key = if can_continue("publickey") {
+            next_user_key(transport, host_key, &mut key_paths)?
+        } else {
+            None
+        };
+        outcome = match user_key {
+            Some((key, method)) => send_public_key_auth(transport, session_id, username, &method, &key)?,
+            None if can_continue("password") && password_prompts < PASSWORD_PROMPTS => {
+                password_prompts += 1;
+                println!("Attempting password authentication...");
+                let password = read_secret("Password: ")?;
+                send_auth_request(transport, username, &AuthMethod::Password(password))?
+            }
+            None => anyhow::bail!(
+                "Authentication failed (server accepts: {})",
+                failure.methods.join(",")
+            ),
+        };
+    }
+}
+
+/// Keys for public key authentication: the configured identity, then the
+/// user keys in ~/.ssh_edu (`user_key`, `user_key_ecdsa_nistp256`, ...)
+fn user_key_paths(config: &ClientConfig) -> Result<Vec<PathBuf>> {
+    let mut key_paths = Vec::new();
+    key_paths.extend(config.identity.clone());
+    for algorithm in KeyAlgorithm::ALL {
+        let key_path = KeyRole::User.path(algorithm)?;
+        if key_path.exists() {
+            key_paths.push(key_path);
+        }
+    }
+    Ok(key_paths)
+}
+
+/// The next key of `key_paths` whose type the server accepts. Keys that
+/// cannot be loaded (unreadable, unsupported, wrong passphrase) are skipped
+/// so that the remaining keys and password authentication can still be tried.
+fn next_user_key(
+    transport: &Transport,
+    host_key: &[u8],
+    key_paths: &mut impl Iterator<Item = PathBuf>,
+) -> Result<Option<(KeyPair, AuthMethod)>> {
+    for key_path in key_paths {
+        let user_key = match load_user_key(&key_path) {
+            Ok(user_key) => user_key,
+            Err(e) => {
+                println!("Skipping key {}: {:#}", key_path.display(), e);
+                continue;
+            }
+        };
+        if let Some(method) = public_key_method(transport.peer_extensions(), &user_key.public_key(), host_key) {
+            return Ok(Some((user_key, method)));
+        }
+    }
+    Ok(None)
+}
+
+/// Load a user key, asking for its passphrase if it is encrypted
+fn load_user_key(key_path: &Path) -> Result<KeyPair> {
+    if !KeyPair::needs_passphrase(key_path)? {
+        return KeyPair::load(key_path);
+    }
+    let passphrase = read_secret(&format!("Enter passphrase for key {}: ", key_path.display()))?;
+    KeyPair::load_with_passphrase(key_path, Some(&passphrase))
+}
+
+/// Read a passphrase or password, without echo when stdin is a terminal
+fn read_secret(prompt: &str) -> Result<String> {
+    if io::stdin().is_terminal() {
+        return Ok(rpassword::prompt_password(prompt)?);
+    }
+    print!("{}", prompt);
+    io::stdout().flush()?;
+    let mut secret = String::new();
+    io::stdin().read_line(&mut secret)?;
+    Ok(secret.trim_end_matches(['\r', '\n']).to_string())
+}
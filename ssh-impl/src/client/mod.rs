@@ -1,4 +1,5 @@
+pub mod config;
 pub mod connection;
 
-pub use connection::connect;
-
+pub use config::ClientConfig;
+pub use connection::{connect, connect_with_config};
@@ -0,0 +1,262 @@
+use std::io::Read;
+use aes::{Aes128, Aes256};
+use anyhow::{Result, Context};
+use ctr::cipher::{KeyIvInit, StreamCipher};
+use crate::crypto::dh::DirectionKeys;
+use crate::crypto::encryption::PacketCipher;
+use crate::crypto::mac::PacketMac;
+use crate::utils::packet::{check_packet_length, Alignment};
+
+type Aes128Ctr = ctr::Ctr128BE<Aes128>;
+type Aes256Ctr = ctr::Ctr128BE<Aes256>;
+
+/// Length of the `packet_length` field
+const LENGTH_FIELD_SIZE: usize = 4;
+
+/// AES in counter mode (RFC 4344 §4). The keystream runs on across packets.
+enum AesCtr {
+    Aes128(Box<Aes128Ctr>),
+    Aes256(Box<Aes256Ctr>),
+}
+
+impl AesCtr {
+    fn apply_keystream(&mut self, data: &mut [u8]) {
+        match self {
+            AesCtr::Aes128(cipher) => cipher.apply_keystream(data),
+            AesCtr::Aes256(cipher) => cipher.apply_keystream(data),
+        }
+    }
+}
+
+/// aes128-ctr / aes256-ctr with a separate HMAC for one direction of the
+/// connection
+pub struct AesCtrCipher {
+    cipher: AesCtr,
+    mac: PacketMac,
+}
+
+impl AesCtrCipher {
+    /// AES block size; packets are aligned to it
+    pub const BLOCK_SIZE: usize = 16;
+
+    /// Create the cipher and MAC from one direction's derived keys
+    pub fn new(cipher: &str, mac: &str, keys: &DirectionKeys) -> Result<Self> {
+        let key = &keys.encryption_key;
+        let iv = &keys.iv;
+        let cipher = match cipher {
+            "aes128-ctr" => AesCtr::Aes128(Box::new(
+                Aes128Ctr::new_from_slices(key, iv)
+                    .map_err(|_| anyhow::anyhow!("Invalid aes128-ctr key or IV length"))?,
+            )),
+            "aes256-ctr" => AesCtr::Aes256(Box::new(
+                Aes256Ctr::new_from_slices(key, iv)
+                    .map_err(|_| anyhow::anyhow!("Invalid aes256-ctr key or IV length"))?,
+            )),
+            other => anyhow::bail!("Unsupported cipher: {}", other),
+        };
+
+        Ok(Self {
+            cipher,
+            mac: PacketMac::new(mac, &keys.integrity_key)?,
+        })
+    }
+
+    /// encrypt-and-MAC: decrypt the first block to learn the length, then the
+    /// rest, and check the MAC over the plaintext packet
+    fn open_encrypt_and_mac(&mut self, sequence_number: u32, reader: &mut dyn Read) -> Result<Vec<u8>> {
+        let mut packet = vec![0u8; Self::BLOCK_SIZE];
+        reader.read_exact(&mut packet)
+            .context("Failed to read packet length")?;
+        self.cipher.apply_keystream(&mut packet);
+
+        let packet_length = u32::from_be_bytes(packet[..LENGTH_FIELD_SIZE].try_into()?) as usize;
+        check_packet_length(packet_length, Self::BLOCK_SIZE, Alignment::WithLength, self.mac.length())?;
+
+        packet.resize(LENGTH_FIELD_SIZE + packet_length, 0);
+        reader.read_exact(&mut packet[Self::BLOCK_SIZE..])
+            .context("Failed to read packet body")?;
+        self.cipher.apply_keystream(&mut packet[Self::BLOCK_SIZE..]);
+
+        let mut mac = vec![0u8; self.mac.length()];
+        reader.read_exact(&mut mac)
+            .context("Failed to read packet MAC")?;
+        self.mac.verify(sequence_number, &packet, &mac)?;
+
+        packet.drain(..LENGTH_FIELD_SIZE);
+        Ok(packet)
+    }
+
+    /// encrypt-then-MAC: the length is in the clear and the MAC over the
+    /// encrypted packet is checked before decrypting
+    fn open_encrypt_then_mac(&mut self, sequence_number: u32, reader: &mut dyn Read) -> Result<Vec<u8>> {
+        let mut length_field = [0u8; LENGTH_FIELD_SIZE];
+        reader.read_exact(&mut length_field)
+            .context("Failed to read packet length")?;
+        let packet_length = u32::from_be_bytes(length_field) as usize;
+        check_packet_length(packet_length, Self::BLOCK_SIZE, Alignment::AfterLength, self.mac.length())?;
+
+        let mut packet = vec![0u8; LENGTH_FIELD_SIZE + packet_length];
+        packet[..LENGTH_FIELD_SIZE].copy_from_slice(&length_field);
+        reader.read_exact(&mut packet[LENGTH_FIELD_SIZE..])
+            .context("Failed to read packet body")?;
+
+        let mut mac = vec![0u8; self.mac.length()];
+        reader.read_exact(&mut mac)
+            .context("Failed to read packet MAC")?;
+        self.mac.verify(sequence_number, &packet, &mac)?;
+
+        let mut body = packet.split_off(LENGTH_FIELD_SIZE);
+        self.cipher.apply_keystream(&mut body);
+        Ok(body)
+    }
+}
+
+impl PacketCipher for AesCtrCipher {
+    fn block_size(&self) -> usize {
+        Self::BLOCK_SIZE
+    }
+
+    fn alignment(&self) -> Alignment {
+        if self.mac.is_encrypt_then_mac() {
+            Alignment::AfterLength
+        } else {
+            Alignment::WithLength
+        }
+    }
+
+    fn mac_length(&self) -> usize {
+        self.mac.length()
+    }
+
+    fn seal(&mut self, sequence_number: u32, frame: &mut Vec<u8>) -> Result<()> {
+        let mac = if self.mac.is_encrypt_then_mac() {
+            self.cipher.apply_keystream(&mut frame[LENGTH_FIELD_SIZE..]);
+            self.mac.sign(sequence_number, frame)
+        } else {
+            let mac = self.mac.sign(sequence_number, frame);
+            self.cipher.apply_keystream(frame);
+            mac
+        };
+
+        frame.extend_from_slice(&mac);
+        Ok(())
+    }
+
+    fn open(&mut self, sequence_number: u32, reader: &mut dyn Read) -> Result<Vec<u8>> {
+        if self.mac.is_encrypt_then_mac() {
+            self.open_encrypt_then_mac(sequence_number, reader)
+        } else {
+            self.open_encrypt_and_mac(sequence_number, reader)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::mac::mac_key_length;
+    use crate::utils::packet::Packet;
+
+    // NIST SP 800-38A §F.5.1 (CTR-AES128) and §F.5.5 (CTR-AES256)
+    const INITIAL_COUNTER: &str = "f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff";
+    const PLAINTEXT: &str = "6bc1bee22e409f96e93d7e117393172aae2d8a571e03ac9c9eb76fac45af8e51\
+                             30c81c46a35ce411e5fbc1191a0a52eff69f2445df4f9b17ad2b417be66c3710";
+    const VECTORS: [(&str, &str, &str); 2] = [
+        (
+            "aes128-ctr",
+            "2b7e151628aed2a6abf7158809cf4f3c",
+            "874d6191b620e3261bef6864990db6ce9806f66b7970fdff8617187bb9fffdff\
+             5ae4df3edbd5d35e5b4f09020db03eab1e031dda2fbe03d1792170a0f3009cee",
+        ),
+        (
+            "aes256-ctr",
+            "603deb1015ca71be2b73aef0857d77811f352c073b6108d72d9810a30914dff4",
+            "601ec313775789a5b7a7f504bbf3d228f443e3ca4d62b59aca84e990cacaf5c5\
+             2b0930daa23de94ce87017ba2d84988ddfc9c58db67aada613c2dd08457941a6",
+        ),
+    ];
+
+    fn cipher(name: &str, key: &[u8], mac: &str) -> AesCtrCipher {
+        let keys = DirectionKeys {
+            iv: hex::decode(INITIAL_COUNTER).unwrap(),
+            encryption_key: key.to_vec(),
+            integrity_key: vec![0x42; mac_key_length(mac).unwrap()],
+        };
+        AesCtrCipher::new(name, mac, &keys).unwrap()
+    }
+
+    fn sealed_packet(cipher: &mut AesCtrCipher, sequence_number: u32, payload: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let frame = Packet::new(payload.to_vec())
+            .to_frame_with(cipher.block_size(), cipher.alignment(), cipher.mac_length())
+            .unwrap();
+        let mut wire = frame.clone();
+        cipher.seal(sequence_number, &mut wire).unwrap();
+        (frame, wire)
+    }
+
+    #[test]
+    fn keystream_matches_sp800_38a() {
+        for (name, key, ciphertext) in VECTORS {
+            let mut cipher = cipher(name, &hex::decode(key).unwrap(), "hmac-sha2-256");
+            // The keystream runs on between calls, as it does between packets
+            let mut data = hex::decode(PLAINTEXT).unwrap();
+            let (first, rest) = data.split_at_mut(20);
+            cipher.cipher.apply_keystream(first);
+            cipher.cipher.apply_keystream(rest);
+            assert_eq!(hex::encode(&data), ciphertext, "{}", name);
+        }
+    }
+
+    #[test]
+    fn packets_round_trip_with_either_mac_order() {
+        for mac in ["hmac-sha2-256", "hmac-sha2-512-etm@openssh.com"] {
+            for (name, key, _) in VECTORS {
+                let key = hex::decode(key).unwrap();
+                let mut sender = cipher(name, &key, mac);
+                let mut receiver = cipher(name, &key, mac);
+                for (sequence_number, payload) in [(0u32, &b"first"[..]), (1, &[7u8; 100][..])] {
+                    let (_, wire) = sealed_packet(&mut sender, sequence_number, payload);
+                    let body = receiver.open(sequence_number, &mut wire.as_slice()).unwrap();
+                    assert_eq!(Packet::strip_padding(&body).unwrap(), payload);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn mac_covers_plaintext_or_ciphertext() {
+        let key = hex::decode(VECTORS[1].1).unwrap();
+
+        // MAC-then-encrypt: the length is encrypted and the MAC is over the
+        // plaintext frame
+        let mut sender = cipher("aes256-ctr", &key, "hmac-sha2-256");
+        let (frame, wire) = sealed_packet(&mut sender, 5, b"payload");
+        assert_ne!(wire[..LENGTH_FIELD_SIZE], frame[..LENGTH_FIELD_SIZE]);
+        let mac = PacketMac::new("hmac-sha2-256", &[0x42; 32]).unwrap();
+        assert_eq!(wire[frame.len()..], mac.sign(5, &frame));
+
+        // encrypt-then-MAC: the length stays in the clear and the MAC is
+        // over what was sent
+        let mut sender = cipher("aes256-ctr", &key, "hmac-sha2-256-etm@openssh.com");
+        let (frame, wire) = sealed_packet(&mut sender, 5, b"payload");
+        assert_eq!(wire[..LENGTH_FIELD_SIZE], frame[..LENGTH_FIELD_SIZE]);
+        assert_ne!(wire[LENGTH_FIELD_SIZE..frame.len()], frame[LENGTH_FIELD_SIZE..]);
+        let mac = PacketMac::new("hmac-sha2-256-etm@openssh.com", &[0x42; 32]).unwrap();
+        assert_eq!(wire[frame.len()..], mac.sign(5, &wire[..frame.len()]));
+    }
+
+    #[test]
+    fn rejects_tampered_packets() {
+        let key = hex::decode(VECTORS[0].1).unwrap();
+        for mac in ["hmac-sha2-256", "hmac-sha2-256-etm@openssh.com"] {
+            let (_, wire) = sealed_packet(&mut cipher("aes128-ctr", &key, mac), 0, b"payload");
+            // A flipped bit in the body, the MAC or (for ETM) the clear length
+            for position in [LENGTH_FIELD_SIZE + 1, wire.len() - 1, 3] {
+                let mut tampered = wire.clone();
+                tampered[position] ^= 1;
+                assert!(cipher("aes128-ctr", &key, mac).open(0, &mut tampered.as_slice()).is_err(), "{}", mac);
+            }
+            assert!(cipher("aes128-ctr", &key, mac).open(1, &mut wire.as_slice()).is_err(), "{}", mac);
+        }
+    }
+}
@@ -0,0 +1,242 @@
+use std::io::Read;
+use anyhow::{Result, Context};
+use ring::aead::chacha20_poly1305_openssh::{
+    OpeningKey, SealingKey, KEY_LEN, PACKET_LENGTH_LEN, TAG_LEN,
+};
+use crate::crypto::dh::DirectionKeys;
+use crate::crypto::encryption::PacketCipher;
+use crate::utils::packet::{check_packet_length, Alignment};
+
+/// chacha20-poly1305@openssh.com for one direction of the connection
+/// (OpenSSH PROTOCOL.chacha20poly1305).
+///
+/// The 64-byte key holds two ChaCha20 keys: K_1 encrypts only
+/// `packet_length`, K_2 encrypts the rest of the packet and keys Poly1305,
+/// which authenticates the whole encrypted packet. The packet sequence number
+/// is the nonce, so no IV is derived.
+pub struct ChaCha20Poly1305Cipher {
+    sealing_key: SealingKey,
+    opening_key: OpeningKey,
+}
+
+impl ChaCha20Poly1305Cipher {
+    /// ChaCha20 is a stream cipher; packets only need the minimum alignment
+    pub const BLOCK_SIZE: usize = 8;
+
+    /// Create the cipher from one direction's derived keys
+    pub fn new(keys: &DirectionKeys) -> Result<Self> {
+        let key_material: &[u8; KEY_LEN] = keys.encryption_key.as_slice().try_into()
+            .map_err(|_| anyhow::anyhow!(
+                "Invalid chacha20-poly1305 key length {}",
+                keys.encryption_key.len()
+            ))?;
+        Ok(Self {
+            sealing_key: SealingKey::new(key_material),
+            opening_key: OpeningKey::new(key_material),
+        })
+    }
+}
+
+impl PacketCipher for ChaCha20Poly1305Cipher {
+    fn block_size(&self) -> usize {
+        Self::BLOCK_SIZE
+    }
+
+    fn alignment(&self) -> Alignment {
+        Alignment::AfterLength
+    }
+
+    fn mac_length(&self) -> usize {
+        TAG_LEN
+    }
+
+    fn seal(&mut self, sequence_number: u32, frame: &mut Vec<u8>) -> Result<()> {
+        let mut tag = [0u8; TAG_LEN];
+        self.sealing_key.seal_in_place(sequence_number, frame, &mut tag);
+        frame.extend_from_slice(&tag);
+        Ok(())
+    }
+
+    fn open(&mut self, sequence_number: u32, reader: &mut dyn Read) -> Result<Vec<u8>> {
+        let mut encrypted_length = [0u8; PACKET_LENGTH_LEN];
+        reader.read_exact(&mut encrypted_length)
+            .context("Failed to read packet length")?;
+
+        // Not authenticated yet, but needed to know how much to read. It is
+        // checked against the size limits before any allocation.
+        let packet_length = u32::from_be_bytes(
+            self.opening_key.decrypt_packet_length(sequence_number, encrypted_length),
+        ) as usize;
+        check_packet_length(packet_length, Self::BLOCK_SIZE, Alignment::AfterLength, TAG_LEN)?;
+
+        let mut packet = vec![0u8; PACKET_LENGTH_LEN + packet_length];
+        packet[..PACKET_LENGTH_LEN].copy_from_slice(&encrypted_length);
+        reader.read_exact(&mut packet[PACKET_LENGTH_LEN..])
+            .context("Failed to read packet body")?;
+        let mut tag = [0u8; TAG_LEN];
+        reader.read_exact(&mut tag)
+            .context("Failed to read packet tag")?;
+
+        let body = self.opening_key.open_in_place(sequence_number, &mut packet, &tag)
+            .map_err(|_| anyhow::anyhow!("Packet authentication failed"))?;
+        Ok(body.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::packet::Packet;
+
+    // PROTOCOL.chacha20poly1305 does not publish test vectors. With an
+    // all-zero key and sequence number 0 the construction reduces to plain
+    // ChaCha20 with a zero key and zero nonce, whose keystream is given in
+    // RFC 8439 §A.1: block 0 encrypts packet_length under K_1 and block 1 is
+    // the first block encrypting the rest under K_2.
+    const RFC8439_BLOCK_0: &str = "76b8e0ada0f13d90405d6ae55386bd28bdd219b8a08ded1aa836efcc8b770dc7\
+                                   da41597c5157488d7724e03fb8d84a376a43b8f41518a11cc387b669b2ee6586";
+    const RFC8439_BLOCK_1: &str = "9f07e7be5551387a98ba977c732d080dcb0f29a048e3656912c6533e32ee7aed\
+                                   29b721769ce64e43d57133b074d839d531ed1f28510afb45ace10a1f4b794d6f";
+
+    // Generated with Python's cryptography package following
+    // cipher-chachapoly.c: K_2 is the first half of the key, K_1 the second,
+    // the nonce is the 64-bit sequence number, the Poly1305 key the start of
+    // K_2's block 0 and the payload is encrypted from block 1. Key 0x00..0x3f,
+    // sequence number 7, an SSH_MSG_SERVICE_REQUEST frame with zero padding.
+    const FIXED_FRAME: &str = "0000001806050000000c7373682d7573657261757468000000000000";
+    const FIXED_WIRE: &str = "a39afcb22e4315434e8f592d0440ce83b2fdb25940da30367ee33d83\
+                              6a3430ef1d734e90b2e3b0e4717ba829";
+
+    fn cipher_with_key(key: [u8; KEY_LEN]) -> ChaCha20Poly1305Cipher {
+        let keys = DirectionKeys {
+            iv: Vec::new(),
+            encryption_key: key.to_vec(),
+            integrity_key: Vec::new(),
+        };
+        ChaCha20Poly1305Cipher::new(&keys).unwrap()
+    }
+
+    #[test]
+    fn zero_key_matches_rfc8439_keystream() {
+        let mut cipher = cipher_with_key([0u8; KEY_LEN]);
+        let mut frame = vec![0u8; PACKET_LENGTH_LEN + 64];
+        cipher.seal(0, &mut frame).unwrap();
+
+        let block_0 = hex::decode(RFC8439_BLOCK_0).unwrap();
+        let block_1 = hex::decode(RFC8439_BLOCK_1).unwrap();
+        assert_eq!(&frame[..PACKET_LENGTH_LEN], &block_0[..PACKET_LENGTH_LEN]);
+        assert_eq!(&frame[PACKET_LENGTH_LEN..PACKET_LENGTH_LEN + 64], &block_1[..]);
+        assert_eq!(frame.len(), PACKET_LENGTH_LEN + 64 + TAG_LEN);
+    }
+
+    #[test]
+    fn zero_key_decrypts_length_with_rfc8439_block_0() {
+        let cipher = cipher_with_key([0u8; KEY_LEN]);
+        let block_0 = hex::decode(RFC8439_BLOCK_0).unwrap();
+        let encrypted_length: [u8; PACKET_LENGTH_LEN] = block_0[..PACKET_LENGTH_LEN].try_into().unwrap();
+        assert_eq!(cipher.opening_key.decrypt_packet_length(0, encrypted_length), [0u8; 4]);
+    }
+
+    fn sealed_packet(cipher: &mut ChaCha20Poly1305Cipher, sequence_number: u32, payload: &[u8]) -> Vec<u8> {
+        let mut frame = Packet::new(payload.to_vec())
+            .to_frame_with(cipher.block_size(), cipher.alignment(), cipher.mac_length())
+            .unwrap();
+        cipher.seal(sequence_number, &mut frame).unwrap();
+        frame
+    }
+
+    #[test]
+    fn round_trip() {
+        let key: [u8; KEY_LEN] = std::array::from_fn(|i| i as u8);
+        let mut sender = cipher_with_key(key);
+        let mut receiver = cipher_with_key(key);
+
+        for (sequence_number, payload) in [(3u32, &b"first"[..]), (4, &b"second packet"[..])] {
+            let wire = sealed_packet(&mut sender, sequence_number, payload);
+            let body = receiver.open(sequence_number, &mut wire.as_slice()).unwrap();
+            assert_eq!(Packet::strip_padding(&body).unwrap(), payload);
+        }
+    }
+
+    #[test]
+    fn rejects_tampered_packet() {
+        let key = [7u8; KEY_LEN];
+        let mut sender = cipher_with_key(key);
+        let mut receiver = cipher_with_key(key);
+
+        let mut wire = sealed_packet(&mut sender, 0, b"payload");
+        let last = wire.len() - TAG_LEN - 1;
+        wire[last] ^= 1;
+        assert!(receiver.open(0, &mut wire.as_slice()).is_err());
+    }
+
+    #[test]
+    fn rejects_wrong_sequence_number() {
+        let key = [7u8; KEY_LEN];
+        let mut sender = cipher_with_key(key);
+        let mut receiver = cipher_with_key(key);
+
+        let wire = sealed_packet(&mut sender, 1, b"payload");
+        assert!(receiver.open(2, &mut wire.as_slice()).is_err());
+    }
+
+    #[test]
+    fn fixed_key_matches_openssh_construction() {
+        let key: [u8; KEY_LEN] = std::array::from_fn(|i| i as u8);
+        let mut frame = hex::decode(FIXED_FRAME).unwrap();
+        cipher_with_key(key).seal(7, &mut frame).unwrap();
+        assert_eq!(hex::encode(&frame), FIXED_WIRE);
+
+        let body = cipher_with_key(key).open(7, &mut frame.as_slice()).unwrap();
+        assert_eq!(body, hex::decode(FIXED_FRAME).unwrap()[PACKET_LENGTH_LEN..]);
+    }
+
+    #[test]
+    fn packet_length_is_encrypted_with_k1_only() {
+        let key: [u8; KEY_LEN] = std::array::from_fn(|i| i as u8);
+        let frame = hex::decode(FIXED_FRAME).unwrap();
+        let seal = |key| {
+            let mut wire = frame.clone();
+            cipher_with_key(key).seal(7, &mut wire).unwrap();
+            wire
+        };
+        let wire = seal(key);
+
+        // K_2 (first half) does not touch the length, K_1 (second half) does
+        let mut other_k2 = key;
+        other_k2[0] ^= 1;
+        let other = seal(other_k2);
+        assert_eq!(other[..PACKET_LENGTH_LEN], wire[..PACKET_LENGTH_LEN]);
+        assert_ne!(other[PACKET_LENGTH_LEN..], wire[PACKET_LENGTH_LEN..]);
+        let mut other_k1 = key;
+        other_k1[32] ^= 1;
+        let other = seal(other_k1);
+        assert_ne!(other[..PACKET_LENGTH_LEN], wire[..PACKET_LENGTH_LEN]);
+        assert_eq!(other[PACKET_LENGTH_LEN..frame.len()], wire[PACKET_LENGTH_LEN..frame.len()]);
+
+        let encrypted_length: [u8; PACKET_LENGTH_LEN] = wire[..PACKET_LENGTH_LEN].try_into().unwrap();
+        let opening_key = cipher_with_key(key).opening_key;
+        assert_eq!(opening_key.decrypt_packet_length(7, encrypted_length), [0, 0, 0, 24]);
+        assert_ne!(opening_key.decrypt_packet_length(8, encrypted_length), [0, 0, 0, 24]);
+    }
+
+    #[test]
+    fn poly1305_tag_covers_length_and_body() {
+        let key: [u8; KEY_LEN] = std::array::from_fn(|i| i as u8);
+        let wire = hex::decode(FIXED_WIRE).unwrap();
+        let (packet, tag) = wire.split_at(wire.len() - TAG_LEN);
+        let tag: [u8; TAG_LEN] = tag.try_into().unwrap();
+        let opening_key = cipher_with_key(key).opening_key;
+
+        assert!(opening_key.open_in_place(7, &mut packet.to_vec(), &tag).is_ok());
+        // The encrypted length, the body and the tag are all authenticated
+        for position in [0, PACKET_LENGTH_LEN, packet.len() - 1] {
+            let mut tampered = packet.to_vec();
+            tampered[position] ^= 0x80;
+            assert!(opening_key.open_in_place(7, &mut tampered, &tag).is_err(), "{}", position);
+        }
+        let mut tampered_tag = tag;
+        tampered_tag[0] ^= 1;
+        assert!(opening_key.open_in_place(7, &mut packet.to_vec(), &tampered_tag).is_err());
+    }
+}
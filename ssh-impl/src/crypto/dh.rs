@@ -1,21 +1,20 @@
-use ring::agreement;
-use ring::rand::{SystemRandom, SecureRandom};
-use anyhow::{Result, Context};
+use ring::{agreement, digest};
+use ring::rand::SystemRandom;
+use anyhow::Result;
 
-/// Ephemeral key pair for Diffie-Hellman key exchange
+/// Ephemeral key pair for an elliptic curve Diffie-Hellman key exchange
 pub struct EphemeralKeyPair {
     pub private_key: agreement::EphemeralPrivateKey,
     pub public_key: Vec<u8>,
 }
 
 impl EphemeralKeyPair {
-    /// Generate a new ephemeral key pair using X25519
-    pub fn generate() -> Result<Self> {
+    /// Generate a new ephemeral key pair on the given curve (X25519, P-256
+    /// or P-384)
+    pub fn generate(algorithm: &'static agreement::Algorithm) -> Result<Self> {
         let rng = SystemRandom::new();
-        let private_key = agreement::EphemeralPrivateKey::generate(
-            &agreement::X25519,
-            &rng,
-        ).map_err(|_| anyhow::anyhow!("Failed to generate ephemeral private key"))?;
+        let private_key = agreement::EphemeralPrivateKey::generate(algorithm, &rng)
+            .map_err(|_| anyhow::anyhow!("Failed to generate ephemeral private key"))?;
 
         let public_key = private_key.compute_public_key()
             .map_err(|_| anyhow::anyhow!("Failed to compute public key"))?;
@@ -26,70 +25,176 @@ impl EphemeralKeyPair {
         })
     }
 
-    /// Compute shared secret from our private key and peer's public key
+    /// Compute the shared secret from our private key and the peer's public
+    /// key. ring rejects public keys that are not valid points of the curve
+    /// (RFC 5656 §4) and X25519 results that are all zero.
     pub fn compute_shared_secret(
         private_key: agreement::EphemeralPrivateKey,
         peer_public_key: &[u8],
     ) -> Result<Vec<u8>> {
         let peer_public_key = agreement::UnparsedPublicKey::new(
-            &agreement::X25519,
+            private_key.algorithm(),
             peer_public_key,
         );
 
-        let mut shared_secret = vec![0u8; 32];
         agreement::agree_ephemeral(
             private_key,
             &peer_public_key,
-            |key_material| {
-                shared_secret.copy_from_slice(key_material);
-            },
-        ).map_err(|_| anyhow::anyhow!("Failed to compute shared secret"))?;
+            |key_material| key_material.to_vec(),
+        ).map_err(|_| anyhow::anyhow!("Failed to compute shared secret"))
+    }
+}
+
+/// Sizes of the key material one direction's cipher and MAC need
+#[derive(Debug, Clone, Copy)]
+pub struct KeyLengths {
+    pub iv: usize,
+    pub encryption: usize,
+    pub integrity: usize,
+}
+
+/// Derive one key (RFC 4253 §7.2):
+/// K1 = HASH(K || H || letter || session_id), extended with
+/// Kn = HASH(K || H || K1 || ... || Kn-1) until `length` bytes are available.
+/// `shared_secret` is K already in its wire encoding (e.g. mpint).
+pub fn derive_key(
+    hash: &'static digest::Algorithm,
+    shared_secret: &[u8],
+    exchange_hash: &[u8],
+    letter: u8,
+    session_id: &[u8],
+    length: usize,
+) -> Vec<u8> {
+    let mut context = digest::Context::new(hash);
+    context.update(shared_secret);
+    context.update(exchange_hash);
+    context.update(&[letter]);
+    context.update(session_id);
+    let mut key = context.finish().as_ref().to_vec();
+
+    while key.len() < length {
+        let mut context = digest::Context::new(hash);
+        context.update(shared_secret);
+        context.update(exchange_hash);
+        context.update(&key);
+        key.extend_from_slice(context.finish().as_ref());
+    }
+
+    key.truncate(length);
+    key
+}
+
+/// Derive both directions' IVs, encryption keys and integrity keys
+/// (letters A-F of RFC 4253 §7.2)
+pub fn derive_session_keys(
+    hash: &'static digest::Algorithm,
+    shared_secret: &[u8],
+    exchange_hash: &[u8],
+    session_id: &[u8],
+    client_to_server: KeyLengths,
+    server_to_client: KeyLengths,
+) -> SessionKeys {
+    let derive = |letter: u8, length: usize| {
+        derive_key(hash, shared_secret, exchange_hash, letter, session_id, length)
+    };
 
-        Ok(shared_secret)
+    SessionKeys {
+        client_to_server: DirectionKeys {
+            iv: derive(b'A', client_to_server.iv),
+            encryption_key: derive(b'C', client_to_server.encryption),
+            integrity_key: derive(b'E', client_to_server.integrity),
+        },
+        server_to_client: DirectionKeys {
+            iv: derive(b'B', server_to_client.iv),
+            encryption_key: derive(b'D', server_to_client.encryption),
+            integrity_key: derive(b'F', server_to_client.integrity),
+        },
     }
 }
 
-/// Derive session keys from shared secret using HKDF
-pub fn derive_session_keys(shared_secret: &[u8]) -> Result<SessionKeys> {
-    // Simplified key derivation using SHA256 directly
-    // In production, use proper HKDF, but for educational purposes this works
-    use ring::digest;
-    
-    // Derive encryption key: SHA256(shared_secret || "encryption")
-    let mut enc_input = Vec::new();
-    enc_input.extend_from_slice(shared_secret);
-    enc_input.extend_from_slice(b"encryption");
-    let encryption_key_bytes = digest::digest(&digest::SHA256, &enc_input);
-    let mut encryption_key = [0u8; 32];
-    encryption_key.copy_from_slice(encryption_key_bytes.as_ref());
-    
-    // Derive MAC key: SHA256(shared_secret || "mac")
-    let mut mac_input = Vec::new();
-    mac_input.extend_from_slice(shared_secret);
-    mac_input.extend_from_slice(b"mac");
-    let mac_key_digest = digest::digest(&digest::SHA256, &mac_input);
-    let mut mac_key_bytes = [0u8; 32];
-    mac_key_bytes.copy_from_slice(mac_key_digest.as_ref());
-    
-    // Derive IV: First 12 bytes of SHA256(shared_secret || "iv")
-    let mut iv_input = Vec::new();
-    iv_input.extend_from_slice(shared_secret);
-    iv_input.extend_from_slice(b"iv");
-    let iv_digest = digest::digest(&digest::SHA256, &iv_input);
-    let mut iv_bytes = [0u8; 12];
-    iv_bytes.copy_from_slice(&iv_digest.as_ref()[0..12]);
-
-    Ok(SessionKeys {
-        encryption_key,
-        mac_key: mac_key_bytes,
-        iv: iv_bytes,
-    })
+/// Key material for one direction of the connection
+pub struct DirectionKeys {
+    pub iv: Vec<u8>,
+    pub encryption_key: Vec<u8>,
+    pub integrity_key: Vec<u8>,
 }
 
-/// Session keys derived from shared secret
+/// Session keys derived from the key exchange, one set per direction
 pub struct SessionKeys {
-    pub encryption_key: [u8; 32],
-    pub mac_key: [u8; 32],
-    pub iv: [u8; 12],
+    pub client_to_server: DirectionKeys,
+    pub server_to_client: DirectionKeys,
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Known answers computed independently of this code with Python's
+    // hashlib from the formulas of RFC 4253 §7.2. K is the mpint
+    // 0x808182...9f (a leading zero byte because of the high bit),
+    // H = SHA-256("exchange hash") and session_id = SHA-256("session id").
+    const SHARED_SECRET: &str = "0000002100808182838485868788898a8b8c8d8e8f909192939495969798999a9b9c9d9e9f";
+    const EXCHANGE_HASH: &str = "7bde03b6174b06ac22d902ada587e02f46b15e82e96fcc34d951b86efe0642f8";
+    const SESSION_ID: &str = "1cc1c70c03d3fa98125ac304150c6b2ab44b1a308f55aab9e46e6de9f92ae871";
+
+    #[test]
+    fn session_keys_match_known_answers() {
+        let keys = derive_session_keys(
+            &digest::SHA256,
+            &hex::decode(SHARED_SECRET).unwrap(),
+            &hex::decode(EXCHANGE_HASH).unwrap(),
+            &hex::decode(SESSION_ID).unwrap(),
+            KeyLengths { iv: 16, encryption: 64, integrity: 32 },
+            KeyLengths { iv: 12, encryption: 32, integrity: 64 },
+        );
+        // C and F are longer than a SHA-256 output and need K2
+        let expected = [
+            (&keys.client_to_server.iv, "111c34824e27bcf60e047eb9a5128e1c"),
+            (&keys.server_to_client.iv, "aa734bc2b5699314eed373dc"),
+            (
+                &keys.client_to_server.encryption_key,
+                "5fca2c06dce6e078771840e75b83d673fed120e3a5099386e7c5503602664cf9\
+                 d603e14d790e99840c567405baf275ad5e610430a69415f5a94aecc3fbab84e9",
+            ),
+            (
+                &keys.server_to_client.encryption_key,
+                "6f1c38d0871489d96ebd1b57e3283a6febc1aaa39c0c203ba1f153ef1b8b0948",
+            ),
+            (
+                &keys.client_to_server.integrity_key,
+                "535885905e3b7cf7a8db025a042c02031f9aba60dddee1f68457b82e7d9fe362",
+            ),
+            (
+                &keys.server_to_client.integrity_key,
+                "69e33c4ef5329f0e048a7893071a4c29c9c915005ed19f72d836401c5ef8f6f0\
+                 23c2452ab9495a07aa1db5d2ee684d3b383b37f7598e5586d2c5eb268b4b5484",
+            ),
+        ];
+        for (key, answer) in expected {
+            assert_eq!(hex::encode(key), answer);
+        }
+    }
+
+    #[test]
+    fn long_keys_extend_the_short_ones() {
+        let derive = |hash, length| derive_key(
+            hash,
+            &hex::decode(SHARED_SECRET).unwrap(),
+            &hex::decode(EXCHANGE_HASH).unwrap(),
+            b'C',
+            &hex::decode(SESSION_ID).unwrap(),
+            length,
+        );
+        // Three SHA-512 outputs, cut to 100 bytes
+        let long = derive(&digest::SHA512, 100);
+        assert_eq!(
+            hex::encode(&long),
+            "07ee5f14be092ac4f517c7f5abc3d4780d397637b73ed68a5a207f87d9299fba\
+             21cc614535bc8a8d200a8946ff949f32a0d634e2a2e792e38fad9dbb1485c128\
+             454b434c6ed2f3a96351a67b9e5b5b06fcac12550180088862799b09c5a85dd8\
+             15529639"
+        );
+        assert_eq!(derive(&digest::SHA512, 20), long[..20]);
+        assert_eq!(derive(&digest::SHA256, 32), derive(&digest::SHA256, 64)[..32]);
+    }
+}
@@ -1,92 +1,283 @@
-use ring::aead::{self, BoundKey};
+use std::io::Read;
+use ring::aead;
 use anyhow::{Result, Context};
-use crate::crypto::dh::SessionKeys;
+use crate::crypto::aes_ctr::AesCtrCipher;
+use crate::crypto::chacha::ChaCha20Poly1305Cipher;
+use crate::crypto::dh::{DirectionKeys, KeyLengths};
+use crate::crypto::mac::mac_key_length;
+use crate::utils::packet::{check_packet_length, Alignment};
 
-/// Simple nonce sequence using a counter
+/// Packet protection for one direction of the connection
+pub trait PacketCipher {
+    /// Block size the aligned part of every frame must be a multiple of
+    fn block_size(&self) -> usize;
+
+    /// Which part of the frame is aligned to the block size
+    fn alignment(&self) -> Alignment;
+
+    /// Length of the MAC or authentication tag following each frame
+    fn mac_length(&self) -> usize;
+
+    /// Encrypt a `packet_length || padding_length || payload || padding`
+    /// frame in place and append the authentication tag
+    fn seal(&mut self, sequence_number: u32, frame: &mut Vec<u8>) -> Result<()>;
+
+    /// Read and decrypt one packet, returning
+    /// `padding_length || payload || padding`
+    fn open(&mut self, sequence_number: u32, reader: &mut dyn Read) -> Result<Vec<u8>>;
+}
+
+/// Nonce sequence of aes256-gcm@openssh.com (RFC 5647 §7.1): a 4-byte fixed
+/// field followed by a 64-bit invocation counter, both taken from the derived
+/// IV. The counter is incremented after every packet.
 struct CounterNonceSequence {
-    base_iv: [u8; 12],
-    counter: u64,
+    fixed_field: [u8; 4],
+    invocation_counter: u64,
+    /// Nonces handed out so far; the counter must never come back round
+    used: u64,
 }
 
-impl aead::NonceSequence for CounterNonceSequence {
-    fn advance(&mut self) -> Result<aead::Nonce, ring::error::Unspecified> {
-        let mut nonce_bytes = self.base_iv;
-        // XOR counter into last 8 bytes of nonce
-        let counter_bytes = self.counter.to_be_bytes();
-        for i in 0..8 {
-            nonce_bytes[4 + i] ^= counter_bytes[i];
+impl CounterNonceSequence {
+    fn new(iv: &[u8]) -> Result<Self> {
+        if iv.len() != aead::NONCE_LEN {
+            anyhow::bail!("Invalid AES-256-GCM IV length {}", iv.len());
         }
-        self.counter += 1;
+        let mut fixed_field = [0u8; 4];
+        fixed_field.copy_from_slice(&iv[..4]);
+        let mut counter_bytes = [0u8; 8];
+        counter_bytes.copy_from_slice(&iv[4..]);
+        Ok(Self {
+            fixed_field,
+            invocation_counter: u64::from_be_bytes(counter_bytes),
+            used: 0,
+        })
+    }
+
+    fn advance(&mut self) -> Result<aead::Nonce> {
+        // Rekeying happens long before this, but a wrapped counter would
+        // repeat a nonce under the same key
+        if self.used == u64::MAX {
+            anyhow::bail!("AES-GCM nonces exhausted: the keys must be renewed");
+        }
+        self.used += 1;
+
+        let mut nonce_bytes = [0u8; aead::NONCE_LEN];
+        nonce_bytes[..4].copy_from_slice(&self.fixed_field);
+        nonce_bytes[4..].copy_from_slice(&self.invocation_counter.to_be_bytes());
+        self.invocation_counter = self.invocation_counter.wrapping_add(1);
         Ok(aead::Nonce::assume_unique_for_key(nonce_bytes))
     }
 }
 
-/// Encryption context for encrypting/decrypting packets
-pub struct EncryptionContext {
-    sealing_key: aead::SealingKey<CounterNonceSequence>,
-    opening_key: aead::OpeningKey<CounterNonceSequence>,
+/// Key material sizes of a cipher and its MAC (`None` for AEAD ciphers)
+pub fn key_lengths(cipher: &str, mac: Option<&str>) -> Result<KeyLengths> {
+    let (iv, encryption) = match cipher {
+        "aes256-gcm@openssh.com" => (12, 32),
+        "chacha20-poly1305@openssh.com" => (0, 64),
+        "aes128-ctr" => (16, 16),
+        "aes256-ctr" => (16, 32),
+        other => anyhow::bail!("Unsupported cipher: {}", other),
+    };
+    let integrity = match mac {
+        Some(mac) => mac_key_length(mac)?,
+        None => 0,
+    };
+    Ok(KeyLengths { iv, encryption, integrity })
 }
 
-impl EncryptionContext {
-    /// Create a new encryption context from session keys
-    pub fn new(session_keys: &SessionKeys) -> Result<Self> {
-        let unbound_sealing_key = aead::UnboundKey::new(
-            &aead::AES_256_GCM,
-            &session_keys.encryption_key,
-        ).map_err(|_| anyhow::anyhow!("Failed to create sealing key"))?;
-
-        let unbound_opening_key = aead::UnboundKey::new(
-            &aead::AES_256_GCM,
-            &session_keys.encryption_key,
-        ).map_err(|_| anyhow::anyhow!("Failed to create opening key"))?;
-
-        let sealing_nonce_sequence = CounterNonceSequence {
-            base_iv: session_keys.iv,
-            counter: 0,
-        };
-        
-        let opening_nonce_sequence = CounterNonceSequence {
-            base_iv: session_keys.iv,
-            counter: 0,
-        };
+/// Create the negotiated cipher (and MAC, for non-AEAD ciphers) from one
+/// direction's derived keys
+pub fn new_cipher(
+    cipher: &str,
+    mac: Option<&str>,
+    keys: &DirectionKeys,
+) -> Result<Box<dyn PacketCipher>> {
+    match (cipher, mac) {
+        ("aes256-gcm@openssh.com", None) => Ok(Box::new(AesGcmCipher::new(keys)?)),
+        ("chacha20-poly1305@openssh.com", None) => Ok(Box::new(ChaCha20Poly1305Cipher::new(keys)?)),
+        ("aes128-ctr" | "aes256-ctr", Some(mac)) => Ok(Box::new(AesCtrCipher::new(cipher, mac, keys)?)),
+        (_, None) => anyhow::bail!("Cipher {} needs a MAC algorithm", cipher),
+        (_, Some(mac)) => anyhow::bail!("Unsupported cipher and MAC combination: {} with {}", cipher, mac),
+    }
+}
 
-        let sealing_key = aead::SealingKey::new(unbound_sealing_key, sealing_nonce_sequence);
-        let opening_key = aead::OpeningKey::new(unbound_opening_key, opening_nonce_sequence);
+/// aes256-gcm@openssh.com for one direction of the connection.
+///
+/// `packet_length` is sent in the clear but authenticated as additional data;
+/// `padding_length || payload || padding` is encrypted and followed by the
+/// 16-byte tag.
+pub struct AesGcmCipher {
+    key: aead::LessSafeKey,
+    nonces: CounterNonceSequence,
+}
 
+impl AesGcmCipher {
+    /// AES block size; the encrypted part of a packet must be a multiple of it
+    pub const BLOCK_SIZE: usize = 16;
+
+    /// Length of the authentication tag following each packet
+    pub const TAG_LENGTH: usize = 16;
+
+    /// Create the cipher from one direction's derived keys
+    pub fn new(keys: &DirectionKeys) -> Result<Self> {
+        let unbound_key = aead::UnboundKey::new(&aead::AES_256_GCM, &keys.encryption_key)
+            .map_err(|_| anyhow::anyhow!("Invalid AES-256-GCM key length"))?;
         Ok(Self {
-            sealing_key,
-            opening_key,
+            key: aead::LessSafeKey::new(unbound_key),
+            nonces: CounterNonceSequence::new(&keys.iv)?,
         })
     }
+}
+
+impl PacketCipher for AesGcmCipher {
+    fn block_size(&self) -> usize {
+        Self::BLOCK_SIZE
+    }
+
+    fn alignment(&self) -> Alignment {
+        Alignment::AfterLength
+    }
+
+    fn mac_length(&self) -> usize {
+        Self::TAG_LENGTH
+    }
 
-    /// Encrypt a packet
-    pub fn encrypt(&mut self, plaintext: &[u8]) -> Result<Vec<u8>> {
-        let mut in_out = plaintext.to_vec();
-        let tag = self.sealing_key.seal_in_place_separate_tag(
-            aead::Aad::empty(),
-            &mut in_out,
+    fn seal(&mut self, _sequence_number: u32, frame: &mut Vec<u8>) -> Result<()> {
+        let (length_field, body) = frame.split_at_mut(4);
+        let tag = self.key.seal_in_place_separate_tag(
+            self.nonces.advance()?,
+            aead::Aad::from(&*length_field),
+            body,
         ).map_err(|_| anyhow::anyhow!("Failed to encrypt packet"))?;
 
-        // Append authentication tag
-        in_out.extend_from_slice(tag.as_ref());
-        Ok(in_out)
+        frame.extend_from_slice(tag.as_ref());
+        Ok(())
     }
 
-    /// Decrypt a packet
-    pub fn decrypt(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>> {
-        if ciphertext.len() < 16 {
-            anyhow::bail!("Ciphertext too short");
-        }
+    fn open(&mut self, _sequence_number: u32, reader: &mut dyn Read) -> Result<Vec<u8>> {
+        let mut length_field = [0u8; 4];
+        reader.read_exact(&mut length_field)
+            .context("Failed to read packet length")?;
+        let packet_length = u32::from_be_bytes(length_field) as usize;
+        check_packet_length(packet_length, Self::BLOCK_SIZE, Alignment::AfterLength, Self::TAG_LENGTH)?;
+
+        let mut body = vec![0u8; packet_length + Self::TAG_LENGTH];
+        reader.read_exact(&mut body)
+            .context("Failed to read packet body")?;
 
-        let mut in_out = ciphertext.to_vec();
-        self.opening_key.open_in_place(
-            aead::Aad::empty(),
-            &mut in_out,
-        ).map_err(|_| anyhow::anyhow!("Failed to decrypt packet"))?;
+        let plaintext_length = self.key.open_in_place(
+            self.nonces.advance()?,
+            aead::Aad::from(length_field),
+            &mut body,
+        ).map_err(|_| anyhow::anyhow!("Packet authentication failed"))?.len();
 
-        // Remove authentication tag (last 16 bytes)
-        let plaintext_len = in_out.len() - 16;
-        Ok(in_out[..plaintext_len].to_vec())
+        body.truncate(plaintext_length);
+        Ok(body)
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::packet::Packet;
+
+    /// IV whose invocation counter carries into its upper half on the
+    /// second packet
+    const IV: &str = "a0a1a2a300000000ffffffff";
+
+    fn gcm_cipher() -> AesGcmCipher {
+        let keys = DirectionKeys {
+            iv: hex::decode(IV).unwrap(),
+            encryption_key: (0..32).collect(),
+            integrity_key: Vec::new(),
+        };
+        AesGcmCipher::new(&keys).unwrap()
+    }
+
+    /// packet_length = 16 || padding_length = 4 || "hello world" || 4 zero bytes
+    fn fixed_frame() -> Vec<u8> {
+        let mut frame = vec![0, 0, 0, 16, 4];
+        frame.extend_from_slice(b"hello world");
+        frame.extend_from_slice(&[0; 4]);
+        frame
+    }
+
+    fn sealed_packet(cipher: &mut AesGcmCipher, payload: &[u8]) -> Vec<u8> {
+        let mut frame = Packet::new(payload.to_vec())
+            .to_frame_with(cipher.block_size(), cipher.alignment(), cipher.mac_length())
+            .unwrap();
+        cipher.seal(0, &mut frame).unwrap();
+        frame
+    }
+
+    #[test]
+    fn nonce_counter_increments_as_64_bits() {
+        let mut nonces = CounterNonceSequence::new(&hex::decode(IV).unwrap()).unwrap();
+        assert_eq!(hex::encode(nonces.advance().unwrap().as_ref()), "a0a1a2a300000000ffffffff");
+        assert_eq!(hex::encode(nonces.advance().unwrap().as_ref()), "a0a1a2a30000000100000000");
+        assert_eq!(hex::encode(nonces.advance().unwrap().as_ref()), "a0a1a2a30000000100000001");
+        assert!(CounterNonceSequence::new(&[0; 16]).is_err());
+    }
+
+    #[test]
+    fn gcm_matches_known_answers() {
+        // Computed independently with Python's cryptography AESGCM, with the
+        // length field as additional data and one nonce per packet
+        let mut cipher = gcm_cipher();
+        for expected in [
+            "b10a1cf5f01baa97142780928b20be59d50bbb7e8d702e763dc3ab5bb9e9cd4b",
+            "de3157ef95b40849798a7d8e5905ca4e1c82a5c8995e7219fdb4bdb7d3d5bbc1",
+        ] {
+            let mut frame = fixed_frame();
+            cipher.seal(0, &mut frame).unwrap();
+            // The length is sent in the clear
+            assert_eq!(frame[..4], [0, 0, 0, 16]);
+            assert_eq!(hex::encode(&frame[4..]), expected);
+        }
+    }
+
+    #[test]
+    fn length_is_authenticated_as_additional_data() {
+        let mut frame = fixed_frame();
+        gcm_cipher().seal(0, &mut frame).unwrap();
+
+        let key: Vec<u8> = (0..32).collect();
+        let key = aead::LessSafeKey::new(aead::UnboundKey::new(&aead::AES_256_GCM, &key).unwrap());
+        let nonce = || aead::Nonce::try_assume_unique_for_key(&hex::decode(IV).unwrap()).unwrap();
+        let mut body = frame[4..].to_vec();
+        assert!(key.open_in_place(nonce(), aead::Aad::empty(), &mut body).is_err());
+        let mut body = frame[4..].to_vec();
+        assert!(key.open_in_place(nonce(), aead::Aad::from([0, 0, 0, 32]), &mut body).is_err());
+        let mut body = frame[4..].to_vec();
+        let plaintext = key.open_in_place(nonce(), aead::Aad::from([0, 0, 0, 16]), &mut body).unwrap();
+        assert_eq!(plaintext, &fixed_frame()[4..]);
+    }
+
+    #[test]
+    fn gcm_round_trip() {
+        let mut sender = gcm_cipher();
+        let mut receiver = gcm_cipher();
+        let first = sealed_packet(&mut sender, b"same payload");
+        let second = sealed_packet(&mut sender, b"same payload");
+        assert_ne!(first[4..], second[4..]);
+        for wire in [first, second] {
+            let body = receiver.open(0, &mut wire.as_slice()).unwrap();
+            assert_eq!(Packet::strip_padding(&body).unwrap(), b"same payload");
+        }
+    }
+
+    #[test]
+    fn gcm_rejects_tampered_or_reordered_packets() {
+        let mut sender = gcm_cipher();
+        let wire = sealed_packet(&mut sender, b"payload");
+        for position in [4, wire.len() - AesGcmCipher::TAG_LENGTH - 1, wire.len() - 1] {
+            let mut tampered = wire.clone();
+            tampered[position] ^= 1;
+            assert!(gcm_cipher().open(0, &mut tampered.as_slice()).is_err());
+        }
+
+        // The receiver expects the nonce of the first packet
+        let second = sealed_packet(&mut sender, b"payload");
+        assert!(gcm_cipher().open(0, &mut second.as_slice()).is_err());
+    }
+}
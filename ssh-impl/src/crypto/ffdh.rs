@@ -0,0 +1,265 @@
+use num_bigint::BigUint;
+use ring::digest;
+use ring::rand::{SecureRandom, SystemRandom};
+use anyhow::{Result, Context};
+use crate::crypto::kex::KexMethod;
+use crate::crypto::moduli::DhGroup;
+use crate::utils::wire::Writer;
+
+/// Generator of every MODP group
+const GENERATOR: u32 = 2;
+
+/// Size of the private exponent in bytes. 512 bits is at least twice the
+/// security strength of the largest group (RFC 8268 §2, RFC 3526 §8).
+const PRIVATE_EXPONENT_LENGTH: usize = 64;
+
+/// 2048-bit MODP group 14 prime (RFC 3526 §3)
+const GROUP14_PRIME: &str = concat!(
+    "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74",
+    "020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F1437",
+    "4FE1356D6D51C245E485B576625E7EC6F44C42E9A637ED6B0BFF5CB6F406B7ED",
+    "EE386BFB5A899FA5AE9F24117C4B1FE649286651ECE45B3DC2007CB8A163BF05",
+    "98DA48361C55D39A69163FA8FD24CF5F83655D23DCA3AD961C62F356208552BB",
+    "9ED529077096966D670C354E4ABC9804F1746C08CA18217C32905E462E36CE3B",
+    "E39E772C180E86039B2783A2EC07A28FB5C55DF06F4C52C9DE2BCBF695581718",
+    "3995497CEA956AE515D2261898FA051015728E5A8AACAA68FFFFFFFFFFFFFFFF",
+);
+
+/// 4096-bit MODP group 16 prime (RFC 3526 §5)
+const GROUP16_PRIME: &str = concat!(
+    "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74",
+    "020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F1437",
+    "4FE1356D6D51C245E485B576625E7EC6F44C42E9A637ED6B0BFF5CB6F406B7ED",
+    "EE386BFB5A899FA5AE9F24117C4B1FE649286651ECE45B3DC2007CB8A163BF05",
+    "98DA48361C55D39A69163FA8FD24CF5F83655D23DCA3AD961C62F356208552BB",
+    "9ED529077096966D670C354E4ABC9804F1746C08CA18217C32905E462E36CE3B",
+    "E39E772C180E86039B2783A2EC07A28FB5C55DF06F4C52C9DE2BCBF695581718",
+    "3995497CEA956AE515D2261898FA051015728E5A8AAAC42DAD33170D04507A33",
+    "A85521ABDF1CBA64ECFB850458DBEF0A8AEA71575D060C7DB3970F85A6E1E4C7",
+    "ABF5AE8CDB0933D71E8C94E04A25619DCEE3D2261AD2EE6BF12FFA06D98A0864",
+    "D87602733EC86A64521F2B18177B200CBBE117577A615D6C770988C0BAD946E2",
+    "08E24FA074E5AB3143DB5BFCE0FD108E4B82D120A92108011A723C12A787E6D7",
+    "88719A10BDBA5B2699C327186AF4E23C1A946834B6150BDA2583E9CA2AD44CE8",
+    "DBBBC2DB04DE8EF92E8EFC141FBECAA6287C59474E6BC05D99B2964FA090C3A2",
+    "233BA186515BE7ED1F612970CEE2D7AFB81BDD762170481CD0069127D5B05AA9",
+    "93B4EA988D8FDDC186FFB7DC90A6C08F4DF435C934063199FFFFFFFFFFFFFFFF",
+);
+
+/// 8192-bit MODP group 18 prime (RFC 3526 §7)
+const GROUP18_PRIME: &str = concat!(
+    "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74",
+    "020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F1437",
+    "4FE1356D6D51C245E485B576625E7EC6F44C42E9A637ED6B0BFF5CB6F406B7ED",
+    "EE386BFB5A899FA5AE9F24117C4B1FE649286651ECE45B3DC2007CB8A163BF05",
+    "98DA48361C55D39A69163FA8FD24CF5F83655D23DCA3AD961C62F356208552BB",
+    "9ED529077096966D670C354E4ABC9804F1746C08CA18217C32905E462E36CE3B",
+    "E39E772C180E86039B2783A2EC07A28FB5C55DF06F4C52C9DE2BCBF695581718",
+    "3995497CEA956AE515D2261898FA051015728E5A8AAAC42DAD33170D04507A33",
+    "A85521ABDF1CBA64ECFB850458DBEF0A8AEA71575D060C7DB3970F85A6E1E4C7",
+    "ABF5AE8CDB0933D71E8C94E04A25619DCEE3D2261AD2EE6BF12FFA06D98A0864",
+    "D87602733EC86A64521F2B18177B200CBBE117577A615D6C770988C0BAD946E2",
+    "08E24FA074E5AB3143DB5BFCE0FD108E4B82D120A92108011A723C12A787E6D7",
+    "88719A10BDBA5B2699C327186AF4E23C1A946834B6150BDA2583E9CA2AD44CE8",
+    "DBBBC2DB04DE8EF92E8EFC141FBECAA6287C59474E6BC05D99B2964FA090C3A2",
+    "233BA186515BE7ED1F612970CEE2D7AFB81BDD762170481CD0069127D5B05AA9",
+    "93B4EA988D8FDDC186FFB7DC90A6C08F4DF435C93402849236C3FAB4D27C7026",
+    "C1D4DCB2602646DEC9751E763DBA37BDF8FF9406AD9E530EE5DB382F413001AE",
+    "B06A53ED9027D831179727B0865A8918DA3EDBEBCF9B14ED44CE6CBACED4BB1B",
+    "DB7F1447E6CC254B332051512BD7AF426FB8F401378CD2BF5983CA01C64B92EC",
+    "F032EA15D1721D03F482D7CE6E74FEF6D55E702F46980C82B5A84031900B1C9E",
+    "59E7C97FBEC7E8F323A97A7E36CC88BE0F1D45B7FF585AC54BD407B22B4154AA",
+    "CC8F6D7EBF48E1D814CC5ED20F8037E0A79715EEF29BE32806A1D58BB7C5DA76",
+    "F550AA3D8A1FBFF0EB19CCB1A313D55CDA56C9EC2EF29632387FE8D76E3C0468",
+    "043E8F663F4860EE12BF2D5B0B7474D6E694F91E6DBE115974A3926F12FEE5E4",
+    "38777CB6A932DF8CD8BEC4D073B931BA3BC832B68D9DD300741FA7BF8AFC47ED",
+    "2576F6936BA424663AAB639C5AE4F5683423B4742BF1C978238F16CBE39D652D",
+    "E3FDB8BEFC848AD922222E04A4037C0713EB57A81A23F0C73473FC646CEA306B",
+    "4BCBC8862F8385DDFA9D4B7FA2C087E879683303ED5BDD3A062B3CF5B3A278A6",
+    "6D2A13F83F44F82DDF310EE074AB6A364597E899A0255DC164F31CC50846851D",
+    "F9AB48195DED7EA1B1D510BD7EE74D73FAF36BC31ECFA268359046F4EB879F92",
+    "4009438B481C6CD7889A002ED5EE382BC9190DA6FC026E479558E4475677E9AA",
+    "9E3050E2765694DFC81F56E880B96E7160C980DD98EDD3DFFFFFFFFFFFFFFFFF",
+);
+
+/// Finite field Diffie-Hellman (RFC 4253 §8) over a fixed MODP group
+/// (RFC 8268: diffie-hellman-group14-sha256, group16-sha512 and
+/// group18-sha512) or over a group the server picked in a group exchange
+/// (RFC 4419).
+///
+/// e = g^x mod p and f = g^y mod p travel as mpints. SSH_MSG_KEXDH_INIT and
+/// SSH_MSG_KEXDH_REPLY have the same layout as their ECDH counterparts with
+/// the public value's string holding the mpint encoding, so the public values
+/// returned here are mpint bodies. K = f^x mod p = e^y mod p.
+pub struct FiniteFieldDh {
+    prime: BigUint,
+    generator: BigUint,
+    hash: &'static digest::Algorithm,
+    /// Client's private exponent until the server's reply arrives
+    private_exponent: Option<BigUint>,
+}
+
+impl FiniteFieldDh {
+    /// diffie-hellman-group14-sha256
+    pub fn group14_sha256() -> Self {
+        Self::new(modp_group(GROUP14_PRIME), &digest::SHA256)
+    }
+
+    /// diffie-hellman-group16-sha512
+    pub fn group16_sha512() -> Self {
+        Self::new(modp_group(GROUP16_PRIME), &digest::SHA512)
+    }
+
+    /// diffie-hellman-group18-sha512
+    pub fn group18_sha512() -> Self {
+        Self::new(modp_group(GROUP18_PRIME), &digest::SHA512)
+    }
+
+    /// Diffie-Hellman over any group
+    pub fn new(group: DhGroup, hash: &'static digest::Algorithm) -> Self {
+        Self {
+            prime: group.prime,
+            generator: group.generator,
+            hash,
+            private_exponent: None,
+        }
+    }
+
+    /// Random private exponent x (or y), 1 < x
+    fn generate_private_exponent() -> Result<BigUint> {
+        let rng = SystemRandom::new();
+        let mut bytes = [0u8; PRIVATE_EXPONENT_LENGTH];
+        loop {
+            rng.fill(&mut bytes)
+                .map_err(|_| anyhow::anyhow!("Failed to generate private exponent"))?;
+            let exponent = BigUint::from_bytes_be(&bytes);
+            if exponent > BigUint::from(1u32) {
+                return Ok(exponent);
+            }
+        }
+    }
+
+    /// Our public value g^x mod p
+    fn public_value(&self, private_exponent: &BigUint) -> BigUint {
+        self.generator.modpow(private_exponent, &self.prime)
+    }
+
+    /// Parse the peer's e or f and check 1 < value < p-1 (RFC 4253 §8), which
+    /// rules out the values that would force K into a tiny subgroup
+    fn peer_value(&self, mpint_body: &[u8]) -> Result<BigUint> {
+        if mpint_body.first().is_some_and(|byte| byte & 0x80 != 0) {
+            anyhow::bail!("Diffie-Hellman public value is negative");
+        }
+        let value = BigUint::from_bytes_be(mpint_body);
+        let one = BigUint::from(1u32);
+        if value <= one || value >= &self.prime - &one {
+            anyhow::bail!("Diffie-Hellman public value is out of range");
+        }
+        Ok(value)
+    }
+
+    /// K from the peer's public value and our private exponent
+    fn shared_secret(&self, peer_value: &BigUint, private_exponent: &BigUint) -> Vec<u8> {
+        peer_value.modpow(private_exponent, &self.prime).to_bytes_be()
+    }
+}
+
+fn modp_group(prime_hex: &str) -> DhGroup {
+    DhGroup {
+        prime: BigUint::parse_bytes(prime_hex.as_bytes(), 16)
+            .expect("MODP group prime is valid hex"),
+        generator: BigUint::from(GENERATOR),
+    }
+}
+
+/// The fixed MODP groups 14, 16 and 18, used for group exchange when no
+/// moduli file is available
+pub fn modp_groups() -> Vec<DhGroup> {
+    [GROUP14_PRIME, GROUP16_PRIME, GROUP18_PRIME].into_iter().map(modp_group).collect()
+}
+
+/// The mpint encoding of a non-negative integer without its length field
+pub fn mpint_body(value: &BigUint) -> Vec<u8> {
+    let mut writer = Writer::new();
+    writer.write_mpint(&value.to_bytes_be());
+    writer.into_bytes().split_off(4)
+}
+
+impl KexMethod for FiniteFieldDh {
+    fn hash_algorithm(&self) -> &'static digest::Algorithm {
+        self.hash
+    }
+
+    fn client_public_key(&mut self) -> Result<Vec<u8>> {
+        let x = Self::generate_private_exponent()?;
+        let e = self.public_value(&x);
+        self.private_exponent = Some(x);
+        Ok(mpint_body(&e))
+    }
+
+    fn client_shared_secret(&mut self, server_public_key: &[u8]) -> Result<Vec<u8>> {
+        let x = self.private_exponent.take()
+            .context("Client private exponent was not generated")?;
+        let f = self.peer_value(server_public_key)
+            .context("Invalid server public value f")?;
+        Ok(self.shared_secret(&f, &x))
+    }
+
+    fn server_exchange(&mut self, client_public_key: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+        let e = self.peer_value(client_public_key)
+            .context("Invalid client public value e")?;
+        let y = Self::generate_private_exponent()?;
+        let f = self.public_value(&y);
+        Ok((mpint_body(&f), self.shared_secret(&e, &y)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn peer_values_must_be_between_one_and_p_minus_one() {
+        let dh = FiniteFieldDh::group14_sha256();
+        let one = BigUint::from(1u32);
+        let two = BigUint::from(2u32);
+        let rejected = [
+            BigUint::from(0u32),
+            one.clone(),
+            &dh.prime - &one,
+            dh.prime.clone(),
+            &dh.prime + &one,
+        ];
+        for value in rejected {
+            assert!(dh.peer_value(&mpint_body(&value)).is_err(), "accepted {}", value);
+        }
+        for value in [two.clone(), &dh.prime - &two] {
+            assert_eq!(dh.peer_value(&mpint_body(&value)).unwrap(), value);
+        }
+        // Without the leading zero byte the prime's top bit makes it negative
+        assert!(dh.peer_value(&(&dh.prime - &two).to_bytes_be()).is_err());
+        assert!(dh.peer_value(&[]).is_err());
+    }
+
+    #[test]
+    fn client_and_server_agree() {
+        let mut client = FiniteFieldDh::group14_sha256();
+        let mut server = FiniteFieldDh::group14_sha256();
+        let e = client.client_public_key().unwrap();
+        let (f, server_secret) = server.server_exchange(&e).unwrap();
+        let client_secret = client.client_shared_secret(&f).unwrap();
+        assert_eq!(client_secret, server_secret);
+        assert!(BigUint::from_bytes_be(&client_secret) < client.prime);
+
+        // Public values are mpint bodies: a leading zero where the top bit is set
+        for value in [&e, &f] {
+            assert!(value.first().is_some_and(|&byte| byte != 0 || value[1] & 0x80 != 0));
+        }
+        // A peer sending 1 or p-1 is refused on either side
+        let one = mpint_body(&BigUint::from(1u32));
+        assert!(FiniteFieldDh::group14_sha256().server_exchange(&one).is_err());
+        let mut client = FiniteFieldDh::group14_sha256();
+        client.client_public_key().unwrap();
+        let p_minus_one = mpint_body(&(&client.prime - BigUint::from(1u32)));
+        assert!(client.client_shared_secret(&p_minus_one).is_err());
+    }
+}
@@ -0,0 +1,181 @@
+use ring::{agreement, digest};
+use anyhow::{Result, Context};
+use crate::crypto::dh::EphemeralKeyPair;
+use crate::crypto::ffdh::FiniteFieldDh;
+use crate::crypto::mlkem::MlKem768X25519;
+use crate::utils::wire::Writer;
+
+/// Ephemeral part of a key exchange method. The client sends a public value,
+/// the server answers with its own, and both sides arrive at the shared
+/// secret K. Host key signing and the exchange hash are the same for every
+/// method and live in the key exchange itself.
+///
+/// Public values are carried as SSH strings, which the exchange hash covers
+/// as they were sent.
+pub trait KexMethod {
+    /// Hash function of the exchange hash and key derivation
+    fn hash_algorithm(&self) -> &'static digest::Algorithm;
+
+    /// Client side: generate our ephemeral key and return its public value
+    fn client_public_key(&mut self) -> Result<Vec<u8>>;
+
+    /// Client side: K from the server's public value
+    fn client_shared_secret(&mut self, server_public_key: &[u8]) -> Result<Vec<u8>>;
+
+    /// Server side: answer the client's public value with ours and return
+    /// `(server public value, K)`
+    fn server_exchange(&mut self, client_public_key: &[u8]) -> Result<(Vec<u8>, Vec<u8>)>;
+
+    /// Encode K as it enters the exchange hash and key derivation. Most
+    /// methods use it as a big-endian integer (RFC 8731 §3, RFC 5656 §4),
+    /// so the default is an mpint.
+    fn encode_shared_secret(&self, shared_secret: &[u8]) -> Vec<u8> {
+        let mut writer = Writer::new();
+        writer.write_mpint(shared_secret);
+        writer.into_bytes()
+    }
+}
+
+/// Create the negotiated key exchange method
+pub fn new_kex_method(name: &str) -> Result<Box<dyn KexMethod>> {
+    match name {
+        "curve25519-sha256" | "curve25519-sha256@libssh.org" => {
+            Ok(Box::new(Ecdh::new(&agreement::X25519, &digest::SHA256)))
+        }
+        "mlkem768x25519-sha256" => Ok(Box::new(MlKem768X25519::new())),
+        "ecdh-sha2-nistp256" => Ok(Box::new(Ecdh::new(&agreement::ECDH_P256, &digest::SHA256))),
+        "ecdh-sha2-nistp384" => Ok(Box::new(Ecdh::new(&agreement::ECDH_P384, &digest::SHA384))),
+        "diffie-hellman-group14-sha256" => Ok(Box::new(FiniteFieldDh::group14_sha256())),
+        "diffie-hellman-group16-sha512" => Ok(Box::new(FiniteFieldDh::group16_sha512())),
+        "diffie-hellman-group18-sha512" => Ok(Box::new(FiniteFieldDh::group18_sha512())),
+        other => anyhow::bail!("Unsupported key exchange algorithm: {}", other),
+    }
+}
+
+/// Hash function of a Diffie-Hellman group exchange method (RFC 4419,
+/// RFC 8268), whose group is only known once the server has picked it
+pub fn group_exchange_hash(name: &str) -> Option<&'static digest::Algorithm> {
+    match name {
+        "diffie-hellman-group-exchange-sha256" => Some(&digest::SHA256),
+        _ => None,
+    }
+}
+
+/// Elliptic curve Diffie-Hellman: curve25519-sha256 (RFC 8731) and
+/// ecdh-sha2-nistp256/384 (RFC 5656 §4). Q_C and Q_S are sent as strings;
+/// X25519 keys are 32 raw bytes, NIST keys uncompressed points
+/// (`0x04 || x || y`). K is the X25519 output or the x-coordinate of the
+/// shared point.
+pub struct Ecdh {
+    curve: &'static agreement::Algorithm,
+    hash: &'static digest::Algorithm,
+    /// Client's ephemeral private key until the server's reply arrives
+    private_key: Option<agreement::EphemeralPrivateKey>,
+}
+
+impl Ecdh {
+    pub fn new(curve: &'static agreement::Algorithm, hash: &'static digest::Algorithm) -> Self {
+        Self {
+            curve,
+            hash,
+            private_key: None,
+        }
+    }
+}
+
+impl KexMethod for Ecdh {
+    fn hash_algorithm(&self) -> &'static digest::Algorithm {
+        self.hash
+    }
+
+    fn client_public_key(&mut self) -> Result<Vec<u8>> {
+        let key_pair = EphemeralKeyPair::generate(self.curve)
+            .context("Failed to generate client key pair")?;
+        self.private_key = Some(key_pair.private_key);
+        Ok(key_pair.public_key)
+    }
+
+    fn client_shared_secret(&mut self, server_public_key: &[u8]) -> Result<Vec<u8>> {
+        let private_key = self.private_key.take()
+            .context("Client key pair was not generated")?;
+        EphemeralKeyPair::compute_shared_secret(private_key, server_public_key)
+    }
+
+    fn server_exchange(&mut self, client_public_key: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+        let key_pair = EphemeralKeyPair::generate(self.curve)
+            .context("Failed to generate server key pair")?;
+        let shared_secret = EphemeralKeyPair::compute_shared_secret(
+            key_pair.private_key,
+            client_public_key,
+        )?;
+        Ok((key_pair.public_key, shared_secret))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ECDH_METHODS: [(&str, usize); 4] = [
+        ("curve25519-sha256", 32),
+        ("curve25519-sha256@libssh.org", 32),
+        ("ecdh-sha2-nistp256", 65),
+        ("ecdh-sha2-nistp384", 97),
+    ];
+
+    #[test]
+    fn ecdh_client_and_server_agree() {
+        for (name, public_key_length) in ECDH_METHODS {
+            let mut client = new_kex_method(name).unwrap();
+            let mut server = new_kex_method(name).unwrap();
+            let client_public_key = client.client_public_key().unwrap();
+            let (server_public_key, server_secret) = server.server_exchange(&client_public_key).unwrap();
+            let client_secret = client.client_shared_secret(&server_public_key).unwrap();
+
+            assert_eq!(client_public_key.len(), public_key_length, "{}", name);
+            assert_eq!(server_public_key.len(), public_key_length, "{}", name);
+            assert_eq!(client_secret, server_secret, "{}", name);
+            // The private key is used once
+            assert!(client.client_shared_secret(&server_public_key).is_err(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn ecdh_rejects_invalid_public_keys() {
+        for (name, public_key_length) in ECDH_METHODS {
+            let valid = new_kex_method(name).unwrap().client_public_key().unwrap();
+            let mut off_curve = valid.clone();
+            *off_curve.last_mut().unwrap() ^= 1;
+            let mut compressed = valid[..1 + (public_key_length - 1) / 2].to_vec();
+            compressed[0] = 0x02;
+
+            let mut invalid = vec![
+                Vec::new(),
+                valid[..public_key_length - 1].to_vec(),
+                [valid.as_slice(), &[0]].concat(),
+                // The point at infinity, and X25519's all-zero output
+                vec![0],
+                vec![0; public_key_length],
+            ];
+            if public_key_length > 32 {
+                invalid.push(off_curve);
+                invalid.push(compressed);
+            }
+            for public_key in invalid {
+                assert!(
+                    new_kex_method(name).unwrap().server_exchange(&public_key).is_err(),
+                    "{} accepted {}",
+                    name,
+                    hex::encode(&public_key)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn ecdh_uses_the_method_hash() {
+        assert_eq!(new_kex_method("curve25519-sha256").unwrap().hash_algorithm(), &digest::SHA256);
+        assert_eq!(new_kex_method("ecdh-sha2-nistp256").unwrap().hash_algorithm(), &digest::SHA256);
+        assert_eq!(new_kex_method("ecdh-sha2-nistp384").unwrap().hash_algorithm(), &digest::SHA384);
+    }
+}
@@ -1,209 +1,593 @@
+use base64::Engine;
+use base64::engine::general_purpose::{STANDARD, STANDARD_NO_PAD};
 use ed25519_dalek::{SigningKey, VerifyingKey, Signature, Signer, Verifier};
 use rand_core::OsRng;
+use ring::digest;
+use ring::rand::SystemRandom;
+use ring::signature::{self, EcdsaKeyPair, KeyPair as _, RsaKeyPair, RsaPublicKeyComponents};
+use rsa::pkcs8::EncodePrivateKey;
 use std::fs;
+use std::io::Write;
 use std::path::{Path, PathBuf};
 use anyhow::{Result, Context};
+use crate::crypto::openssh_key;
+use crate::utils::wire::{Reader, Writer};
 
-/// Host key pair for server authentication
-pub struct HostKeyPair {
-    pub signing_key: SigningKey,
-    pub verifying_key: VerifyingKey,
+/// SSH algorithm name for Ed25519 keys and signatures (RFC 8709)
+pub const ED25519_ALGORITHM: &str = "ssh-ed25519";
+
+/// Key type name of RSA public keys (RFC 4253 §6.6)
+pub const RSA_KEY_TYPE: &str = "ssh-rsa";
+
+/// RSA signatures with SHA-256 and SHA-512 (RFC 8332)
+pub const RSA_SHA2_256_ALGORITHM: &str = "rsa-sha2-256";
+pub const RSA_SHA2_512_ALGORITHM: &str = "rsa-sha2-512";
+
+/// ECDSA keys and signatures (RFC 5656)
+pub const ECDSA_NISTP256_ALGORITHM: &str = "ecdsa-sha2-nistp256";
+pub const ECDSA_NISTP384_ALGORITHM: &str = "ecdsa-sha2-nistp384";
+
+/// Size of generated RSA keys
+const RSA_KEY_BITS: usize = 3072;
+
+/// Public key algorithms, named by the key type at the start of their blobs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyAlgorithm {
+    Ed25519,
+    EcdsaP256,
+    EcdsaP384,
+    Rsa,
 }
 
-impl HostKeyPair {
-    /// Generate a new host key pair
-    pub fn generate() -> Result<Self> {
-        let mut csprng = OsRng;
-        let signing_key = SigningKey::generate(&mut csprng);
-        let verifying_key = signing_key.verifying_key();
-        
-        Ok(Self {
-            signing_key,
-            verifying_key,
-        })
+impl KeyAlgorithm {
+    /// Every algorithm, most preferred first
+    pub const ALL: [KeyAlgorithm; 4] = [Self::Ed25519, Self::EcdsaP256, Self::EcdsaP384, Self::Rsa];
+
+    /// Key type name at the start of the public key blob
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Ed25519 => ED25519_ALGORITHM,
+            Self::Rsa => RSA_KEY_TYPE,
+            Self::EcdsaP256 => ECDSA_NISTP256_ALGORITHM,
+            Self::EcdsaP384 => ECDSA_NISTP384_ALGORITHM,
+        }
     }
 
-    /// Load host key from file or generate if it doesn't exist
-    pub fn load_or_generate(key_path: &Path) -> Result<Self> {
-        if key_path.exists() {
-            Self::load(key_path)
-        } else {
-            let key_pair = Self::generate()?;
-            key_pair.save(key_path)?;
-            Ok(key_pair)
+    /// Algorithm of a key type name
+    pub fn from_name(name: &str) -> Result<Self> {
+        Self::ALL.into_iter()
+            .find(|algorithm| algorithm.name() == name)
+            .with_context(|| format!("Unsupported key type: {}", name))
+    }
+
+    /// Signature algorithms a key of this type signs with, most preferred
+    /// first. RSA keys sign with SHA-2 only (RFC 8332); the SHA-1 `ssh-rsa`
+    /// signature is not offered.
+    pub fn signature_algorithms(self) -> &'static [&'static str] {
+        match self {
+            Self::Ed25519 => &[ED25519_ALGORITHM],
+            Self::Rsa => &[RSA_SHA2_512_ALGORITHM, RSA_SHA2_256_ALGORITHM],
+            Self::EcdsaP256 => &[ECDSA_NISTP256_ALGORITHM],
+            Self::EcdsaP384 => &[ECDSA_NISTP384_ALGORITHM],
         }
     }
 
-    /// Load host key from file
-    pub fn load(key_path: &Path) -> Result<Self> {
-        let data = fs::read(key_path)
-            .context("Failed to read host key file")?;
-        
-        let key_data: KeyData = serde_json::from_slice(&data)
-            .context("Failed to parse host key file")?;
-        
-        if key_data.private_key.len() != 32 {
-            anyhow::bail!("Invalid private key length: expected 32 bytes");
+    /// Whether a key of this type can produce `signature_algorithm` signatures
+    pub fn supports(self, signature_algorithm: &str) -> bool {
+        self.signature_algorithms().contains(&signature_algorithm)
+    }
+
+    /// Curve of an ECDSA key type
+    fn ecdsa_curve(self) -> Option<EcdsaCurve> {
+        match self {
+            Self::EcdsaP256 => Some(EcdsaCurve::NistP256),
+            Self::EcdsaP384 => Some(EcdsaCurve::NistP384),
+            Self::Ed25519 | Self::Rsa => None,
         }
-        
-        let private_key_array: [u8; 32] = key_data.private_key.try_into()
-            .map_err(|_| anyhow::anyhow!("Failed to convert private key to array"))?;
-        let signing_key = SigningKey::from_bytes(&private_key_array);
-        let verifying_key = signing_key.verifying_key();
-        
-        Ok(Self {
-            signing_key,
-            verifying_key,
-        })
     }
+}
 
-    /// Save host key to file
-    pub fn save(&self, key_path: &Path) -> Result<()> {
-        // Create parent directory if it doesn't exist
-        if let Some(parent) = key_path.parent() {
-            fs::create_dir_all(parent)
-                .context("Failed to create key directory")?;
+/// Curve of an ECDSA key (RFC 5656 §6.2)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EcdsaCurve {
+    NistP256,
+    NistP384,
+}
+
+impl EcdsaCurve {
+    /// Key type of keys on this curve
+    pub fn algorithm(self) -> KeyAlgorithm {
+        match self {
+            Self::NistP256 => KeyAlgorithm::EcdsaP256,
+            Self::NistP384 => KeyAlgorithm::EcdsaP384,
         }
+    }
 
-        let key_data = KeyData {
-            private_key: self.signing_key.to_bytes().to_vec(),
-            public_key: self.verifying_key.to_bytes().to_vec(),
-        };
+    /// Curve identifier in public key blobs
+    fn identifier(self) -> &'static str {
+        match self {
+            Self::NistP256 => "nistp256",
+            Self::NistP384 => "nistp384",
+        }
+    }
 
-        let json = serde_json::to_string_pretty(&key_data)
-            .context("Failed to serialize key data")?;
-        
-        fs::write(key_path, json)
-            .context("Failed to write host key file")?;
-        
-        Ok(())
+    fn signing(self) -> &'static signature::EcdsaSigningAlgorithm {
+        match self {
+            Self::NistP256 => &signature::ECDSA_P256_SHA256_FIXED_SIGNING,
+            Self::NistP384 => &signature::ECDSA_P384_SHA384_FIXED_SIGNING,
+        }
+    }
+
+    fn verification(self) -> &'static signature::EcdsaVerificationAlgorithm {
+        match self {
+            Self::NistP256 => &signature::ECDSA_P256_SHA256_FIXED,
+            Self::NistP384 => &signature::ECDSA_P384_SHA384_FIXED,
+        }
+    }
+
+    /// Size of r and s, which ring expects zero-padded to this length
+    fn scalar_len(self) -> usize {
+        match self {
+            Self::NistP256 => 32,
+            Self::NistP384 => 48,
+        }
     }
+}
+
+/// What a key pair is used for, which decides where it is kept
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyRole {
+    /// Server host key, signing the exchange hash
+    Host,
+    /// Client key for publickey authentication
+    User,
+}
 
-    /// Get public key bytes
-    pub fn public_key_bytes(&self) -> Vec<u8> {
-        self.verifying_key.to_bytes().to_vec()
+impl KeyRole {
+    /// File in ~/.ssh_edu holding the key of this role and algorithm:
+    /// `host_key`, `host_key_rsa`, `user_key_ecdsa_nistp256`, ...
+    pub fn file_name(self, algorithm: KeyAlgorithm) -> String {
+        let prefix = match self {
+            Self::Host => "host_key",
+            Self::User => "user_key",
+        };
+        let suffix = match algorithm {
+            KeyAlgorithm::Ed25519 => "",
+            KeyAlgorithm::Rsa => "_rsa",
+            KeyAlgorithm::EcdsaP256 => "_ecdsa_nistp256",
+            KeyAlgorithm::EcdsaP384 => "_ecdsa_nistp384",
+        };
+        format!("{}{}", prefix, suffix)
     }
 
-    /// Sign data with the host key
-    pub fn sign(&self, data: &[u8]) -> Signature {
-        self.signing_key.sign(data)
+    /// Path of the key of this role and algorithm
+    pub fn path(self, algorithm: KeyAlgorithm) -> Result<PathBuf> {
+        Ok(get_ssh_edu_dir()?.join(self.file_name(algorithm)))
     }
 }
 
-/// User key pair for client authentication
-pub struct UserKeyPair {
-    pub signing_key: SigningKey,
-    pub verifying_key: VerifyingKey,
+/// Private key of any supported algorithm. RSA and ECDSA keys keep their
+/// PKCS#8 encoding so they can be saved again.
+pub enum KeyPair {
+    Ed25519(SigningKey),
+    Rsa {
+        key_pair: RsaKeyPair,
+        pkcs8: Vec<u8>,
+    },
+    Ecdsa {
+        curve: EcdsaCurve,
+        key_pair: EcdsaKeyPair,
+        pkcs8: Vec<u8>,
+    },
 }
 
-impl UserKeyPair {
-    /// Generate a new user key pair
-    pub fn generate() -> Result<Self> {
-        let mut csprng = OsRng;
-        let signing_key = SigningKey::generate(&mut csprng);
-        let verifying_key = signing_key.verifying_key();
-        
-        Ok(Self {
-            signing_key,
-            verifying_key,
-        })
+impl KeyPair {
+    /// Generate a new key pair
+    pub fn generate(algorithm: KeyAlgorithm) -> Result<Self> {
+        match algorithm {
+            KeyAlgorithm::Ed25519 => Ok(Self::Ed25519(SigningKey::generate(&mut OsRng))),
+            KeyAlgorithm::Rsa => {
+                // ring cannot generate RSA keys, so the rsa crate does
+                let private_key = rsa::RsaPrivateKey::new(&mut OsRng, RSA_KEY_BITS)
+                    .context("Failed to generate RSA key")?;
+                let pkcs8 = private_key.to_pkcs8_der()
+                    .context("Failed to encode RSA key")?;
+                Self::from_pkcs8(algorithm, pkcs8.as_bytes().to_vec())
+            }
+            KeyAlgorithm::EcdsaP256 | KeyAlgorithm::EcdsaP384 => {
+                let curve = algorithm.ecdsa_curve().context("Not an ECDSA key type")?;
+                let pkcs8 = EcdsaKeyPair::generate_pkcs8(curve.signing(), &SystemRandom::new())
+                    .map_err(|_| anyhow::anyhow!("Failed to generate ECDSA key"))?;
+                Self::from_pkcs8(algorithm, pkcs8.as_ref().to_vec())
+            }
+        }
     }
 
-    /// Load user key from file or generate if it doesn't exist
-    pub fn load_or_generate(key_path: &Path) -> Result<Self> {
+    /// Load an RSA or ECDSA key from its PKCS#8 encoding
+    pub(crate) fn from_pkcs8(algorithm: KeyAlgorithm, pkcs8: Vec<u8>) -> Result<Self> {
+        match algorithm {
+            KeyAlgorithm::Ed25519 => anyhow::bail!("Ed25519 keys are not stored as PKCS#8"),
+            KeyAlgorithm::Rsa => {
+                let key_pair = RsaKeyPair::from_pkcs8(&pkcs8)
+                    .map_err(|e| anyhow::anyhow!("Invalid RSA key: {}", e))?;
+                Ok(Self::Rsa { key_pair, pkcs8 })
+            }
+            KeyAlgorithm::EcdsaP256 | KeyAlgorithm::EcdsaP384 => {
+                let curve = algorithm.ecdsa_curve().context("Not an ECDSA key type")?;
+                let key_pair = EcdsaKeyPair::from_pkcs8(curve.signing(), &pkcs8, &SystemRandom::new())
+                    .map_err(|e| anyhow::anyhow!("Invalid ECDSA key: {}", e))?;
+                Ok(Self::Ecdsa { curve, key_pair, pkcs8 })
+            }
+        }
+    }
+
+    /// Load key from file or generate if it doesn't exist
+    pub fn load_or_generate(key_path: &Path, algorithm: KeyAlgorithm) -> Result<Self> {
         if key_path.exists() {
-            Self::load(key_path)
+            let key_pair = Self::load(key_path)?;
+            if key_pair.algorithm() != algorithm {
+                anyhow::bail!(
+                    "{} holds a {} key, expected {}",
+                    key_path.display(),
+                    key_pair.algorithm().name(),
+                    algorithm.name()
+                );
+            }
+            Ok(key_pair)
         } else {
-            let key_pair = Self::generate()?;
+            let key_pair = Self::generate(algorithm)?;
             key_pair.save(key_path)?;
             Ok(key_pair)
         }
     }
 
-    /// Load user key from file
+    /// Load an unencrypted key from file
     pub fn load(key_path: &Path) -> Result<Self> {
-        let data = fs::read(key_path)
-            .context("Failed to read user key file")?;
-        
-        let key_data: KeyData = serde_json::from_slice(&data)
-            .context("Failed to parse user key file")?;
-        
+        Self::load_with_passphrase(key_path, None)
+    }
+
+    /// Load key from file: an OpenSSH private key, decrypted with
+    /// `passphrase` if it is encrypted, or a key in the older JSON format
+    pub fn load_with_passphrase(key_path: &Path, passphrase: Option<&str>) -> Result<Self> {
+        let text = fs::read_to_string(key_path)
+            .with_context(|| format!("Failed to read key file {}", key_path.display()))?;
+
+        if openssh_key::is_openssh_key(&text) {
+            let (key_pair, _comment) = openssh_key::decode(&text, passphrase)
+                .with_context(|| format!("Failed to load key file {}", key_path.display()))?;
+            return Ok(key_pair);
+        }
+
+        let key_data: KeyData = serde_json::from_str(&text)
+            .with_context(|| format!("Failed to parse key file {}", key_path.display()))?;
+
+        let algorithm = KeyAlgorithm::from_name(&key_data.key_type)?;
+        if algorithm != KeyAlgorithm::Ed25519 {
+            return Self::from_pkcs8(algorithm, key_data.private_key);
+        }
+
         if key_data.private_key.len() != 32 {
             anyhow::bail!("Invalid private key length: expected 32 bytes");
         }
-        
+
         let private_key_array: [u8; 32] = key_data.private_key.try_into()
             .map_err(|_| anyhow::anyhow!("Failed to convert private key to array"))?;
-        let signing_key = SigningKey::from_bytes(&private_key_array);
-        let verifying_key = signing_key.verifying_key();
-        
-        Ok(Self {
-            signing_key,
-            verifying_key,
-        })
+        Ok(Self::Ed25519(SigningKey::from_bytes(&private_key_array)))
     }
 
-    /// Save user key to file
+    /// Whether the key file is an encrypted OpenSSH private key
+    pub fn needs_passphrase(key_path: &Path) -> Result<bool> {
+        let text = fs::read_to_string(key_path)
+            .with_context(|| format!("Failed to read key file {}", key_path.display()))?;
+        if !openssh_key::is_openssh_key(&text) {
+            return Ok(false);
+        }
+        openssh_key::is_encrypted(&text)
+    }
+
+    /// Save key to file as an unencrypted OpenSSH private key
     pub fn save(&self, key_path: &Path) -> Result<()> {
+        self.save_with_passphrase(key_path, None)
+    }
+
+    /// Save key to file as an OpenSSH private key, encrypted if a passphrase
+    /// is given. Like ssh-keygen, the file is only readable by its owner.
+    pub fn save_with_passphrase(&self, key_path: &Path, passphrase: Option<&str>) -> Result<()> {
+        // Create parent directory if it doesn't exist
         if let Some(parent) = key_path.parent() {
             fs::create_dir_all(parent)
                 .context("Failed to create key directory")?;
         }
 
-        let key_data = KeyData {
-            private_key: self.signing_key.to_bytes().to_vec(),
-            public_key: self.verifying_key.to_bytes().to_vec(),
-        };
+        let text = openssh_key::encode(self, "", passphrase)?;
+
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(key_path)
+            .with_context(|| format!("Failed to write key file {}", key_path.display()))?;
+        file.write_all(text.as_bytes())
+            .with_context(|| format!("Failed to write key file {}", key_path.display()))?;
 
-        let json = serde_json::to_string_pretty(&key_data)
-            .context("Failed to serialize key data")?;
-        
-        fs::write(key_path, json)
-            .context("Failed to write user key file")?;
-        
         Ok(())
     }
 
-    /// Get public key bytes
-    pub fn public_key_bytes(&self) -> Vec<u8> {
-        self.verifying_key.to_bytes().to_vec()
+    /// Algorithm of this key
+    pub fn algorithm(&self) -> KeyAlgorithm {
+        match self {
+            Self::Ed25519(_) => KeyAlgorithm::Ed25519,
+            Self::Rsa { .. } => KeyAlgorithm::Rsa,
+            Self::Ecdsa { curve, .. } => curve.algorithm(),
+        }
+    }
+
+    /// Public half of the key
+    pub fn public_key(&self) -> PublicKey {
+        match self {
+            Self::Ed25519(signing_key) => {
+                PublicKey::Ed25519(signing_key.verifying_key().to_bytes().to_vec())
+            }
+            Self::Rsa { key_pair, .. } => {
+                let components = RsaPublicKeyComponents::<Vec<u8>>::from(key_pair.public());
+                PublicKey::Rsa { e: components.e, n: components.n }
+            }
+            Self::Ecdsa { curve, key_pair, .. } => PublicKey::Ecdsa {
+                curve: *curve,
+                point: key_pair.public_key().as_ref().to_vec(),
+            },
+        }
+    }
+
+    /// Public key in SSH blob format (K_S for host keys)
+    pub fn public_key_blob(&self) -> Vec<u8> {
+        self.public_key().to_blob()
     }
 
-    /// Sign data with the user key
-    pub fn sign(&self, data: &[u8]) -> Signature {
-        self.signing_key.sign(data)
+    /// Sign data. Returns the signature in SSH blob format,
+    /// `string algorithm || string signature`:
+    /// - Ed25519: the 64-byte signature
+    /// - RSA: the PKCS#1 v1.5 signature, as long as the modulus (RFC 8332 §3)
+    /// - ECDSA: `mpint r || mpint s` (RFC 5656 §3.1.2)
+    pub fn sign(&self, signature_algorithm: &str, data: &[u8]) -> Result<Vec<u8>> {
+        if !self.algorithm().supports(signature_algorithm) {
+            anyhow::bail!("A {} key cannot sign {}", self.algorithm().name(), signature_algorithm);
+        }
+        let rng = SystemRandom::new();
+        let signature = match self {
+            Self::Ed25519(signing_key) => signing_key.sign(data).to_bytes().to_vec(),
+            Self::Rsa { key_pair, .. } => {
+                let encoding: &'static dyn signature::RsaEncoding =
+                    if signature_algorithm == RSA_SHA2_512_ALGORITHM {
+                        &signature::RSA_PKCS1_SHA512
+                    } else {
+                        &signature::RSA_PKCS1_SHA256
+                    };
+                let mut signature = vec![0u8; key_pair.public().modulus_len()];
+                key_pair.sign(encoding, &rng, data, &mut signature)
+                    .map_err(|_| anyhow::anyhow!("Failed to sign with RSA key"))?;
+                signature
+            }
+            Self::Ecdsa { key_pair, curve, .. } => {
+                let fixed = key_pair.sign(&rng, data)
+                    .map_err(|_| anyhow::anyhow!("Failed to sign with ECDSA key"))?;
+                let (r, s) = fixed.as_ref().split_at(curve.scalar_len());
+                let mut writer = Writer::new();
+                writer.write_mpint(r);
+                writer.write_mpint(s);
+                writer.into_bytes()
+            }
+        };
+        Ok(encode_signature_blob(signature_algorithm, &signature))
     }
 }
 
-/// Verify a signature with a public key
-pub fn verify_signature(public_key: &[u8], message: &[u8], signature: &[u8]) -> Result<()> {
-    if public_key.len() != 32 {
-        anyhow::bail!("Invalid public key length: expected 32 bytes");
+/// Public key of any supported algorithm
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PublicKey {
+    /// The raw 32-byte key
+    Ed25519(Vec<u8>),
+    /// Exponent and modulus, big-endian without leading zeros
+    Rsa { e: Vec<u8>, n: Vec<u8> },
+    /// Uncompressed curve point `0x04 || x || y`
+    Ecdsa { curve: EcdsaCurve, point: Vec<u8> },
+}
+
+impl PublicKey {
+    /// Algorithm of this key
+    pub fn algorithm(&self) -> KeyAlgorithm {
+        match self {
+            Self::Ed25519(_) => KeyAlgorithm::Ed25519,
+            Self::Rsa { .. } => KeyAlgorithm::Rsa,
+            Self::Ecdsa { curve, .. } => curve.algorithm(),
+        }
+    }
+
+    /// Encode in SSH blob format:
+    /// - `string "ssh-ed25519" || string key` (RFC 8709)
+    /// - `string "ssh-rsa" || mpint e || mpint n` (RFC 4253 §6.6)
+    /// - `string "ecdsa-sha2-[curve]" || string "[curve]" || string Q` (RFC 5656 §3.1)
+    pub fn to_blob(&self) -> Vec<u8> {
+        let mut writer = Writer::new();
+        writer.write_string(self.algorithm().name().as_bytes());
+        match self {
+            Self::Ed25519(key) => writer.write_string(key),
+            Self::Rsa { e, n } => {
+                writer.write_mpint(e);
+                writer.write_mpint(n);
+            }
+            Self::Ecdsa { curve, point } => {
+                writer.write_string(curve.identifier().as_bytes());
+                writer.write_string(point);
+            }
+        }
+        writer.into_bytes()
+    }
+
+    /// Decode an SSH public key blob
+    pub fn from_blob(blob: &[u8]) -> Result<Self> {
+        let mut reader = Reader::new(blob);
+        let algorithm = KeyAlgorithm::from_name(&reader.read_utf8()?)?;
+        let key = match algorithm {
+            KeyAlgorithm::Ed25519 => {
+                let key = reader.read_string()?;
+                if key.len() != 32 {
+                    anyhow::bail!("Invalid public key length: expected 32 bytes");
+                }
+                Self::Ed25519(key.to_vec())
+            }
+            KeyAlgorithm::Rsa => {
+                let e = reader.read_mpint()?;
+                let n = reader.read_mpint()?;
+                Self::Rsa { e, n }
+            }
+            KeyAlgorithm::EcdsaP256 | KeyAlgorithm::EcdsaP384 => {
+                let curve = algorithm.ecdsa_curve().context("Not an ECDSA key type")?;
+                let identifier = reader.read_utf8()?;
+                if identifier != curve.identifier() {
+                    anyhow::bail!("Curve {} does not match key type {}", identifier, algorithm.name());
+                }
+                Self::Ecdsa { curve, point: reader.read_string()?.to_vec() }
+            }
+        };
+        reader.finish()?;
+        Ok(key)
+    }
+
+    /// OpenSSH-style fingerprint: `SHA256:` and the unpadded base64 SHA-256
+    /// of the blob
+    pub fn fingerprint(&self) -> String {
+        let hash = digest::digest(&digest::SHA256, &self.to_blob());
+        format!("SHA256:{}", STANDARD_NO_PAD.encode(hash))
+    }
+
+    /// OpenSSH public key line, as in `id_ed25519.pub` and authorized_keys:
+    /// `key-type base64(blob) comment`
+    pub fn to_openssh(&self, comment: &str) -> String {
+        let line = format!("{} {}", self.algorithm().name(), STANDARD.encode(self.to_blob()));
+        if comment.is_empty() {
+            line
+        } else {
+            format!("{} {}", line, comment)
+        }
+    }
+
+    /// Parse an OpenSSH public key line, returning the key and its comment
+    pub fn from_openssh(line: &str) -> Result<(Self, String)> {
+        let mut fields = line.trim().splitn(3, char::is_whitespace);
+        let key_type = fields.next().unwrap_or_default();
+        let blob = fields.next().context("Missing public key after key type")?;
+        let comment = fields.next().unwrap_or_default().trim().to_string();
+
+        let blob = STANDARD.decode(blob).context("Invalid base64 in public key")?;
+        let key = Self::from_blob(&blob)?;
+        if key.algorithm().name() != key_type {
+            anyhow::bail!("Key type {} does not match {} key", key_type, key.algorithm().name());
+        }
+        Ok((key, comment))
     }
+
+    /// Verify a `signature_algorithm` signature in SSH blob format. The
+    /// signature must be of the expected algorithm and the key of a type
+    /// that signs with it.
+    pub fn verify(&self, signature_algorithm: &str, message: &[u8], signature_blob: &[u8]) -> Result<()> {
+        let mut reader = Reader::new(signature_blob);
+        let blob_algorithm = reader.read_utf8()?;
+        let signature = reader.read_string()?;
+        reader.finish()?;
+        if blob_algorithm != signature_algorithm {
+            anyhow::bail!("Expected a {} signature, got {}", signature_algorithm, blob_algorithm);
+        }
+        if !self.algorithm().supports(signature_algorithm) {
+            anyhow::bail!(
+                "A {} key cannot verify {} signatures",
+                self.algorithm().name(),
+                signature_algorithm
+            );
+        }
+
+        match self {
+            Self::Ed25519(key) => verify_ed25519(key, message, signature),
+            Self::Rsa { e, n } => {
+                // Some implementations drop leading zero bytes of the signature
+                if signature.len() > n.len() {
+                    anyhow::bail!("RSA signature is longer than the modulus");
+                }
+                let mut padded = vec![0u8; n.len() - signature.len()];
+                padded.extend_from_slice(signature);
+                let parameters = if signature_algorithm == RSA_SHA2_512_ALGORITHM {
+                    &signature::RSA_PKCS1_2048_8192_SHA512
+                } else {
+                    &signature::RSA_PKCS1_2048_8192_SHA256
+                };
+                RsaPublicKeyComponents { n, e }
+                    .verify(parameters, message, &padded)
+                    .map_err(|_| anyhow::anyhow!("Signature verification failed"))
+            }
+            Self::Ecdsa { curve, point } => {
+                let mut reader = Reader::new(signature);
+                let r = reader.read_mpint()?;
+                let s = reader.read_mpint()?;
+                reader.finish()?;
+                let mut fixed = Vec::with_capacity(2 * curve.scalar_len());
+                for scalar in [r, s] {
+                    if scalar.len() > curve.scalar_len() {
+                        anyhow::bail!("ECDSA signature value is too long");
+                    }
+                    fixed.resize(fixed.len() + curve.scalar_len() - scalar.len(), 0);
+                    fixed.extend_from_slice(&scalar);
+                }
+                signature::UnparsedPublicKey::new(curve.verification(), point)
+                    .verify(message, &fixed)
+                    .map_err(|_| anyhow::anyhow!("Signature verification failed"))
+            }
+        }
+    }
+}
+
+/// Verify a raw 64-byte Ed25519 signature with a raw 32-byte public key
+fn verify_ed25519(public_key: &[u8], message: &[u8], signature: &[u8]) -> Result<()> {
     if signature.len() != 64 {
         anyhow::bail!("Invalid signature length: expected 64 bytes");
     }
-    
+
     let public_key_array: [u8; 32] = public_key.try_into()
-        .map_err(|_| anyhow::anyhow!("Failed to convert public key to array"))?;
+        .map_err(|_| anyhow::anyhow!("Invalid public key length: expected 32 bytes"))?;
     let verifying_key = VerifyingKey::from_bytes(&public_key_array)
         .map_err(|e| anyhow::anyhow!("Invalid public key bytes: {}", e))?;
-    
+
     let signature_array: [u8; 64] = signature.try_into()
         .map_err(|_| anyhow::anyhow!("Failed to convert signature to array"))?;
     let sig = Signature::from_bytes(&signature_array);
-    
+
     verifying_key.verify(message, &sig)
         .map_err(|e| anyhow::anyhow!("Signature verification failed: {}", e))?;
-    
+
     Ok(())
 }
 
-/// Key data structure for serialization
-#[derive(serde::Serialize, serde::Deserialize)]
+/// Wrap a raw signature in the SSH blob format: `string algorithm || string signature`
+pub fn encode_signature_blob(algorithm: &str, signature: &[u8]) -> Vec<u8> {
+    let mut writer = Writer::new();
+    writer.write_string(algorithm.as_bytes());
+    writer.write_string(signature);
+    writer.into_bytes()
+}
+
+/// Key file format used before OpenSSH private keys, still read so existing
+/// keys keep working. Ed25519 keys store the raw seed, RSA and ECDSA keys
+/// their PKCS#8 encoding. The public key stored next to it is derived again
+/// from the private key.
+#[derive(serde::Deserialize)]
 struct KeyData {
+    /// Key type name; files written before other types existed lack it
+    #[serde(default = "default_key_type")]
+    key_type: String,
     private_key: Vec<u8>,
-    public_key: Vec<u8>,
+}
+
+fn default_key_type() -> String {
+    ED25519_ALGORITHM.to_string()
 }
 
 /// Get SSH education directory path
@@ -213,3 +597,109 @@ pub fn get_ssh_edu_dir() -> Result<PathBuf> {
     Ok(PathBuf::from(home).join(".ssh_edu"))
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_sign_and_verify_every_algorithm() {
+        let message = b"exchange hash";
+        for algorithm in KeyAlgorithm::ALL {
+            let key_pair = KeyPair::generate(algorithm).unwrap();
+            let public_key = PublicKey::from_blob(&key_pair.public_key_blob()).unwrap();
+            assert_eq!(public_key, key_pair.public_key());
+            assert_eq!(public_key.algorithm(), algorithm);
+            for &signature_algorithm in algorithm.signature_algorithms() {
+                let signature = key_pair.sign(signature_algorithm, message).unwrap();
+                public_key.verify(signature_algorithm, message, &signature).unwrap();
+                assert!(public_key.verify(signature_algorithm, b"other data", &signature).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_mismatched_signature_algorithm() {
+        let key_pair = KeyPair::generate(KeyAlgorithm::Rsa).unwrap();
+        let signature = key_pair.sign(RSA_SHA2_256_ALGORITHM, b"data").unwrap();
+        // The blob says rsa-sha2-256, so it is no rsa-sha2-512 signature
+        assert!(key_pair.public_key().verify(RSA_SHA2_512_ALGORITHM, b"data", &signature).is_err());
+        // An ECDSA key cannot vouch for an RSA signature
+        let ecdsa_key = KeyPair::generate(KeyAlgorithm::EcdsaP256).unwrap().public_key();
+        assert!(ecdsa_key.verify(RSA_SHA2_256_ALGORITHM, b"data", &signature).is_err());
+        assert!(key_pair.sign(ED25519_ALGORITHM, b"data").is_err());
+    }
+
+    #[test]
+    fn key_files_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("key");
+        for algorithm in [KeyAlgorithm::Ed25519, KeyAlgorithm::EcdsaP384] {
+            let key_pair = KeyPair::generate(algorithm).unwrap();
+            key_pair.save(&path).unwrap();
+            let loaded = KeyPair::load_or_generate(&path, algorithm).unwrap();
+            assert_eq!(loaded.public_key(), key_pair.public_key());
+        }
+        assert!(KeyPair::load_or_generate(&path, KeyAlgorithm::Ed25519).is_err());
+    }
+
+    // Keys made with `ssh-keygen -t ed25519|ecdsa|rsa`; fingerprints as
+    // printed by `ssh-keygen -l`. The signatures are OpenSSH's over the same
+    // data, taken from `ssh-keygen -Y sign -n test` of "exchange hash": the
+    // signed data is the SSHSIG preamble with the SHA-512 of the message.
+    const SSHSIG_DATA: &str = "5353485349470000000474657374000000000000000673686135313200000040\
+        af1d3205f05173232429559726444a2e65dfcaed9d0d976a594ddbf965901bbfd388cc9d422ce505f3aba698\
+        c26ddbe74738cde81b4e5e572ed35f714f0885b2";
+
+    const OPENSSH_KEYS: [(&str, &str, &str, &str); 3] = [
+        (
+            "AAAAC3NzaC1lZDI1NTE5AAAAIO1YUvExSfp3vrrfbui784SBn9B/8g6gI4muTm5evpWI",
+            "SHA256:R3hDshdhrmIIczULWjc1W+5cEo5gubIoN8JeW8zPPjU",
+            ED25519_ALGORITHM,
+            "0000000b7373682d6564323535313900000040427844d8b08c5d3c42fff9a8f0c17a429ee01fd2275ca8ba4a\
+             b0d9439e12f156722528e9b885f3641ea096623c24e01772c2a8c5a7e3941e79c0c695f747f20c",
+        ),
+        (
+            "AAAAE2VjZHNhLXNoYTItbmlzdHAyNTYAAAAIbmlzdHAyNTYAAABBBOPYGsPlHWq6mtCfCi0IA4jExkNtQDJlPQnq\
+             g/GDGVllmLZ6o9f1CXV1XiSlouXaDiUB8S0s7+gzbPTiPmL8SG8=",
+            "SHA256:XzwXl+1v0WZssxjb5t+vGtKeW79Q4O4UMhAxditX10M",
+            ECDSA_NISTP256_ALGORITHM,
+            "0000001365636473612d736861322d6e697374703235360000004a0000002100bdc90210219c139697a711d9\
+             6992d4600aa0a78d6bc5eec499063f0c6e3a95790000002100eb3428f638e5ac4035a587476469b498ffecb6\
+             220557ee86527cfa62c779d493",
+        ),
+        (
+            "AAAAB3NzaC1yc2EAAAADAQABAAABAQDU4Bz3ovGRYaBRZoPNDPEphW4duPXaqIitDQkYtPjQhn3LJLvZXHqNohcG\
+             49OplR166Uv4zL1ZgvwCW+F7WS08gxYd2xygLT6nljASV0WN6TS91ZbIIjUgHFcFiOjBWBV7GAhgXbR2Ts9LikUk\
+             XTNFZh2sBSohftKr+f2HhcA53Igka+zp9dJSALw7FBHN//q6IU2/Tbh7alcvBB9w7u1A71fdoiqzx0Irv2r6RI1z\
+             Mtb8baaEw8+XlK3t20YM/qgwRebFkEWalX2SnqdgNW7mXeRuijlG2pRGdvXrHaz4whkoXGia9wsDb5hM3M9p65ZV\
+             8Z3+Pn4ReN1X19ZyOwxb",
+            "SHA256:UWAqrEig/s01k/NvBQKowz+5ZF7zqdWR6ylpfh/Acjg",
+            RSA_SHA2_512_ALGORITHM,
+            "0000000c7273612d736861322d353132000001009e9277c229c4fcb782657539787569d31ef30057d1f571af\
+             2e1fc37d898b9bfb74b7a0dd953d487e3fd05946f0fd8bea630918dea640d76c6e5abd146247271a44db9d91\
+             d91a7cb2ecb5a1dc43decb66992e3e449b83029ab8424162c5dfc962d83f066c112adc76209874bf5fe176e8\
+             13b569b307de904c33c634b9376999a7e02051f2ac84bd6aff4e424e0bb7593b101edfc7b7245b96ffbed738\
+             33d645129d775d76bc8e8f233b7eb44ed92df3e918e1729be92ecb6bf12d99fa22587085c01be6d8aaadefa8\
+             d34d0d94001a74d0acfb620c8d0a8188cf11b08a68ad6591284f2c2e4b794b245c00e075e4171cbdabe7bc60\
+             a784cc0c987eb2dd345c6950",
+        ),
+    ];
+
+    #[test]
+    fn openssh_keys_decode_fingerprint_and_verify() {
+        let data = hex::decode(SSHSIG_DATA).unwrap();
+        for (blob, fingerprint, signature_algorithm, signature) in OPENSSH_KEYS {
+            let blob = STANDARD.decode(blob).unwrap();
+            let public_key = PublicKey::from_blob(&blob).unwrap();
+            assert_eq!(public_key.to_blob(), blob);
+            assert_eq!(public_key.fingerprint(), fingerprint);
+
+            let line = public_key.to_openssh("alice@example com");
+            assert_eq!(PublicKey::from_openssh(&line).unwrap(), (public_key.clone(), "alice@example com".to_string()));
+
+            let signature = hex::decode(signature).unwrap();
+            public_key.verify(signature_algorithm, &data, &signature).unwrap();
+            assert!(public_key.verify(signature_algorithm, b"exchange hash", &signature).is_err());
+        }
+    }
+}
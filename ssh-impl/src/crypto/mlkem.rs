@@ -0,0 +1,213 @@
+use ml_kem::kem::{Decapsulate, Encapsulate};
+use ml_kem::{Ciphertext, Encoded, EncodedSizeUser, KemCore, MlKem768};
+use ring::{agreement, digest};
+use anyhow::{Result, Context};
+use crate::crypto::dh::EphemeralKeyPair;
+use crate::crypto::kex::KexMethod;
+use crate::utils::wire::Writer;
+
+type DecapsulationKey = <MlKem768 as KemCore>::DecapsulationKey;
+type EncapsulationKey = <MlKem768 as KemCore>::EncapsulationKey;
+
+/// Size of an ML-KEM-768 encapsulation key (FIPS 203 §8)
+const ENCAPSULATION_KEY_LEN: usize = 1184;
+
+/// Size of an ML-KEM-768 ciphertext (FIPS 203 §8)
+const CIPHERTEXT_LEN: usize = 1088;
+
+/// Size of an X25519 public key
+const X25519_KEY_LEN: usize = 32;
+
+/// mlkem768x25519-sha256 (draft-ietf-sshm-mlkem-hybrid-kex): ML-KEM-768
+/// and X25519 side by side, so the session stays secure as long as either
+/// holds.
+///
+/// The client sends C_INIT = ML-KEM encapsulation key || X25519 public key,
+/// the server answers with S_REPLY = ML-KEM ciphertext || X25519 public key.
+/// K = SHA-256(K_PQ || K_CL) of the two shared secrets and, unlike the
+/// other methods, is encoded as a string.
+pub struct MlKem768X25519 {
+    /// Client's private keys until the server's reply arrives
+    client_keys: Option<(DecapsulationKey, agreement::EphemeralPrivateKey)>,
+}
+
+impl Default for MlKem768X25519 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MlKem768X25519 {
+    pub fn new() -> Self {
+        Self { client_keys: None }
+    }
+}
+
+impl KexMethod for MlKem768X25519 {
+    fn hash_algorithm(&self) -> &'static digest::Algorithm {
+        &digest::SHA256
+    }
+
+    fn client_public_key(&mut self) -> Result<Vec<u8>> {
+        let (decapsulation_key, encapsulation_key) = MlKem768::generate(&mut rand::thread_rng());
+        let x25519 = EphemeralKeyPair::generate(&agreement::X25519)
+            .context("Failed to generate client key pair")?;
+
+        let mut client_init = encapsulation_key.as_bytes().to_vec();
+        client_init.extend_from_slice(&x25519.public_key);
+        self.client_keys = Some((decapsulation_key, x25519.private_key));
+        Ok(client_init)
+    }
+
+    fn client_shared_secret(&mut self, server_public_key: &[u8]) -> Result<Vec<u8>> {
+        let (decapsulation_key, x25519_private_key) = self.client_keys.take()
+            .context("Client key pair was not generated")?;
+        if server_public_key.len() != CIPHERTEXT_LEN + X25519_KEY_LEN {
+            anyhow::bail!("Invalid hybrid server reply length {}", server_public_key.len());
+        }
+        let (ciphertext, x25519_public_key) = server_public_key.split_at(CIPHERTEXT_LEN);
+
+        let ciphertext = Ciphertext::<MlKem768>::try_from(ciphertext)
+            .map_err(|_| anyhow::anyhow!("Invalid ML-KEM ciphertext"))?;
+        let pq_secret = decapsulation_key.decapsulate(&ciphertext)
+            .map_err(|_| anyhow::anyhow!("Failed to decapsulate ML-KEM ciphertext"))?;
+        let classical_secret = EphemeralKeyPair::compute_shared_secret(
+            x25519_private_key,
+            x25519_public_key,
+        )?;
+        Ok(combine_secrets(&pq_secret, &classical_secret))
+    }
+
+    fn server_exchange(&mut self, client_public_key: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+        if client_public_key.len() != ENCAPSULATION_KEY_LEN + X25519_KEY_LEN {
+            anyhow::bail!("Invalid hybrid client key length {}", client_public_key.len());
+        }
+        let (encapsulation_key, x25519_public_key) = client_public_key.split_at(ENCAPSULATION_KEY_LEN);
+
+        let encapsulation_key = parse_encapsulation_key(encapsulation_key)?;
+        let (ciphertext, pq_secret) = encapsulation_key.encapsulate(&mut rand::thread_rng())
+            .map_err(|_| anyhow::anyhow!("Failed to encapsulate ML-KEM secret"))?;
+        let x25519 = EphemeralKeyPair::generate(&agreement::X25519)
+            .context("Failed to generate server key pair")?;
+        let classical_secret = EphemeralKeyPair::compute_shared_secret(
+            x25519.private_key,
+            x25519_public_key,
+        )?;
+
+        let mut server_reply = ciphertext.to_vec();
+        server_reply.extend_from_slice(&x25519.public_key);
+        Ok((server_reply, combine_secrets(&pq_secret, &classical_secret)))
+    }
+
+    fn encode_shared_secret(&self, shared_secret: &[u8]) -> Vec<u8> {
+        let mut writer = Writer::new();
+        writer.write_string(shared_secret);
+        writer.into_bytes()
+    }
+}
+
+/// Decode the client's encapsulation key, with the modulus check of
+/// FIPS 203 §7.2: every coefficient must already be reduced mod q, which
+/// holds exactly when decoding and re-encoding gives the same bytes
+fn parse_encapsulation_key(bytes: &[u8]) -> Result<EncapsulationKey> {
+    let encoded = Encoded::<EncapsulationKey>::try_from(bytes)
+        .map_err(|_| anyhow::anyhow!("Invalid ML-KEM encapsulation key length {}", bytes.len()))?;
+    let encapsulation_key = EncapsulationKey::from_bytes(&encoded);
+    if encapsulation_key.as_bytes() != encoded {
+        anyhow::bail!("ML-KEM encapsulation key is not reduced mod q");
+    }
+    Ok(encapsulation_key)
+}
+
+/// K = SHA-256(K_PQ || K_CL)
+fn combine_secrets(pq_secret: &[u8], classical_secret: &[u8]) -> Vec<u8> {
+    let mut context = digest::Context::new(&digest::SHA256);
+    context.update(pq_secret);
+    context.update(classical_secret);
+    context.finish().as_ref().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ml_kem::{EncapsulateDeterministic, B32};
+
+    // Vectors from NIST's ACVP ML-KEM test files (ACVP-Server
+    // gen-val/json-files, internalProjection.json), ML-KEM-768 groups. The
+    // long keys and ciphertexts are checked by their SHA-256.
+
+    fn sha256_hex(data: &[u8]) -> String {
+        hex::encode(digest::digest(&digest::SHA256, data))
+    }
+
+    fn b32(hex_value: &str) -> B32 {
+        B32::try_from(hex::decode(hex_value).unwrap().as_slice()).unwrap()
+    }
+
+    fn nist_encapsulation_key() -> Vec<u8> {
+        hex::decode(include_str!("testdata/mlkem768_encapsulation_key.hex").trim()).unwrap()
+    }
+
+    #[test]
+    fn key_generation_matches_nist_vector() {
+        // ML-KEM-keyGen-FIPS203, tgId 2, tcId 26
+        let (decapsulation_key, encapsulation_key) = MlKem768::generate_deterministic(
+            &b32("E34A701C4C87582F42264EE422D3C684D97611F2523EFE0C998AF05056D693DC"),
+            &b32("A85768F3486BD32A01BF9A8F21EA938E648EAE4E5448C34C3EB88820B159EEDD"),
+        );
+        assert_eq!(
+            sha256_hex(&encapsulation_key.as_bytes()),
+            "7799c9d8eef172aa78c073514f2f039c240de8c5cb61bca82ba0bc46041ce279"
+        );
+        assert_eq!(
+            sha256_hex(&decapsulation_key.as_bytes()),
+            "104b3444c3de2b81143788d27e17648f45c80f617f906156db2258da96dead40"
+        );
+    }
+
+    #[test]
+    fn encapsulation_matches_nist_vector() {
+        // ML-KEM-encapDecap-FIPS203, tgId 2, tcId 26
+        let encapsulation_key = parse_encapsulation_key(&nist_encapsulation_key()).unwrap();
+        let (ciphertext, shared_secret) = encapsulation_key
+            .encapsulate_deterministic(&b32(
+                "2CE74AD291133518FE60C7DF5D251B9D82ADD48462FF505C6E547E949E6B6BF7",
+            ))
+            .unwrap();
+        assert_eq!(
+            sha256_hex(&ciphertext),
+            "ac57163b80ead205b8323e1402b8ca66bece40d8df9994b12d43bbb4f6e19bf4"
+        );
+        assert_eq!(
+            hex::encode(shared_secret),
+            "2696d28e9c61c2a01ce9b1608dcb9d292785a0cd58efb7fe13b1de95f0db55b3"
+        );
+    }
+
+    #[test]
+    fn rejects_unreduced_encapsulation_key() {
+        let mut encapsulation_key = nist_encapsulation_key();
+        // First 12-bit coefficient 0xFFF >= q = 3329
+        encapsulation_key[0] = 0xFF;
+        encapsulation_key[1] |= 0x0F;
+        assert!(parse_encapsulation_key(&encapsulation_key).is_err());
+    }
+
+    #[test]
+    fn client_and_server_agree() {
+        let mut client = MlKem768X25519::new();
+        let mut server = MlKem768X25519::new();
+
+        let client_init = client.client_public_key().unwrap();
+        assert_eq!(client_init.len(), ENCAPSULATION_KEY_LEN + X25519_KEY_LEN);
+        let (server_reply, server_secret) = server.server_exchange(&client_init).unwrap();
+        assert_eq!(server_reply.len(), CIPHERTEXT_LEN + X25519_KEY_LEN);
+        let client_secret = client.client_shared_secret(&server_reply).unwrap();
+
+        assert_eq!(client_secret, server_secret);
+        assert_eq!(client_secret.len(), 32);
+        // A string, so a leading zero byte is kept
+        assert_eq!(client.encode_shared_secret(&client_secret)[..4], [0, 0, 0, 32]);
+        assert!(server.server_exchange(&client_init[1..]).is_err());
+    }
+}
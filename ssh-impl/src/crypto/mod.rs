@@ -1,4 +1,13 @@
 pub mod dh;
+pub mod kex;
+pub mod ffdh;
+pub mod mlkem;
+pub mod moduli;
 pub mod keys;
+pub mod openssh_key;
+pub mod password;
 pub mod encryption;
+pub mod chacha;
+pub mod aes_ctr;
+pub mod mac;
 
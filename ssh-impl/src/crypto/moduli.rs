@@ -0,0 +1,130 @@
+use std::path::Path;
+use num_bigint::BigUint;
+use rand::seq::SliceRandom;
+use anyhow::{Result, Context};
+
+/// Default location of the moduli file on OpenSSH systems
+pub const DEFAULT_MODULI_FILE: &str = "/etc/ssh/moduli";
+
+/// `type` of safe primes, p = 2q + 1 with q prime
+const MODULI_TYPE_SAFE: u32 = 2;
+
+/// `tests` bit marking a modulus found to be composite
+const MODULI_TESTS_COMPOSITE: u32 = 0x01;
+
+/// A Diffie-Hellman group for diffie-hellman-group-exchange
+#[derive(Debug, Clone)]
+pub struct DhGroup {
+    pub prime: BigUint,
+    pub generator: BigUint,
+}
+
+impl DhGroup {
+    /// Size of the prime in bits
+    pub fn bits(&self) -> u32 {
+        self.prime.bits() as u32
+    }
+}
+
+/// Parse one line of a moduli file (moduli(5)):
+/// `timestamp type tests trials size generator modulus`, where `size` is the
+/// bit length minus one. Comments, blank lines and moduli that are not
+/// tested safe primes give `None`.
+fn parse_moduli_line(line: &str) -> Result<Option<DhGroup>> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let [_timestamp, moduli_type, tests, trials, size, generator, modulus] = fields[..] else {
+        anyhow::bail!("Expected 7 fields, found {}", fields.len());
+    };
+    let moduli_type: u32 = moduli_type.parse().context("Invalid type")?;
+    let tests: u32 = tests.parse().context("Invalid tests")?;
+    let trials: u32 = trials.parse().context("Invalid trials")?;
+    let size: u64 = size.parse().context("Invalid size")?;
+    let generator: BigUint = generator.parse().context("Invalid generator")?;
+    let prime = BigUint::parse_bytes(modulus.as_bytes(), 16)
+        .context("Invalid modulus")?;
+
+    if moduli_type != MODULI_TYPE_SAFE
+        || tests & MODULI_TESTS_COMPOSITE != 0
+        || tests & !MODULI_TESTS_COMPOSITE == 0
+        || trials == 0
+    {
+        return Ok(None);
+    }
+    if prime.bits() != size + 1 {
+        anyhow::bail!("Modulus has {} bits, size field says {}", prime.bits(), size + 1);
+    }
+    Ok(Some(DhGroup { prime, generator }))
+}
+
+/// Read the safe primes of an OpenSSH moduli file
+pub fn load_moduli(path: &Path) -> Result<Vec<DhGroup>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read moduli file {}", path.display()))?;
+    content.lines()
+        .enumerate()
+        .filter_map(|(index, line)| {
+            parse_moduli_line(line)
+                .with_context(|| format!("{}:{}: invalid modulus", path.display(), index + 1))
+                .transpose()
+        })
+        .collect()
+}
+
+/// Pick a group for a client asking for `min <= bits <= max`, preferably
+/// `preferred` bits: the smallest size of at least `preferred` bits, or else
+/// the largest size below it, and a random group of that size
+pub fn choose_group(groups: &[DhGroup], min: u32, preferred: u32, max: u32) -> Option<DhGroup> {
+    let in_range = || groups.iter().filter(|group| (min..=max).contains(&group.bits()));
+    let bits = in_range().map(DhGroup::bits).filter(|&bits| bits >= preferred).min()
+        .or_else(|| in_range().map(DhGroup::bits).max())?;
+    let candidates: Vec<&DhGroup> = in_range().filter(|group| group.bits() == bits).collect();
+    candidates.choose(&mut rand::thread_rng()).map(|&group| group.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2^127 - 1 is prime but not a safe prime; the parser only checks the
+    // fields, so it serves as a small modulus
+    const MODULUS: &str = "7FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF";
+
+    #[test]
+    fn parses_only_tested_safe_primes() {
+        let group = parse_moduli_line(&format!("20260101000000 2 6 100 126 5 {}", MODULUS))
+            .unwrap()
+            .unwrap();
+        assert_eq!(group.bits(), 127);
+        assert_eq!(group.generator, BigUint::from(5u32));
+
+        for line in [
+            "# comment".to_string(),
+            String::new(),
+            format!("20260101000000 4 6 100 126 5 {}", MODULUS),
+            format!("20260101000000 2 7 100 126 5 {}", MODULUS),
+            format!("20260101000000 2 0 100 126 5 {}", MODULUS),
+            format!("20260101000000 2 6 0 126 5 {}", MODULUS),
+        ] {
+            assert!(parse_moduli_line(&line).unwrap().is_none(), "{}", line);
+        }
+        assert!(parse_moduli_line(&format!("20260101000000 2 6 100 1023 5 {}", MODULUS)).is_err());
+        assert!(parse_moduli_line("20260101000000 2 6 100 126 5").is_err());
+    }
+
+    #[test]
+    fn chooses_smallest_group_of_preferred_size() {
+        let group = |bits: u32| DhGroup {
+            prime: BigUint::from(1u32) << (bits - 1),
+            generator: BigUint::from(2u32),
+        };
+        let groups = [group(2048), group(3072), group(4096), group(8192)];
+        assert_eq!(choose_group(&groups, 2048, 3000, 8192).unwrap().bits(), 3072);
+        assert_eq!(choose_group(&groups, 2048, 7000, 6000).unwrap().bits(), 4096);
+        assert!(choose_group(&groups, 1024, 1024, 1536).is_none());
+    }
+}
@@ -1,6 +1,16 @@
-use clap::{Parser, Subcommand};
-use ssh_impl::server;
-use ssh_impl::client;
+use anyhow::Result;
+use clap::{Args, Parser, Subcommand};
+use std::io::{self, IsTerminal, Write};
+use std::path::PathBuf;
+use std::time::Duration;
+use ssh_impl::crypto::keys::{KeyAlgorithm, KeyPair, KeyRole};
+use ssh_impl::protocol::client_auth::AuthPolicy;
+use ssh_impl::protocol::authorized_keys::{authorized_keys_path, import_public_keys};
+use ssh_impl::protocol::negotiation::AlgorithmPreferences;
+use ssh_impl::protocol::users::UserDatabase;
+use ssh_impl::protocol::transport::RekeyLimits;
+use ssh_impl::server::{self, ServerConfig};
+use ssh_impl::client::{self, ClientConfig};
 use std::process;
 
 #[derive(Parser)]
@@ -18,6 +28,15 @@ enum Commands {
         /// Port to listen on
         #[arg(short, long, default_value = "2222")]
         port: u16,
+        /// Moduli file with the groups offered in diffie-hellman-group-exchange
+        #[arg(long)]
+        moduli: Option<PathBuf>,
+        #[command(flatten)]
+        algorithms: AlgorithmArgs,
+        #[command(flatten)]
+        rekey: RekeyArgs,
+        #[command(flatten)]
+        auth: AuthArgs,
     },
     /// Run SSH client
     Client {
@@ -30,25 +49,335 @@ enum Commands {
         /// Username
         #[arg(short, long)]
         user: String,
+        /// Private key for public key authentication (OpenSSH format)
+        #[arg(short, long)]
+        identity: Option<PathBuf>,
+        #[command(flatten)]
+        algorithms: AlgorithmArgs,
+        #[command(flatten)]
+        rekey: RekeyArgs,
+    },
+    /// Export and import OpenSSH public keys
+    Key {
+        #[command(subcommand)]
+        command: KeyCommand,
+    },
+    /// Set up ~/.ssh_edu: host keys and an empty user database
+    Init {
+        /// Also add this user, asking for a password
+        #[arg(short, long)]
+        user: Option<String>,
+    },
+    /// Manage the users that may log in with a password
+    User {
+        #[command(subcommand)]
+        command: UserCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum KeyCommand {
+    /// Print one of our public keys as an OpenSSH public key line
+    Export {
+        /// Export the server host key instead of the user key
+        #[arg(long)]
+        host: bool,
+        /// Key type
+        #[arg(short = 't', long, default_value = "ssh-ed25519")]
+        key_type: String,
+    },
+    /// Authorize the keys of an OpenSSH public key file (e.g. id_ed25519.pub)
+    Import {
+        /// User the keys may log in as
+        #[arg(short, long)]
+        user: String,
+        /// Public key file
+        file: PathBuf,
     },
 }
 
+#[derive(Subcommand)]
+enum UserCommand {
+    /// Add a user, asking for a password
+    Add {
+        user: String,
+    },
+    /// Change a user's password
+    Passwd {
+        user: String,
+    },
+    /// Delete a user
+    Del {
+        user: String,
+    },
+    /// List users
+    List,
+}
+
+/// Algorithm preference overrides, most preferred first
+#[derive(Args)]
+struct AlgorithmArgs {
+    /// Key exchange algorithms (comma-separated)
+    #[arg(long, value_delimiter = ',')]
+    kex: Vec<String>,
+    /// Host key algorithms (comma-separated)
+    #[arg(long, value_delimiter = ',')]
+    host_key_algorithms: Vec<String>,
+    /// Ciphers (comma-separated)
+    #[arg(long, value_delimiter = ',')]
+    ciphers: Vec<String>,
+    /// MAC algorithms for non-AEAD ciphers (comma-separated)
+    #[arg(long, value_delimiter = ',')]
+    macs: Vec<String>,
+    /// Compression algorithms (comma-separated)
+    #[arg(long, value_delimiter = ',')]
+    compression: Vec<String>,
+}
+
+impl AlgorithmArgs {
+    /// Apply the overrides on top of the default preferences
+    fn into_preferences(self) -> AlgorithmPreferences {
+        let mut preferences = AlgorithmPreferences::default();
+        if !self.kex.is_empty() {
+            preferences.kex = self.kex;
+        }
+        if !self.host_key_algorithms.is_empty() {
+            preferences.host_key = self.host_key_algorithms;
+        }
+        if !self.ciphers.is_empty() {
+            preferences.ciphers = self.ciphers;
+        }
+        if !self.macs.is_empty() {
+            preferences.macs = self.macs;
+        }
+        if !self.compression.is_empty() {
+            preferences.compression = self.compression;
+        }
+        preferences
+    }
+}
+
+/// Key re-exchange limit overrides
+#[derive(Args)]
+struct RekeyArgs {
+    /// Renew keys after this many bytes (default 1 GiB)
+    #[arg(long)]
+    rekey_bytes: Option<u64>,
+    /// Renew keys after this many packets
+    #[arg(long)]
+    rekey_packets: Option<u64>,
+    /// Renew keys after this many seconds, 0 to disable (default 3600)
+    #[arg(long)]
+    rekey_seconds: Option<u64>,
+}
+
+impl RekeyArgs {
+    /// Apply the overrides on top of the default limits
+    fn into_limits(self) -> RekeyLimits {
+        let mut limits = RekeyLimits::default();
+        if let Some(bytes) = self.rekey_bytes {
+            limits.bytes = bytes;
+        }
+        if let Some(packets) = self.rekey_packets {
+            limits.packets = packets;
+        }
+        if let Some(seconds) = self.rekey_seconds {
+            limits.interval = (seconds > 0).then(|| Duration::from_secs(seconds));
+        }
+        limits
+    }
+}
+
+/// User authentication policy overrides
+#[derive(Args)]
+struct AuthArgs {
+    /// Method lists a user must complete, e.g. "publickey,password publickey"
+    /// (default: "publickey password")
+    #[arg(long)]
+    auth_methods: Option<String>,
+    /// Failed authentication attempts before disconnecting (default 6)
+    #[arg(long)]
+    max_auth_tries: Option<u32>,
+}
+
+impl AuthArgs {
+    /// Apply the overrides on top of the default policy
+    fn into_policy(self) -> Result<AuthPolicy> {
+        let mut policy = AuthPolicy::default();
+        if let Some(methods) = self.auth_methods {
+            policy.method_chains = AuthPolicy::parse_methods(&methods)?;
+        }
+        if let Some(tries) = self.max_auth_tries {
+            policy.max_tries = tries;
+        }
+        Ok(policy)
+    }
+}
+
+/// Run a `key` subcommand
+fn run_key_command(command: KeyCommand) -> Result<()> {
+    match command {
+        KeyCommand::Export { host, key_type } => {
+            let role = if host { KeyRole::Host } else { KeyRole::User };
+            let key_path = role.path(KeyAlgorithm::from_name(&key_type)?)?;
+            if !key_path.exists() {
+                anyhow::bail!("No key at {}", key_path.display());
+            }
+            println!("{}", KeyPair::load(&key_path)?.public_key().to_openssh(""));
+        }
+        KeyCommand::Import { user, file } => {
+            let added = import_public_keys(&user, &file)?;
+            println!(
+                "Added {} key(s) to {}",
+                added,
+                authorized_keys_path(&user)?.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Run `init`: create what the server needs before the first login
+fn run_init(user: Option<String>) -> Result<()> {
+    for algorithm in KeyAlgorithm::ALL {
+        let key_path = KeyRole::Host.path(algorithm)?;
+        let key = KeyPair::load_or_generate(&key_path, algorithm)?;
+        println!("Host key {}: {}", key_path.display(), key.public_key().fingerprint());
+    }
+
+    let users_file = UserDatabase::default_path()?;
+    let mut users = if users_file.exists() {
+        UserDatabase::load(&users_file)?
+    } else {
+        UserDatabase::new(&users_file)
+    };
+    if let Some(user) = user {
+        users.set_password(&user, &read_new_password()?)?;
+        println!("Added user {}", user);
+    }
+    users.save()?;
+    println!("User database {} ({} user(s))", users_file.display(), users.users.len());
+    Ok(())
+}
+
+/// Run a `user` subcommand
+fn run_user_command(command: UserCommand) -> Result<()> {
+    let users_file = UserDatabase::default_path()?;
+    if !users_file.exists() {
+        anyhow::bail!(
+            "No user database at {}, create one with `ssh-impl init`",
+            users_file.display()
+        );
+    }
+    let mut users = UserDatabase::load(&users_file)?;
+    match command {
+        UserCommand::Add { user } => {
+            if users.users.contains_key(&user) {
+                anyhow::bail!("User {} already exists", user);
+            }
+            users.set_password(&user, &read_new_password()?)?;
+            println!("Added user {}", user);
+        }
+        UserCommand::Passwd { user } => {
+            if !users.users.contains_key(&user) {
+                anyhow::bail!("No user {}", user);
+            }
+            users.set_password(&user, &read_new_password()?)?;
+            println!("Changed the password of {}", user);
+        }
+        UserCommand::Del { user } => {
+            if !users.remove(&user) {
+                anyhow::bail!("No user {}", user);
+            }
+            println!("Deleted user {}", user);
+        }
+        UserCommand::List => {
+            for user in users.users.keys() {
+                println!("{}", user);
+            }
+            return Ok(());
+        }
+    }
+    users.save()
+}
+
+/// Ask for a new password twice, without echoing it on a terminal.
+/// Piped input is read line by line so the commands can be scripted.
+fn read_new_password() -> Result<String> {
+    let read = |prompt: &str| -> Result<String> {
+        if io::stdin().is_terminal() {
+            return Ok(rpassword::prompt_password(prompt)?);
+        }
+        print!("{}", prompt);
+        io::stdout().flush()?;
+        let mut password = String::new();
+        io::stdin().read_line(&mut password)?;
+        Ok(password.trim_end_matches(['\r', '\n']).to_string())
+    };
+    let password = read("New password: ")?;
+    if password.is_empty() {
+        anyhow::bail!("Empty password");
+    }
+    if read("Retype new password: ")? != password {
+        anyhow::bail!("Passwords do not match");
+    }
+    Ok(password)
+}
+
 fn main() {
     let cli = Cli::parse();
 
     match cli.command {
-        Commands::Server { port } => {
-            if let Err(e) = server::run(port) {
-                eprintln!("Server error: {}", e);
+        Commands::Server { port, moduli, algorithms, rekey, auth } => {
+            let auth = match auth.into_policy() {
+                Ok(auth) => auth,
+                Err(e) => {
+                    eprintln!("Server error: {:#}", e);
+                    process::exit(1);
+                }
+            };
+            let mut config = ServerConfig {
+                algorithms: algorithms.into_preferences(),
+                rekey: rekey.into_limits(),
+                auth,
+                ..ServerConfig::new(port)
+            };
+            if let Some(moduli) = moduli {
+                config.moduli_file = moduli;
+            }
+            if let Err(e) = server::run_with_config(&config) {
+                eprintln!("Server error: {:#}", e);
                 process::exit(1);
             }
         }
-        Commands::Client { host, port, user } => {
-            if let Err(e) = client::connect(&host, port, &user) {
-                eprintln!("Client error: {}", e);
+        Commands::Client { host, port, user, identity, algorithms, rekey } => {
+            let config = ClientConfig {
+                algorithms: algorithms.into_preferences(),
+                rekey: rekey.into_limits(),
+                identity,
+            };
+            if let Err(e) = client::connect_with_config(&host, port, &user, &config) {
+                eprintln!("Client error: {:#}", e);
+                process::exit(1);
+            }
+        }
+        Commands::Key { command } => {
+            if let Err(e) = run_key_command(command) {
+                eprintln!("Key error: {:#}", e);
+                process::exit(1);
+            }
+        }
+        Commands::Init { user } => {
+            if let Err(e) = run_init(user) {
+                eprintln!("Init error: {:#}", e);
+                process::exit(1);
+            }
+        }
+        Commands::User { command } => {
+            if let Err(e) = run_user_command(command) {
+                eprintln!("User error: {:#}", e);
                 process::exit(1);
             }
         }
     }
 }
-
@@ -0,0 +1,502 @@
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use anyhow::{Result, Context};
+use crate::crypto::keys::{get_ssh_edu_dir, KeyAlgorithm, PublicKey};
+use crate::protocol::session::SessionRestrictions;
+
+/// A key allowed to log in as a user
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthorizedKey {
+    pub options: KeyOptions,
+    pub key: PublicKey,
+    pub comment: String,
+}
+
+/// Options in front of an authorized_keys line (sshd(8) AUTHORIZED_KEYS FILE
+/// FORMAT). `from=` and `expiry-time=` decide whether the key may log in at
+/// all, the rest restrict the session. A key whose restrictions the session
+/// cannot enforce (a forced command) is refused.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyOptions {
+    /// Patterns the client address must match, empty for any (`from=`)
+    pub from: Vec<String>,
+    /// When the key stops being accepted (`expiry-time=`)
+    pub expiry_time: Option<SystemTime>,
+    pub restrictions: SessionRestrictions,
+}
+
+impl KeyOptions {
+    /// Parse the comma-separated options field. Values are double-quoted and
+    /// may contain commas, spaces and `\"`.
+    pub fn parse(field: &str) -> Result<Self> {
+        let mut options = Self::default();
+        let restrictions = &mut options.restrictions;
+        for (name, value) in split_options(field)? {
+            let flag = |value: Option<String>| match value {
+                Some(_) => anyhow::bail!("Option {} takes no value", name),
+                None => Ok(()),
+            };
+            let required = |value: Option<String>| {
+                value.with_context(|| format!("Option {} needs a value", name))
+            };
+            match name.to_ascii_lowercase().as_str() {
+                "restrict" => {
+                    flag(value)?;
+                    restrictions.no_pty = true;
+                    restrictions.no_port_forwarding = true;
+                    restrictions.no_agent_forwarding = true;
+                }
+                // Later options override earlier ones, so `restrict,pty`
+                // allows just a pty
+                "no-pty" | "pty" => {
+                    flag(value)?;
+                    restrictions.no_pty = name.eq_ignore_ascii_case("no-pty");
+                }
+                "no-port-forwarding" | "port-forwarding" => {
+                    flag(value)?;
+                    restrictions.no_port_forwarding = name.eq_ignore_ascii_case("no-port-forwarding");
+                }
+                "no-agent-forwarding" | "agent-forwarding" => {
+                    flag(value)?;
+                    restrictions.no_agent_forwarding = name.eq_ignore_ascii_case("no-agent-forwarding");
+                }
+                "command" => {
+                    if restrictions.forced_command.is_some() {
+                        anyhow::bail!("Duplicate command option");
+                    }
+                    restrictions.forced_command = Some(required(value)?);
+                }
+                "environment" => {
+                    let value = required(value)?;
+                    let (variable, setting) = value.split_once('=')
+                        .context("environment needs NAME=value")?;
+                    if variable.is_empty()
+                        || !variable.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+                    {
+                        anyhow::bail!("Invalid environment variable name: {}", variable);
+                    }
+                    if !restrictions.environment.iter().any(|(existing, _)| existing == variable) {
+                        restrictions.environment.push((variable.to_string(), setting.to_string()));
+                    }
+                }
+                "from" => {
+                    options.from.extend(required(value)?.split(',').map(str::to_string));
+                }
+                "expiry-time" => {
+                    if options.expiry_time.is_some() {
+                        anyhow::bail!("Duplicate expiry-time option");
+                    }
+                    options.expiry_time = Some(parse_expiry_time(&required(value)?)?);
+                }
+                "permitopen" => {
+                    let value = required(value)?;
+                    let (host, port) = value.rsplit_once(':')
+                        .with_context(|| format!("permitopen needs host:port, got {}", value))?;
+                    if host.is_empty() || (port != "*" && port.parse::<u16>().is_err()) {
+                        anyhow::bail!("Invalid permitopen destination: {}", value);
+                    }
+                    restrictions.permit_open.push(value);
+                }
+                _ => anyhow::bail!("Unknown option: {}", name),
+            }
+        }
+        Ok(options)
+    }
+
+    /// Check that a client at `client_address` may log in with the key now.
+    /// Host names are not resolved, so `from=` patterns match the address.
+    pub fn check_login(&self, client_address: IpAddr, now: SystemTime) -> Result<()> {
+        if self.expiry_time.is_some_and(|expiry| now >= expiry) {
+            anyhow::bail!("key has expired");
+        }
+        if !self.from.is_empty() && !matches_from(&self.from, client_address) {
+            anyhow::bail!("{} is not permitted by from=\"{}\"", client_address, self.from.join(","));
+        }
+        Ok(())
+    }
+}
+
+/// authorized_keys file of `username`: ~/.ssh_edu/authorized_keys_<user>
+pub fn authorized_keys_path(username: &str) -> Result<PathBuf> {
+    Ok(get_ssh_edu_dir()?.join(format!("authorized_keys_{}", username)))
+}
+
+/// Parse one authorized_keys line in OpenSSH format (`[options] key-type
+/// base64 comment`). Blank lines and `#` comments give `None`. Lines written
+/// before this format hold the hex of a key blob, or of a raw Ed25519 key.
+pub fn parse_line(line: &str) -> Result<Option<AuthorizedKey>> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+
+    if let Ok(bytes) = hex::decode(line) {
+        let key = if bytes.len() == 32 {
+            PublicKey::Ed25519(bytes)
+        } else {
+            PublicKey::from_blob(&bytes)?
+        };
+        return Ok(Some(AuthorizedKey { options: KeyOptions::default(), key, comment: String::new() }));
+    }
+
+    // A line starts with options unless it starts with the key type
+    let first_field = line.split_whitespace().next().unwrap_or_default();
+    let (options, key_line) = if KeyAlgorithm::from_name(first_field).is_ok() {
+        (KeyOptions::default(), line)
+    } else {
+        let (field, rest) = split_options_field(line);
+        (KeyOptions::parse(field)?, rest)
+    };
+    let (key, comment) = PublicKey::from_openssh(key_line)?;
+    Ok(Some(AuthorizedKey { options, key, comment }))
+}
+
+/// Split a line into its options field and the rest, at the first
+/// whitespace outside double quotes
+fn split_options_field(line: &str) -> (&str, &str) {
+    let mut quoted = false;
+    let mut escaped = false;
+    for (index, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => return (&line[..index], line[index..].trim_start()),
+            _ => {}
+        }
+    }
+    (line, "")
+}
+
+/// Split the options field into names and unquoted values
+fn split_options(field: &str) -> Result<Vec<(String, Option<String>)>> {
+    let mut options = Vec::new();
+    let mut chars = field.chars().peekable();
+    while chars.peek().is_some() {
+        let mut name = String::new();
+        while let Some(&c) = chars.peek() {
+            if c == '=' || c == ',' {
+                break;
+            }
+            name.push(c);
+            chars.next();
+        }
+        if name.is_empty() {
+            anyhow::bail!("Empty option name");
+        }
+
+        let mut value = None;
+        if chars.next_if_eq(&'=').is_some() {
+            if chars.next() != Some('"') {
+                anyhow::bail!("Value of option {} must be quoted", name);
+            }
+            let mut text = String::new();
+            loop {
+                match chars.next() {
+                    Some('\\') if chars.peek() == Some(&'"') => text.push(chars.next().unwrap()),
+                    Some('"') => break,
+                    Some(c) => text.push(c),
+                    None => anyhow::bail!("Missing closing quote in option {}", name),
+                }
+            }
+            value = Some(text);
+        }
+
+        match chars.next() {
+            None | Some(',') => {}
+            Some(c) => anyhow::bail!("Unexpected {:?} after option {}", c, name),
+        }
+        options.push((name, value));
+    }
+    Ok(options)
+}
+
+/// Whether `address` matches a `from=` pattern list: `*` and `?` wildcards
+/// or `address/bits` CIDR blocks, with `!` negating. A negated match denies
+/// even if another pattern matches.
+fn matches_from(patterns: &[String], address: IpAddr) -> bool {
+    let address = address.to_canonical();
+    let text = address.to_string();
+    let mut matched = false;
+    for pattern in patterns {
+        let (negated, pattern) = match pattern.strip_prefix('!') {
+            Some(pattern) => (true, pattern),
+            None => (false, pattern.as_str()),
+        };
+        let is_match = match pattern.split_once('/') {
+            Some((network, bits)) => matches_cidr(network, bits, address),
+            None => wildcard_match(pattern.as_bytes(), text.as_bytes()),
+        };
+        if is_match && negated {
+            return false;
+        }
+        matched |= is_match;
+    }
+    matched
+}
+
+/// Whether `address` is inside the CIDR block `network/bits`
+fn matches_cidr(network: &str, bits: &str, address: IpAddr) -> bool {
+    let (Ok(network), Ok(bits)) = (network.parse::<IpAddr>(), bits.parse::<u32>()) else {
+        return false;
+    };
+    match (network, address) {
+        (IpAddr::V4(network), IpAddr::V4(address)) if bits <= 32 => {
+            let mask = u32::MAX.checked_shl(32 - bits).unwrap_or(0);
+            u32::from(network) & mask == u32::from(address) & mask
+        }
+        (IpAddr::V6(network), IpAddr::V6(address)) if bits <= 128 => {
+            let mask = u128::MAX.checked_shl(128 - bits).unwrap_or(0);
+            u128::from(network) & mask == u128::from(address) & mask
+        }
+        _ => false,
+    }
+}
+
+/// Match `text` against a pattern where `*` matches any run of characters
+/// and `?` a single one
+fn wildcard_match(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some((b'*', rest)) => (0..=text.len()).any(|skip| wildcard_match(rest, &text[skip..])),
+        Some((&c, rest)) => text.split_first().is_some_and(|(&t, text_rest)| {
+            (c == b'?' || c.eq_ignore_ascii_case(&t)) && wildcard_match(rest, text_rest)
+        }),
+    }
+}
+
+/// Parse an `expiry-time` of the form YYYYMMDD[HHMM[SS]]. Like OpenSSH,
+/// the time is in the local time zone unless the value ends in `Z` for UTC.
+fn parse_expiry_time(value: &str) -> Result<SystemTime> {
+    let (digits, utc) = match value.strip_suffix(['Z', 'z']) {
+        Some(digits) => (digits, true),
+        None => (value, false),
+    };
+    if !matches!(digits.len(), 8 | 12 | 14) || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        anyhow::bail!("Invalid expiry-time: {}", value);
+    }
+    let field = |range: std::ops::Range<usize>| -> u64 {
+        digits.get(range).map_or(0, |text| text.parse().unwrap_or(0))
+    };
+    let (year, month, day) = (field(0..4), field(4..6), field(6..8));
+    let (hour, minute, second) = (field(8..10), field(10..12), field(12..14));
+    if !(1970..=9999).contains(&year) || !(1..=12).contains(&month) || !(1..=31).contains(&day)
+        || hour > 23 || minute > 59 || second > 59
+    {
+        anyhow::bail!("Invalid expiry-time: {}", value);
+    }
+    if !utc {
+        return local_time([year, month, day, hour, minute, second])
+            .with_context(|| format!("Invalid expiry-time: {}", value));
+    }
+    let days = days_since_epoch(year, month, day);
+    let seconds = ((days * 24 + hour) * 60 + minute) * 60 + second;
+    Ok(UNIX_EPOCH + Duration::from_secs(seconds))
+}
+
+/// A local date and time (year, month, day, hour, minute, second), resolved
+/// with the time zone rules in effect then, as OpenSSH does with mktime(3)
+#[cfg(unix)]
+fn local_time(fields: [u64; 6]) -> Result<SystemTime> {
+    let [year, month, day, hour, minute, second] = fields.map(|field| field as libc::c_int);
+    // SAFETY: libc::tm is plain data, for which all zeroes is a valid value
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    tm.tm_year = year - 1900;
+    tm.tm_mon = month - 1;
+    tm.tm_mday = day;
+    tm.tm_hour = hour;
+    tm.tm_min = minute;
+    tm.tm_sec = second;
+    // Let mktime work out whether daylight saving time applies
+    tm.tm_isdst = -1;
+    // SAFETY: tm is a valid, exclusively borrowed struct tm
+    let seconds = unsafe { libc::mktime(&mut tm) };
+    if seconds == -1 {
+        anyhow::bail!("not a representable local time");
+    }
+    let offset = Duration::from_secs(seconds.unsigned_abs());
+    Ok(if seconds < 0 { UNIX_EPOCH - offset } else { UNIX_EPOCH + offset })
+}
+
+/// Without the C library's time zone database a local time would be
+/// guessed, so only UTC expiry times are accepted
+#[cfg(not(unix))]
+fn local_time(_fields: [u64; 6]) -> Result<SystemTime> {
+    anyhow::bail!("local times are not supported here, end the value with Z for UTC")
+}
+
+/// Days from 1970-01-01 to a date in the proleptic Gregorian calendar
+fn days_since_epoch(year: u64, month: u64, day: u64) -> u64 {
+    // Count years from March, so the leap day comes last
+    let (year, month) = if month <= 2 { (year - 1, month + 9) } else { (year, month - 3) };
+    let days_before_year = year * 365 + year / 4 - year / 100 + year / 400;
+    let days_before_month = (153 * month + 2) / 5;
+    // 719468 days from 0000-03-01 to 1970-01-01
+    days_before_year + days_before_month + day - 1 - 719468
+}
+
+/// Keys authorized for `username`. Like sshd, invalid lines are reported and
+/// skipped rather than locking the user out.
+pub fn load_authorized_keys(username: &str) -> Result<Vec<AuthorizedKey>> {
+    let path = authorized_keys_path(username)?;
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let content = std::fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+
+    let mut keys = Vec::new();
+    for (number, line) in content.lines().enumerate() {
+        match parse_line(line) {
+            Ok(Some(key)) => keys.push(key),
+            Ok(None) => {}
+            Err(e) => println!("[Phase 5] Skipping {} line {}: {:#}", path.display(), number + 1, e),
+        }
+    }
+    Ok(keys)
+}
+
+/// Authorize the keys of an OpenSSH public key file (such as
+/// `id_ed25519.pub`) for `username`. Returns how many keys were added; keys
+/// already present are left alone.
+pub fn import_public_keys(username: &str, public_key_file: &Path) -> Result<usize> {
+    let content = std::fs::read_to_string(public_key_file)
+        .with_context(|| format!("Failed to read {}", public_key_file.display()))?;
+    let mut known: Vec<PublicKey> = load_authorized_keys(username)?
+        .into_iter()
+        .map(|authorized| authorized.key)
+        .collect();
+
+    let mut added = String::new();
+    for (number, line) in content.lines().enumerate() {
+        let Some(authorized) = parse_line(line)
+            .with_context(|| format!("{} line {}", public_key_file.display(), number + 1))?
+        else {
+            continue;
+        };
+        if known.contains(&authorized.key) {
+            continue;
+        }
+        added.push_str(line.trim());
+        added.push('\n');
+        known.push(authorized.key);
+    }
+    if added.is_empty() {
+        return Ok(0);
+    }
+
+    let path = authorized_keys_path(username)?;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .context("Failed to create .ssh_edu directory")?;
+    }
+    let mut content = if path.exists() {
+        std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?
+    } else {
+        String::new()
+    };
+    if !content.is_empty() && !content.ends_with('\n') {
+        content.push('\n');
+    }
+    content.push_str(&added);
+    std::fs::write(&path, content)
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    Ok(added.lines().count())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::keys::{KeyAlgorithm, KeyPair};
+
+    #[test]
+    fn parses_openssh_lines_comments_and_legacy_hex() {
+        let key = KeyPair::generate(KeyAlgorithm::Ed25519).unwrap().public_key();
+        let PublicKey::Ed25519(raw) = &key else { unreachable!() };
+
+        assert_eq!(parse_line("").unwrap(), None);
+        assert_eq!(parse_line("  # deploy keys").unwrap(), None);
+        let authorized = parse_line(&key.to_openssh("alice@laptop")).unwrap().unwrap();
+        assert_eq!(authorized, AuthorizedKey {
+            options: KeyOptions::default(),
+            key: key.clone(),
+            comment: "alice@laptop".to_string(),
+        });
+        assert_eq!(parse_line(&hex::encode(raw)).unwrap().unwrap().key, key);
+        assert_eq!(parse_line(&hex::encode(key.to_blob())).unwrap().unwrap().key, key);
+
+        // The key type must match the blob
+        let line = key.to_openssh("").replace("ssh-ed25519", "ssh-rsa");
+        assert!(parse_line(&line).is_err());
+    }
+
+    #[test]
+    fn parses_options_in_front_of_the_key() {
+        let key = KeyPair::generate(KeyAlgorithm::Ed25519).unwrap().public_key();
+        let line = format!(
+            r#"restrict,pty,command="echo \"a, b\"",environment="A=1",environment="A=2",permitopen="db:5432" {}"#,
+            key.to_openssh("deploy")
+        );
+        let authorized = parse_line(&line).unwrap().unwrap();
+        assert_eq!(authorized.key, key);
+        assert_eq!(authorized.comment, "deploy");
+        let restrictions = &authorized.options.restrictions;
+        assert_eq!(restrictions.forced_command.as_deref(), Some(r#"echo "a, b""#));
+        assert_eq!(restrictions.environment, [("A".to_string(), "1".to_string())]);
+        assert!(restrictions.permits_pty());
+        assert!(!restrictions.permits_agent_forwarding());
+        // restrict forbids forwarding altogether, permitopen only narrows it
+        assert!(!restrictions.permits_open("db", 5432));
+
+        let options = KeyOptions::parse(r#"permitopen="db:5432",permitopen="[::1]:*""#).unwrap();
+        assert!(options.restrictions.permits_open("DB", 5432));
+        assert!(options.restrictions.permits_open("::1", 22));
+        assert!(!options.restrictions.permits_open("db", 22));
+
+        // The echo session cannot run a forced command, so that key is refused
+        assert!(restrictions.check_enforceable().is_err());
+        let options = KeyOptions::parse(r#"restrict,environment="A=1",permitopen="db:5432""#).unwrap();
+        options.restrictions.check_enforceable().unwrap();
+
+        for bad in ["no-pty=\"1\"", "command", "command=unquoted", "from=\"x", "bogus", "permitopen=\"db\""] {
+            assert!(KeyOptions::parse(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn from_and_expiry_time_limit_logins() {
+        let now = SystemTime::now();
+        let options = KeyOptions::parse(r#"from="10.0.0.0/8,192.168.1.?,!10.0.0.13""#).unwrap();
+        assert!(options.check_login("10.1.2.3".parse().unwrap(), now).is_ok());
+        assert!(options.check_login("192.168.1.7".parse().unwrap(), now).is_ok());
+        assert!(options.check_login("::ffff:10.1.2.3".parse().unwrap(), now).is_ok());
+        assert!(options.check_login("10.0.0.13".parse().unwrap(), now).is_err());
+        assert!(options.check_login("192.168.1.70".parse().unwrap(), now).is_err());
+
+        assert_eq!(parse_expiry_time("20300101Z").unwrap(), UNIX_EPOCH + Duration::from_secs(1893456000));
+        assert_eq!(
+            parse_expiry_time("20240229123456Z").unwrap(),
+            UNIX_EPOCH + Duration::from_secs(1709210096)
+        );
+        // Without Z the time is local: converting back gives the same fields
+        let local = parse_expiry_time("20300101123456").unwrap();
+        let seconds = local.duration_since(UNIX_EPOCH).unwrap().as_secs() as libc::time_t;
+        // SAFETY: both pointers are valid; tm is plain data
+        let tm = unsafe {
+            let mut tm: libc::tm = std::mem::zeroed();
+            assert!(!libc::localtime_r(&seconds, &mut tm).is_null());
+            tm
+        };
+        assert_eq!(
+            (tm.tm_year + 1900, tm.tm_mon + 1, tm.tm_mday, tm.tm_hour, tm.tm_min, tm.tm_sec),
+            (2030, 1, 1, 12, 34, 56)
+        );
+        assert!(parse_expiry_time("2024022").is_err());
+        assert!(parse_expiry_time("20241301").is_err());
+
+        let options = KeyOptions::parse(r#"expiry-time="20200101""#).unwrap();
+        assert!(options.check_login("127.0.0.1".parse().unwrap(), now).is_err());
+    }
+}
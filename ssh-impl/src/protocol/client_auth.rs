@@ -1,215 +1,809 @@
-use std::path::PathBuf;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 use anyhow::{Result, Context};
-use crate::crypto::keys::{UserKeyPair, verify_signature, get_ssh_edu_dir};
-use crate::utils::packet::Packet;
-use crate::utils::stream::ReadWrite;
+use crate::crypto::keys::{KeyPair, PublicKey};
+use crate::protocol::authorized_keys::load_authorized_keys;
+use crate::protocol::extensions::Extensions;
+use crate::protocol::messages::{
+    expect_message, printable, SSH_MSG_USERAUTH_FAILURE, SSH_MSG_USERAUTH_PK_OK,
+    SSH_MSG_USERAUTH_REQUEST, SSH_MSG_USERAUTH_SUCCESS,
+};
+use crate::protocol::session::SessionRestrictions;
+use crate::protocol::transport::Transport;
+use crate::protocol::users::UserDatabase;
+use crate::utils::wire::{Decode, Encode, Reader, Writer};
 
 /// Authentication methods
 #[derive(Debug, Clone)]
 pub enum AuthMethod {
+    /// Asks which methods may continue (RFC 4252 §5.2); never succeeds
+    None,
     Password(String),
-    PublicKey(Vec<u8>),
+    PublicKey {
+        algorithm: String,
+        /// Public key blob
+        public_key: Vec<u8>,
+        /// Server host key, for publickey-hostbound-v00@openssh.com
+        host_key: Option<Vec<u8>>,
+        /// Signature blob over `AuthRequest::signed_data`; a request
+        /// without one asks whether the key would be accepted
+        signature: Option<Vec<u8>>,
+    },
+    /// A method we do not implement, such as keyboard-interactive, or a
+    /// password change request (RFC 4252 §8)
+    Unsupported(String),
+}
+
+impl AuthMethod {
+    /// Method name as listed in SSH_MSG_USERAUTH_FAILURE
+    pub fn name(&self) -> &str {
+        match self {
+            AuthMethod::None => "none",
+            AuthMethod::Password(_) => "password",
+            AuthMethod::PublicKey { .. } => "publickey",
+            AuthMethod::Unsupported(name) => name,
+        }
+    }
+}
+
+/// Service requested on top of user authentication
+const CONNECTION_SERVICE: &str = "ssh-connection";
+
+/// Signature algorithms accepted for publickey authentication, announced to
+/// clients in server-sig-algs
+pub const SIGNATURE_ALGORITHMS: &[&str] = &[
+    "ssh-ed25519",
+    "ecdsa-sha2-nistp256",
+    "ecdsa-sha2-nistp384",
+    "rsa-sha2-512",
+    "rsa-sha2-256",
+];
+
+/// Name of the publickey method bound to the server host key (OpenSSH
+/// PROTOCOL §3.2)
+const PUBLICKEY_HOSTBOUND_METHOD: &str = "publickey-hostbound-v00@openssh.com";
+
+/// Methods the server implements and may offer in SSH_MSG_USERAUTH_FAILURE
+const SUPPORTED_METHODS: [&str; 2] = ["publickey", "password"];
+
+/// Which methods the server requires, like OpenSSH's
+/// AuthenticationMethods and MaxAuthTries
+#[derive(Debug, Clone)]
+pub struct AuthPolicy {
+    /// Alternative lists of methods; a user is authenticated after
+    /// completing every method of one list, in order
+    pub method_chains: Vec<Vec<String>>,
+    /// Failed attempts before the server disconnects
+    pub max_tries: u32,
+    /// Password database, `~/.ssh_edu/users.json` if not set
+    pub users_file: Option<PathBuf>,
+}
+
+impl Default for AuthPolicy {
+    fn default() -> Self {
+        Self {
+            method_chains: SUPPORTED_METHODS.iter().map(|&method| vec![method.to_string()]).collect(),
+            max_tries: 6,
+            users_file: None,
+        }
+    }
+}
+
+impl AuthPolicy {
+    /// Parse method chains in AuthenticationMethods syntax: alternatives
+    /// separated by spaces, each a comma-separated list of methods
+    /// (`"publickey,password publickey"`)
+    pub fn parse_methods(spec: &str) -> Result<Vec<Vec<String>>> {
+        let chains: Vec<Vec<String>> = spec.split_whitespace()
+            .map(|chain| chain.split(',').map(str::to_string).collect())
+            .collect();
+        if chains.is_empty() {
+            anyhow::bail!("No authentication methods given");
+        }
+        for method in chains.iter().flatten() {
+            if !SUPPORTED_METHODS.contains(&method.as_str()) {
+                anyhow::bail!("Unsupported authentication method: {:?}", method);
+            }
+        }
+        Ok(chains)
+    }
+
+    /// Methods that may continue after `completed`
+    pub fn next_methods(&self, completed: &[String]) -> Vec<String> {
+        let mut methods: Vec<String> = Vec::new();
+        for chain in &self.method_chains {
+            if chain.len() > completed.len() && chain.starts_with(completed) {
+                let method = &chain[completed.len()];
+                if !methods.contains(method) {
+                    methods.push(method.clone());
+                }
+            }
+        }
+        methods
+    }
+
+    /// Whether `completed` finishes one of the chains
+    pub fn is_satisfied(&self, completed: &[String]) -> bool {
+        self.method_chains.iter().any(|chain| chain == completed)
+    }
+}
+
+/// SSH_MSG_USERAUTH_REQUEST (RFC 4252 §5)
+pub struct AuthRequest {
+    pub username: String,
+    pub service: String,
+    pub method: AuthMethod,
+}
+
+impl AuthRequest {
+    /// Data a publickey signature covers (RFC 4252 §7): the session
+    /// identifier, then the request up to the signature with the boolean
+    /// set. publickey-hostbound requests also cover the host key.
+    pub fn signed_data(&self, session_id: &[u8]) -> Vec<u8> {
+        let mut writer = Writer::new();
+        writer.write_string(session_id);
+        self.encode_fields(&mut writer, true);
+        writer.into_bytes()
+    }
+
+    /// Everything but the signature; `signed` is the publickey boolean
+    fn encode_fields(&self, writer: &mut Writer, signed: bool) {
+        writer.write_byte(SSH_MSG_USERAUTH_REQUEST);
+        writer.write_string(self.username.as_bytes());
+        writer.write_string(self.service.as_bytes());
+        match &self.method {
+            AuthMethod::None => writer.write_string(b"none"),
+            AuthMethod::Password(password) => {
+                writer.write_string(b"password");
+                writer.write_bool(false);
+                writer.write_string(password.as_bytes());
+            }
+            AuthMethod::PublicKey { algorithm, public_key, host_key, .. } => {
+                let method = match host_key {
+                    Some(_) => PUBLICKEY_HOSTBOUND_METHOD,
+                    None => "publickey",
+                };
+                writer.write_string(method.as_bytes());
+                writer.write_bool(signed);
+                writer.write_string(algorithm.as_bytes());
+                writer.write_string(public_key);
+                if let Some(host_key) = host_key {
+                    writer.write_string(host_key);
+                }
+            }
+            AuthMethod::Unsupported(name) => writer.write_string(name.as_bytes()),
+        }
+    }
+}
+
+impl Encode for AuthRequest {
+    fn encode(&self, writer: &mut Writer) {
+        let signature = match &self.method {
+            AuthMethod::PublicKey { signature, .. } => signature.as_ref(),
+            _ => None,
+        };
+        self.encode_fields(writer, signature.is_some());
+        if let Some(signature) = signature {
+            writer.write_string(signature);
+        }
+    }
+}
+
+impl Decode for AuthRequest {
+    fn decode(reader: &mut Reader<'_>) -> Result<Self> {
+        expect_message(reader, SSH_MSG_USERAUTH_REQUEST)?;
+        let username = reader.read_utf8()
+            .context("Invalid username")?;
+        let service = reader.read_utf8()
+            .context("Invalid service name")?;
+        let method_name = reader.read_utf8()
+            .context("Missing authentication method")?;
+        let method = match method_name.as_str() {
+            "none" => AuthMethod::None,
+            "password" => {
+                let change = reader.read_bool()?;
+                let password = reader.read_utf8().context("Invalid password")?;
+                if change {
+                    // Fails like any other attempt rather than ending the
+                    // connection
+                    reader.read_utf8().context("Invalid new password")?;
+                    AuthMethod::Unsupported(method_name)
+                } else {
+                    AuthMethod::Password(password)
+                }
+            }
+            "publickey" | PUBLICKEY_HOSTBOUND_METHOD => {
+                let signed = reader.read_bool()?;
+                let algorithm = reader.read_utf8()?;
+                let public_key = reader.read_string().context("Invalid public key")?.to_vec();
+                let host_key = if method_name == PUBLICKEY_HOSTBOUND_METHOD {
+                    Some(reader.read_string().context("Invalid host key")?.to_vec())
+                } else {
+                    None
+                };
+                let signature = if signed {
+                    Some(reader.read_string().context("Invalid signature")?.to_vec())
+                } else {
+                    None
+                };
+                AuthMethod::PublicKey { algorithm, public_key, host_key, signature }
+            }
+            _ => {
+                // Method-specific fields we cannot interpret
+                reader.read_rest();
+                AuthMethod::Unsupported(method_name)
+            }
+        };
+        Ok(Self { username, service, method })
+    }
+}
+
+/// SSH_MSG_USERAUTH_SUCCESS
+pub struct AuthSuccess;
+
+impl Encode for AuthSuccess {
+    fn encode(&self, writer: &mut Writer) {
+        writer.write_byte(SSH_MSG_USERAUTH_SUCCESS);
+    }
+}
+
+impl Decode for AuthSuccess {
+    fn decode(reader: &mut Reader<'_>) -> Result<Self> {
+        expect_message(reader, SSH_MSG_USERAUTH_SUCCESS)?;
+        Ok(Self)
+    }
+}
+
+/// SSH_MSG_USERAUTH_FAILURE
+pub struct AuthFailure {
+    pub methods: Vec<String>,
+    pub partial_success: bool,
+}
+
+impl Encode for AuthFailure {
+    fn encode(&self, writer: &mut Writer) {
+        writer.write_byte(SSH_MSG_USERAUTH_FAILURE);
+        writer.write_name_list(&self.methods);
+        writer.write_bool(self.partial_success);
+    }
+}
+
+impl Decode for AuthFailure {
+    fn decode(reader: &mut Reader<'_>) -> Result<Self> {
+        expect_message(reader, SSH_MSG_USERAUTH_FAILURE)?;
+        Ok(Self {
+            methods: reader.read_name_list()?,
+            partial_success: reader.read_bool()?,
+        })
+    }
+}
+
+/// SSH_MSG_USERAUTH_PK_OK: the server would accept a signature by this key
+pub struct AuthPkOk {
+    pub algorithm: String,
+    pub public_key: Vec<u8>,
+}
+
+impl Encode for AuthPkOk {
+    fn encode(&self, writer: &mut Writer) {
+        writer.write_byte(SSH_MSG_USERAUTH_PK_OK);
+        writer.write_string(self.algorithm.as_bytes());
+        writer.write_string(&self.public_key);
+    }
+}
+
+impl Decode for AuthPkOk {
+    fn decode(reader: &mut Reader<'_>) -> Result<Self> {
+        expect_message(reader, SSH_MSG_USERAUTH_PK_OK)?;
+        Ok(Self {
+            algorithm: reader.read_utf8()?,
+            public_key: reader.read_string()?.to_vec(),
+        })
+    }
+}
+
+/// Server answer to an authentication request
+pub enum AuthOutcome {
+    Success,
+    /// Lists the methods that may continue; `partial_success` is set when
+    /// the request succeeded but more methods are required
+    Failure(AuthFailure),
 }
 
 /// Send authentication request (client side)
 pub fn send_auth_request(
-    stream: &mut dyn ReadWrite,
+    transport: &mut Transport,
     username: &str,
     method: &AuthMethod,
-) -> Result<()> {
+) -> Result<AuthOutcome> {
     println!("[Phase 5] Sending authentication request...");
-    
-    let mut auth_packet = Vec::new();
-    
-    // Add username
-    auth_packet.extend_from_slice(&(username.len() as u32).to_be_bytes());
-    auth_packet.extend_from_slice(username.as_bytes());
-    
+
     match method {
-        AuthMethod::Password(password) => {
-            auth_packet.push(0); // Method: password
-            auth_packet.extend_from_slice(&(password.len() as u32).to_be_bytes());
-            auth_packet.extend_from_slice(password.as_bytes());
-            println!("[Phase 5] Using password authentication");
-        }
-        AuthMethod::PublicKey(public_key) => {
-            auth_packet.push(1); // Method: public key
-            auth_packet.extend_from_slice(&(public_key.len() as u32).to_be_bytes());
-            auth_packet.extend_from_slice(public_key);
-            println!("[Phase 5] Using public key authentication");
-        }
-    }
-    
-    let packet = Packet::new(auth_packet);
-    packet.write(stream)
+        AuthMethod::None => println!("[Phase 5] Asking which methods the server accepts (none)"),
+        AuthMethod::Password(_) => println!("[Phase 5] Using password authentication"),
+        AuthMethod::PublicKey { algorithm, host_key, .. } => println!(
+            "[Phase 5] Using {} authentication ({})",
+            if host_key.is_some() { PUBLICKEY_HOSTBOUND_METHOD } else { "publickey" },
+            algorithm
+        ),
+        AuthMethod::Unsupported(name) => println!("[Phase 5] Using {} authentication", name),
+    }
+
+    let request = AuthRequest {
+        username: username.to_string(),
+        service: CONNECTION_SERVICE.to_string(),
+        method: method.clone(),
+    };
+    transport.send(&request)
         .context("Failed to send authentication request")?;
-    
+
     // Receive authentication response
-    let response_packet = Packet::read(stream)
+    let response = transport.recv_payload()
         .context("Failed to receive authentication response")?;
-    
-    if response_packet.payload == b"SUCCESS" {
-        println!("[Phase 5] Authentication successful!");
-        Ok(())
-    } else if response_packet.payload == b"FAILURE" {
-        anyhow::bail!("Authentication failed");
-    } else {
-        anyhow::bail!("Invalid authentication response");
+
+    match response.first() {
+        Some(&SSH_MSG_USERAUTH_SUCCESS) => {
+            AuthSuccess::from_bytes(&response)?;
+            println!("[Phase 5] Authentication successful!");
+            transport.set_authenticated();
+            Ok(AuthOutcome::Success)
+        }
+        Some(&SSH_MSG_USERAUTH_FAILURE) => {
+            let failure = AuthFailure::from_bytes(&response)?;
+            if failure.partial_success {
+                println!(
+                    "[Phase 5] {} accepted, server requires more (can continue: {})",
+                    method.name(),
+                    failure.methods.join(",")
+                );
+            } else {
+                println!(
+                    "[Phase 5] {} refused (can continue: {})",
+                    method.name(),
+                    failure.methods.join(",")
+                );
+            }
+            Ok(AuthOutcome::Failure(failure))
+        }
+        _ => anyhow::bail!("Invalid authentication response"),
     }
 }
 
-/// Handle authentication request (server side)
-pub fn handle_auth_request(
-    stream: &mut dyn ReadWrite,
-) -> Result<String> {
-    println!("[Phase 5] Receiving authentication request...");
-    
-    let auth_packet = Packet::read(stream)
-        .context("Failed to receive authentication request")?;
-    
-    if auth_packet.payload.len() < 4 {
-        anyhow::bail!("Invalid authentication packet");
-    }
-    
-    // Parse username
-    let username_len = u32::from_be_bytes([
-        auth_packet.payload[0],
-        auth_packet.payload[1],
-        auth_packet.payload[2],
-        auth_packet.payload[3],
-    ]) as usize;
-    
-    if auth_packet.payload.len() < 4 + username_len {
-        anyhow::bail!("Invalid username length");
-    }
-    
-    let username = String::from_utf8(
-        auth_packet.payload[4..4 + username_len].to_vec()
-    ).context("Invalid username encoding")?;
-    
-    println!("[Phase 5] Authenticating user: {}", username);
-    
-    // Parse authentication method
-    let method_offset = 4 + username_len;
-    if auth_packet.payload.len() <= method_offset {
-        anyhow::bail!("Missing authentication method");
-    }
-    
-    let method = auth_packet.payload[method_offset];
-    let mut authenticated = false;
-    
-    match method {
-        0 => {
-            // Password authentication
-            if auth_packet.payload.len() < method_offset + 1 + 4 {
-                anyhow::bail!("Invalid password packet");
+/// Authenticate with a user key (client side): first ask whether the server
+/// would accept the key, then send the request again signed with it
+/// (RFC 4252 §7). `method` comes from `public_key_method`.
+pub fn send_public_key_auth(
+    transport: &mut Transport,
+    session_id: &[u8],
+    username: &str,
+    method: &AuthMethod,
+    key: &KeyPair,
+) -> Result<AuthOutcome> {
+    let AuthMethod::PublicKey { algorithm, public_key, .. } = method else {
+        anyhow::bail!("Not a public key method");
+    };
+    println!("[Phase 5] Asking whether the server accepts our {} key...", key.algorithm().name());
+
+    let mut request = AuthRequest {
+        username: username.to_string(),
+        service: CONNECTION_SERVICE.to_string(),
+        method: method.clone(),
+    };
+    transport.send(&request)
+        .context("Failed to send public key query")?;
+
+    let response = transport.recv_payload()
+        .context("Failed to receive public key query response")?;
+    match response.first() {
+        Some(&SSH_MSG_USERAUTH_PK_OK) => {
+            let pk_ok = AuthPkOk::from_bytes(&response)?;
+            if pk_ok.algorithm != *algorithm || pk_ok.public_key != *public_key {
+                anyhow::bail!("SSH_MSG_USERAUTH_PK_OK names a different key");
             }
-            
-            let password_len = u32::from_be_bytes([
-                auth_packet.payload[method_offset + 1],
-                auth_packet.payload[method_offset + 2],
-                auth_packet.payload[method_offset + 3],
-                auth_packet.payload[method_offset + 4],
-            ]) as usize;
-            
-            if auth_packet.payload.len() < method_offset + 5 + password_len {
-                anyhow::bail!("Invalid password length");
+            println!("[Phase 5] Server accepts the key (SSH_MSG_USERAUTH_PK_OK)");
+        }
+        Some(&SSH_MSG_USERAUTH_FAILURE) => {
+            let failure = AuthFailure::from_bytes(&response)?;
+            println!(
+                "[Phase 5] Server does not accept the key (can continue: {})",
+                failure.methods.join(",")
+            );
+            return Ok(AuthOutcome::Failure(failure));
+        }
+        _ => anyhow::bail!("Invalid public key query response"),
+    }
+
+    // The signature proves we hold the private key, and covers the session
+    // identifier so it cannot be replayed on another connection
+    let signature = key.sign(algorithm, &request.signed_data(session_id))?;
+    if let AuthMethod::PublicKey { signature: request_signature, .. } = &mut request.method {
+        *request_signature = Some(signature);
+    }
+    send_auth_request(transport, username, &request.method)
+}
+
+/// Choose how to authenticate with a user key (client side): the first
+/// signature algorithm of the key that the server lists in server-sig-algs.
+/// A server that sent no server-sig-algs may still accept the key, so then
+/// the key's preferred algorithm is used. The request is bound to the server
+/// host key when the server supports that.
+pub fn public_key_method(
+    extensions: &Extensions,
+    public_key: &PublicKey,
+    host_key: &[u8],
+) -> Option<AuthMethod> {
+    let key_algorithm = public_key.algorithm();
+    let signature_algorithm = match &extensions.server_sig_algs {
+        Some(accepted) => {
+            let algorithm = key_algorithm.signature_algorithms().iter()
+                .find(|&&algorithm| accepted.iter().any(|name| name == algorithm));
+            if algorithm.is_none() {
+                println!(
+                    "[Phase 5] Server does not accept {} keys (server-sig-algs: {})",
+                    key_algorithm.name(),
+                    accepted.join(",")
+                );
             }
-            
-            let password = String::from_utf8(
-                auth_packet.payload[method_offset + 5..method_offset + 5 + password_len].to_vec()
-            ).context("Invalid password encoding")?;
-            
-            authenticated = authenticate_password(&username, &password)?;
-        }
-        1 => {
-            // Public key authentication
-            if auth_packet.payload.len() < method_offset + 1 + 4 {
-                anyhow::bail!("Invalid public key packet");
+            algorithm?
+        }
+        None => key_algorithm.signature_algorithms().first()?,
+    };
+    Some(AuthMethod::PublicKey {
+        algorithm: signature_algorithm.to_string(),
+        public_key: public_key.to_blob(),
+        host_key: extensions.publickey_hostbound.then(|| host_key.to_vec()),
+        signature: None,
+    })
+}
+
+/// A user the server has authenticated
+pub struct AuthenticatedUser {
+    pub username: String,
+    /// Limits from the authorized_keys options of the key used, if any
+    pub restrictions: SessionRestrictions,
+}
+
+/// Handle authentication requests (server side) until the user completes
+/// one of the method chains of `policy`. Each failure lists the methods
+/// that may continue; after `policy.max_tries` failed attempts this returns
+/// an error. Public key queries are answered with SSH_MSG_USERAUTH_PK_OK;
+/// signatures must cover `session_id`. `host_key` is our host key blob,
+/// which publickey-hostbound requests must name; `client_address` is
+/// checked against `from=` options.
+///
+/// As in OpenSSH, a refused public key query counts as a failed attempt,
+/// so a client cannot probe for authorized keys beyond `max_tries`. A query
+/// answered with PK_OK does not count: it only confirms a key the client
+/// already holds, and the signed request that must follow is counted if it
+/// fails. `none` requests only ask for the method list and do not count.
+pub fn handle_auth_request(
+    transport: &mut Transport,
+    host_key: &[u8],
+    session_id: &[u8],
+    client_address: IpAddr,
+    policy: &AuthPolicy,
+) -> Result<AuthenticatedUser> {
+    let users_file = match &policy.users_file {
+        Some(users_file) => users_file.clone(),
+        None => UserDatabase::default_path()?,
+    };
+    // Methods completed so far by `current_user`; RFC 4252 §5 lets the
+    // client change the user name, which starts over
+    let mut current_user = String::new();
+    let mut completed: Vec<String> = Vec::new();
+    let mut restrictions = SessionRestrictions::default();
+    let mut failures = 0;
+
+    loop {
+        println!("[Phase 5] Receiving authentication request...");
+
+        let request: AuthRequest = transport.recv()
+            .context("Failed to receive authentication request")?;
+        let username = &request.username;
+        if *username != current_user {
+            current_user = username.clone();
+            completed.clear();
+            restrictions = SessionRestrictions::default();
+        }
+
+        println!(
+            "[Phase 5] Authenticating user: {} ({})",
+            printable(username),
+            printable(request.method.name())
+        );
+
+        let allowed = request.service == CONNECTION_SERVICE
+            && policy.next_methods(&completed).iter().any(|method| method == request.method.name());
+        let success = if !allowed {
+            false
+        } else {
+            match &request.method {
+                AuthMethod::Password(password) => authenticate_password(&users_file, username, password)?,
+                AuthMethod::PublicKey { algorithm, public_key, host_key: bound_host_key, signature } => {
+                    if !SIGNATURE_ALGORITHMS.contains(&algorithm.as_str()) {
+                        // Such as ssh-rsa (SHA-1), certificates and security keys
+                        println!("[Phase 5] Unsupported public key algorithm: {}", algorithm);
+                        false
+                    } else if bound_host_key.as_ref().is_some_and(|bound| bound != host_key) {
+                        println!("[Phase 5] Request is bound to a different host key");
+                        false
+                    } else {
+                        match (authorized_public_key(username, algorithm, public_key, client_address)?, signature) {
+                            (None, _) => false,
+                            (Some(_), None) => {
+                                println!("[Phase 5] Key is acceptable, sending SSH_MSG_USERAUTH_PK_OK");
+                                transport.send(&AuthPkOk {
+                                    algorithm: algorithm.clone(),
+                                    public_key: public_key.clone(),
+                                })
+                                .context("Failed to send SSH_MSG_USERAUTH_PK_OK")?;
+                                continue;
+                            }
+                            (Some((key, key_restrictions)), Some(signature)) => {
+                                match key.verify(algorithm, &request.signed_data(session_id), signature) {
+                                    Ok(()) => {
+                                        println!("[Phase 5] Signature verified ({})", algorithm);
+                                        restrictions = key_restrictions;
+                                        true
+                                    }
+                                    Err(e) => {
+                                        println!("[Phase 5] Invalid signature: {}", e);
+                                        false
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+                AuthMethod::None | AuthMethod::Unsupported(_) => false,
             }
-            
-            let key_len = u32::from_be_bytes([
-                auth_packet.payload[method_offset + 1],
-                auth_packet.payload[method_offset + 2],
-                auth_packet.payload[method_offset + 3],
-                auth_packet.payload[method_offset + 4],
-            ]) as usize;
-            
-            if auth_packet.payload.len() < method_offset + 5 + key_len {
-                anyhow::bail!("Invalid public key length");
+        };
+
+        // Send authentication response
+        if success {
+            completed.push(request.method.name().to_string());
+            if policy.is_satisfied(&completed) {
+                println!("[Phase 5] Authentication successful!");
+                transport.send(&AuthSuccess)
+                    .context("Failed to send authentication response")?;
+                transport.set_authenticated();
+                return Ok(AuthenticatedUser { username: request.username, restrictions });
             }
-            
-            let public_key = auth_packet.payload[method_offset + 5..method_offset + 5 + key_len].to_vec();
-            
-            authenticated = authenticate_public_key(&username, &public_key)?;
-        }
-        _ => {
-            anyhow::bail!("Unknown authentication method: {}", method);
-        }
-    }
-    
-    // Send authentication response
-    let response = if authenticated {
-        println!("[Phase 5] Authentication successful!");
-        b"SUCCESS".to_vec()
-    } else {
-        println!("[Phase 5] Authentication failed!");
-        b"FAILURE".to_vec()
-    };
-    
-    let response_packet = Packet::new(response);
-    response_packet.write(stream)
-        .context("Failed to send authentication response")?;
-    
-    if authenticated {
-        Ok(username)
-    } else {
-        anyhow::bail!("Authentication failed")
-    }
-}
-
-/// Authenticate user with password (simplified - in production, use proper password hashing)
-fn authenticate_password(username: &str, password: &str) -> Result<bool> {
-    // Simplified: check against a simple user database
-    // In production, use proper password hashing (bcrypt, argon2, etc.)
-    let ssh_edu_dir = get_ssh_edu_dir()?;
-    let users_file = ssh_edu_dir.join("users.json");
-    
+            println!("[Phase 5] Partial success, completed: {}", completed.join(","));
+        } else if !matches!(request.method, AuthMethod::None) {
+            // A none request only asks for the method list, so it is not
+            // counted as an attempt
+            failures += 1;
+            println!("[Phase 5] Authentication failed! ({} of {} tries)", failures, policy.max_tries);
+            if failures >= policy.max_tries {
+                anyhow::bail!("Too many authentication failures for {}", username);
+            }
+        }
+        let failure = AuthFailure {
+            methods: policy.next_methods(&completed),
+            partial_success: success,
+        };
+        transport.send(&failure)
+            .context("Failed to send authentication response")?;
+    }
+}
+
+/// Check a password against the hashes in the user database
+fn authenticate_password(users_file: &Path, username: &str, password: &str) -> Result<bool> {
     if !users_file.exists() {
-        // Create default user for testing
-        let default_users = serde_json::json!({
-            "testuser": "testpass"
-        });
-        std::fs::create_dir_all(&ssh_edu_dir)?;
-        std::fs::write(&users_file, serde_json::to_string_pretty(&default_users)?)?;
-    }
-    
-    let users_data: serde_json::Value = serde_json::from_str(
-        &std::fs::read_to_string(&users_file)?
-    )?;
-    
-    if let Some(stored_password) = users_data.get(username).and_then(|v| v.as_str()) {
-        Ok(stored_password == password)
-    } else {
-        Ok(false)
-    }
-}
-
-/// Authenticate user with public key
-fn authenticate_public_key(username: &str, public_key: &[u8]) -> Result<bool> {
-    let ssh_edu_dir = get_ssh_edu_dir()?;
-    let authorized_keys_file = ssh_edu_dir.join(format!("authorized_keys_{}", username));
-    
-    if !authorized_keys_file.exists() {
+        println!(
+            "[Phase 5] No user database at {}, create one with `ssh-impl init`",
+            users_file.display()
+        );
         return Ok(false);
     }
-    
-    let authorized_keys_content = std::fs::read_to_string(&authorized_keys_file)?;
-    let public_key_hex = hex::encode(public_key);
-    
+    UserDatabase::load(users_file)?.verify(username, password)
+}
+
+/// Look the public key up in authorized_keys_<user>. Returns the key with
+/// the restrictions of the first line naming it whose options let the
+/// client log in and can be enforced by the session. Possession of the
+/// private key is checked separately.
+fn authorized_public_key(
+    username: &str,
+    algorithm: &str,
+    public_key_blob: &[u8],
+    client_address: IpAddr,
+) -> Result<Option<(PublicKey, SessionRestrictions)>> {
+    let public_key = match PublicKey::from_blob(public_key_blob) {
+        Ok(public_key) => public_key,
+        Err(e) => {
+            println!("[Phase 5] Invalid public key: {:#}", e);
+            return Ok(None);
+        }
+    };
+    if !public_key.algorithm().supports(algorithm) {
+        println!("[Phase 5] {} key offered for {}", public_key.algorithm().name(), algorithm);
+        return Ok(None);
+    }
+    println!("[Phase 5] Offered {} key {}", public_key.algorithm().name(), public_key.fingerprint());
+
     // Check if public key is in authorized_keys
-    Ok(authorized_keys_content.lines().any(|line| {
-        line.trim() == public_key_hex
-    }))
+    let now = SystemTime::now();
+    for authorized in load_authorized_keys(username)? {
+        if authorized.key != public_key {
+            continue;
+        }
+        let usable = authorized.options.check_login(client_address, now)
+            .and_then(|()| authorized.options.restrictions.check_enforceable());
+        match usable {
+            Ok(()) => return Ok(Some((public_key, authorized.options.restrictions))),
+            Err(e) => println!("[Phase 5] Key refused by its options: {}", e),
+        }
+    }
+    Ok(None)
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::keys::KeyAlgorithm;
+    use crate::protocol::transport::Transport;
+
+    #[test]
+    fn signed_request_round_trips_and_verifies() {
+        let key = KeyPair::generate(KeyAlgorithm::Ed25519).unwrap();
+        let session_id = [7u8; 32];
+        let mut request = AuthRequest {
+            username: "alice".to_string(),
+            service: CONNECTION_SERVICE.to_string(),
+            method: AuthMethod::PublicKey {
+                algorithm: "ssh-ed25519".to_string(),
+                public_key: key.public_key_blob(),
+                host_key: Some(vec![1, 2, 3]),
+                signature: None,
+            },
+        };
+        // A query carries FALSE and no signature
+        let query = AuthRequest::from_bytes(&request.to_bytes()).unwrap();
+        assert!(matches!(query.method, AuthMethod::PublicKey { signature: None, .. }));
+
+        let signed_data = request.signed_data(&session_id);
+        assert_eq!(signed_data[..4], [0, 0, 0, 32]);
+        let signature = key.sign("ssh-ed25519", &signed_data).unwrap();
+        if let AuthMethod::PublicKey { signature: slot, .. } = &mut request.method {
+            *slot = Some(signature.clone());
+        }
+
+        // The server rebuilds the same signed data from what it received
+        let received = AuthRequest::from_bytes(&request.to_bytes()).unwrap();
+        assert_eq!(received.signed_data(&session_id), signed_data);
+        let AuthMethod::PublicKey { signature: Some(received_signature), .. } = &received.method else {
+            panic!("signature lost");
+        };
+        assert_eq!(*received_signature, signature);
+        key.public_key().verify("ssh-ed25519", &signed_data, received_signature).unwrap();
+        // Bound to this session only
+        assert!(key.public_key()
+            .verify("ssh-ed25519", &received.signed_data(&[8u8; 32]), received_signature)
+            .is_err());
+    }
+
+    #[test]
+    fn policy_requires_methods_in_order() {
+        let policy = AuthPolicy {
+            method_chains: AuthPolicy::parse_methods("publickey,password password").unwrap(),
+            max_tries: 6,
+            users_file: None,
+        };
+        let done = |methods: &[&str]| methods.iter().map(|m| m.to_string()).collect::<Vec<_>>();
+        assert_eq!(policy.next_methods(&[]), ["publickey", "password"]);
+        assert!(!policy.is_satisfied(&done(&["publickey"])));
+        assert_eq!(policy.next_methods(&done(&["publickey"])), ["password"]);
+        assert!(policy.is_satisfied(&done(&["publickey", "password"])));
+        assert!(policy.is_satisfied(&done(&["password"])));
+        assert!(policy.next_methods(&done(&["password"])).is_empty());
+        assert!(AuthPolicy::parse_methods("publickey,keyboard-interactive").is_err());
+        assert!(AuthPolicy::parse_methods(" ").is_err());
+    }
+
+    #[test]
+    fn unknown_methods_decode() {
+        let mut writer = Writer::new();
+        writer.write_byte(SSH_MSG_USERAUTH_REQUEST);
+        writer.write_string(b"alice");
+        writer.write_string(CONNECTION_SERVICE.as_bytes());
+        writer.write_string(b"keyboard-interactive");
+        writer.write_string(b"");
+        writer.write_string(b"");
+        let request = AuthRequest::from_bytes(&writer.into_bytes()).unwrap();
+        assert_eq!(request.method.name(), "keyboard-interactive");
+
+        let none = AuthRequest { method: AuthMethod::None, ..request };
+        let decoded = AuthRequest::from_bytes(&none.to_bytes()).unwrap();
+        assert!(matches!(decoded.method, AuthMethod::None));
+    }
+
+    /// Run `handle_auth_request` on one end of a socket pair, with a user
+    /// database holding alice/secret, and return the other end
+    fn spawn_auth_server(
+        policy: AuthPolicy,
+    ) -> (Transport, std::thread::JoinHandle<Result<AuthenticatedUser>>, tempfile::TempDir) {
+        let dir = tempfile::tempdir().unwrap();
+        let users_file = dir.path().join("users.json");
+        let mut users = UserDatabase::new(&users_file);
+        users.set_password("alice", "secret").unwrap();
+        users.save().unwrap();
+
+        let (server_stream, client_stream) = std::os::unix::net::UnixStream::pair().unwrap();
+        let policy = AuthPolicy { users_file: Some(users_file), ..policy };
+        let server = std::thread::spawn(move || {
+            let mut transport = Transport::new(Box::new(server_stream));
+            let address = IpAddr::from([127, 0, 0, 1]);
+            handle_auth_request(&mut transport, &[1, 2, 3], &[7u8; 32], address, &policy)
+        });
+        (Transport::new(Box::new(client_stream)), server, dir)
+    }
+
+    fn request(username: &str, method: AuthMethod) -> AuthRequest {
+        AuthRequest { username: username.to_string(), service: CONNECTION_SERVICE.to_string(), method }
+    }
+
+    fn expect_failure(transport: &mut Transport) -> AuthFailure {
+        AuthFailure::from_bytes(&transport.recv_payload().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn unsupported_requests_fail_without_disconnecting() {
+        let (mut client, server, _dir) = spawn_auth_server(AuthPolicy::default());
+
+        // A certificate, as OpenSSH clients offer before plain keys
+        let key = KeyPair::generate(KeyAlgorithm::Ed25519).unwrap();
+        client.send(&request("alice", AuthMethod::PublicKey {
+            algorithm: "ssh-ed25519-cert-v01@openssh.com".to_string(),
+            public_key: key.public_key_blob(),
+            host_key: None,
+            signature: None,
+        }))
+        .unwrap();
+        let failure = expect_failure(&mut client);
+        assert_eq!(failure.methods, ["publickey", "password"]);
+        assert!(!failure.partial_success);
+
+        // A password change request
+        let mut writer = Writer::new();
+        writer.write_byte(SSH_MSG_USERAUTH_REQUEST);
+        for field in ["alice", CONNECTION_SERVICE, "password"] {
+            writer.write_string(field.as_bytes());
+        }
+        writer.write_bool(true);
+        writer.write_string(b"secret");
+        writer.write_string(b"new secret");
+        client.send_payload(&writer.into_bytes()).unwrap();
+        assert!(!expect_failure(&mut client).partial_success);
+
+        client.send(&request("alice", AuthMethod::Password("secret".to_string()))).unwrap();
+        AuthSuccess::from_bytes(&client.recv_payload().unwrap()).unwrap();
+        assert_eq!(server.join().unwrap().unwrap().username, "alice");
+    }
+
+    #[test]
+    fn refused_key_queries_count_as_attempts() {
+        let policy = AuthPolicy { max_tries: 3, ..AuthPolicy::default() };
+        let (mut client, server, _dir) = spawn_auth_server(policy);
+
+        // None requests are free; keys nobody authorized are not
+        client.send(&request("alice", AuthMethod::None)).unwrap();
+        expect_failure(&mut client);
+        for _ in 0..3 {
+            let key = KeyPair::generate(KeyAlgorithm::Ed25519).unwrap();
+            client.send(&request("alice", AuthMethod::PublicKey {
+                algorithm: "ssh-ed25519".to_string(),
+                public_key: key.public_key_blob(),
+                host_key: None,
+                signature: None,
+            }))
+            .unwrap();
+        }
+        expect_failure(&mut client);
+        expect_failure(&mut client);
+        let error = server.join().unwrap().err().unwrap();
+        assert!(error.to_string().contains("Too many authentication failures"), "{}", error);
+    }
+}
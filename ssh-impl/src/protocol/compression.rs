@@ -0,0 +1,200 @@
+use anyhow::{Result, Context};
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+
+/// Largest payload we inflate a compressed payload to, so a small packet
+/// cannot expand without bound
+const MAX_DECOMPRESSED_SIZE: usize = 256 * 1024;
+
+/// Negotiated compression method of one direction (RFC 4253 §6.2)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompressionAlgorithm {
+    None,
+    /// `zlib`: starts with the first packet after SSH_MSG_NEWKEYS
+    Zlib,
+    /// `zlib@openssh.com`: starts only once user authentication succeeded,
+    /// so unauthenticated peers never reach the decompressor
+    ZlibDelayed,
+}
+
+impl CompressionAlgorithm {
+    /// Method of a compression algorithm name
+    pub fn from_name(name: &str) -> Result<Self> {
+        match name {
+            "none" => Ok(Self::None),
+            "zlib" => Ok(Self::Zlib),
+            "zlib@openssh.com" => Ok(Self::ZlibDelayed),
+            other => anyhow::bail!("Unsupported compression algorithm: {}", other),
+        }
+    }
+
+    /// Whether payloads are compressed at this point of the connection
+    pub fn is_active(self, authenticated: bool) -> bool {
+        match self {
+            Self::None => false,
+            Self::Zlib => true,
+            Self::ZlibDelayed => authenticated,
+        }
+    }
+}
+
+/// Payload sizes before and after compression in one direction
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CompressionStats {
+    pub uncompressed_bytes: u64,
+    pub compressed_bytes: u64,
+}
+
+impl CompressionStats {
+    /// Uncompressed size divided by compressed size (1.0 before any data)
+    pub fn ratio(&self) -> f64 {
+        if self.compressed_bytes == 0 {
+            1.0
+        } else {
+            self.uncompressed_bytes as f64 / self.compressed_bytes as f64
+        }
+    }
+
+    fn record(&mut self, uncompressed: usize, compressed: usize) {
+        self.uncompressed_bytes += uncompressed as u64;
+        self.compressed_bytes += compressed as u64;
+    }
+}
+
+impl std::fmt::Display for CompressionStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} -> {} bytes (ratio {:.2})",
+            self.uncompressed_bytes,
+            self.compressed_bytes,
+            self.ratio()
+        )
+    }
+}
+
+/// zlib stream compressing every outgoing payload. The stream runs on
+/// across packets (and key re-exchanges); each payload ends with a partial
+/// flush so the peer can decompress it on its own.
+pub struct Compressor {
+    stream: Compress,
+}
+
+impl Default for Compressor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Compressor {
+    pub fn new() -> Self {
+        Self {
+            stream: Compress::new(Compression::default(), true),
+        }
+    }
+
+    /// Compress one payload
+    pub fn compress(&mut self, payload: &[u8], stats: &mut CompressionStats) -> Result<Vec<u8>> {
+        let mut output = Vec::with_capacity(payload.len() + 64);
+        let start = self.stream.total_in();
+        loop {
+            let consumed = (self.stream.total_in() - start) as usize;
+            self.stream.compress_vec(&payload[consumed..], &mut output, FlushCompress::Partial)
+                .context("Failed to compress payload")?;
+            // The flush is complete once zlib stops filling the whole buffer
+            if output.len() < output.capacity() {
+                break;
+            }
+            output.reserve(output.capacity());
+        }
+        stats.record(payload.len(), output.len());
+        Ok(output)
+    }
+}
+
+/// zlib stream decompressing every incoming payload
+pub struct Decompressor {
+    stream: Decompress,
+}
+
+impl Default for Decompressor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decompressor {
+    pub fn new() -> Self {
+        Self {
+            stream: Decompress::new(true),
+        }
+    }
+
+    /// Decompress one payload
+    pub fn decompress(&mut self, payload: &[u8], stats: &mut CompressionStats) -> Result<Vec<u8>> {
+        let mut output = Vec::with_capacity(payload.len() * 4);
+        let start = self.stream.total_in();
+        loop {
+            let consumed = (self.stream.total_in() - start) as usize;
+            let status = self.stream.decompress_vec(&payload[consumed..], &mut output, FlushDecompress::Sync)
+                .context("Failed to decompress payload")?;
+            if status == Status::StreamEnd {
+                anyhow::bail!("Peer ended its compression stream");
+            }
+            if output.len() > MAX_DECOMPRESSED_SIZE {
+                anyhow::bail!("Decompressed payload exceeds {} bytes", MAX_DECOMPRESSED_SIZE);
+            }
+            let consumed = (self.stream.total_in() - start) as usize;
+            if consumed == payload.len() && output.len() < output.capacity() {
+                break;
+            }
+            output.reserve(output.capacity().max(1024));
+        }
+        stats.record(output.len(), payload.len());
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn payloads_round_trip_over_one_stream() {
+        let mut compressor = Compressor::new();
+        let mut decompressor = Decompressor::new();
+        let mut sent = CompressionStats::default();
+        let mut received = CompressionStats::default();
+
+        let log_line = b"2026-10-17 12:00:00 INFO request handled in 3 ms\n".repeat(200);
+        for payload in [&b"first"[..], &log_line, &b""[..], &log_line] {
+            let compressed = compressor.compress(payload, &mut sent).unwrap();
+            let decompressed = decompressor.decompress(&compressed, &mut received).unwrap();
+            assert_eq!(decompressed, payload);
+        }
+
+        assert_eq!(sent.uncompressed_bytes, received.uncompressed_bytes);
+        assert_eq!(sent.compressed_bytes, received.compressed_bytes);
+        assert!(sent.ratio() > 10.0, "{}", sent);
+    }
+
+    #[test]
+    fn rejects_oversized_payload() {
+        let mut compressor = Compressor::new();
+        let mut decompressor = Decompressor::new();
+        let mut stats = CompressionStats::default();
+
+        let compressed = compressor.compress(&vec![0u8; MAX_DECOMPRESSED_SIZE * 2], &mut stats).unwrap();
+        assert!(decompressor.decompress(&compressed, &mut stats).is_err());
+    }
+
+    #[test]
+    fn rejects_payload_just_over_the_limit() {
+        let mut compressor = Compressor::new();
+        let mut decompressor = Decompressor::new();
+        let mut stats = CompressionStats::default();
+
+        // Lands between the last buffer doubling and the next one
+        let compressed = compressor.compress(&vec![0u8; 400 * 1024], &mut stats).unwrap();
+        assert!(decompressor.decompress(&compressed, &mut stats).is_err());
+    }
+}
@@ -0,0 +1,147 @@
+use anyhow::{Result, Context};
+use crate::protocol::messages::{
+    expect_message, SSH_MSG_EXT_INFO, SSH_MSG_PING, SSH_MSG_PONG,
+};
+use crate::utils::wire::{Decode, Encode, Reader, Writer};
+
+/// Signature algorithms the server accepts for publickey authentication (RFC 8308 §3.1)
+pub const SERVER_SIG_ALGS: &str = "server-sig-algs";
+
+/// Transport-level SSH_MSG_PING / SSH_MSG_PONG (OpenSSH PROTOCOL §1.9)
+pub const PING: &str = "ping@openssh.com";
+
+/// publickey-hostbound-v00@openssh.com user authentication (OpenSSH PROTOCOL §3.2)
+pub const PUBLICKEY_HOSTBOUND: &str = "publickey-hostbound@openssh.com";
+
+/// Version advertised for the OpenSSH extensions
+const OPENSSH_EXTENSION_VERSION: &[u8] = b"0";
+
+/// SSH_MSG_EXT_INFO (RFC 8308 §2.3): extension names with opaque values
+#[derive(Debug, Clone)]
+pub struct ExtInfo {
+    pub extensions: Vec<(String, Vec<u8>)>,
+}
+
+impl Encode for ExtInfo {
+    fn encode(&self, writer: &mut Writer) {
+        writer.write_byte(SSH_MSG_EXT_INFO);
+        writer.write_u32(self.extensions.len() as u32);
+        for (name, value) in &self.extensions {
+            writer.write_string(name.as_bytes());
+            writer.write_string(value);
+        }
+    }
+}
+
+impl Decode for ExtInfo {
+    fn decode(reader: &mut Reader<'_>) -> Result<Self> {
+        expect_message(reader, SSH_MSG_EXT_INFO)?;
+        let count = reader.read_u32()?;
+        // Every extension takes at least two length fields, so the count
+        // cannot be trusted for a preallocation
+        let mut extensions = Vec::new();
+        for _ in 0..count {
+            let name = reader.read_utf8()
+                .context("Invalid extension name")?;
+            let value = reader.read_string()
+                .with_context(|| format!("Invalid value of extension {}", name))?;
+            extensions.push((name, value.to_vec()));
+        }
+        Ok(Self { extensions })
+    }
+}
+
+/// SSH_MSG_PING: the peer answers with SSH_MSG_PONG carrying the same data
+pub struct Ping {
+    pub data: Vec<u8>,
+}
+
+impl Encode for Ping {
+    fn encode(&self, writer: &mut Writer) {
+        writer.write_byte(SSH_MSG_PING);
+        writer.write_string(&self.data);
+    }
+}
+
+impl Decode for Ping {
+    fn decode(reader: &mut Reader<'_>) -> Result<Self> {
+        expect_message(reader, SSH_MSG_PING)?;
+        Ok(Self {
+            data: reader.read_string()?.to_vec(),
+        })
+    }
+}
+
+/// SSH_MSG_PONG
+pub struct Pong {
+    pub data: Vec<u8>,
+}
+
+impl Encode for Pong {
+    fn encode(&self, writer: &mut Writer) {
+        writer.write_byte(SSH_MSG_PONG);
+        writer.write_string(&self.data);
+    }
+}
+
+impl Decode for Pong {
+    fn decode(reader: &mut Reader<'_>) -> Result<Self> {
+        expect_message(reader, SSH_MSG_PONG)?;
+        Ok(Self {
+            data: reader.read_string()?.to_vec(),
+        })
+    }
+}
+
+/// What the peer announced in its SSH_MSG_EXT_INFO. Extensions we do not
+/// know are ignored (RFC 8308 §2.5).
+#[derive(Debug, Clone, Default)]
+pub struct Extensions {
+    /// `None` if the server did not say; it may still accept some algorithms
+    pub server_sig_algs: Option<Vec<String>>,
+    pub ping: bool,
+    pub publickey_hostbound: bool,
+}
+
+impl Extensions {
+    /// Record an SSH_MSG_EXT_INFO. Later messages update earlier ones.
+    pub fn update(&mut self, ext_info: &ExtInfo) -> Result<()> {
+        for (name, value) in &ext_info.extensions {
+            match name.as_str() {
+                SERVER_SIG_ALGS => {
+                    let algorithms = std::str::from_utf8(value)
+                        .context("Invalid server-sig-algs")?;
+                    self.server_sig_algs = Some(
+                        algorithms.split(',')
+                            .filter(|name| !name.is_empty())
+                            .map(str::to_string)
+                            .collect(),
+                    );
+                }
+                PING => self.ping = value == OPENSSH_EXTENSION_VERSION,
+                PUBLICKEY_HOSTBOUND => self.publickey_hostbound = value == OPENSSH_EXTENSION_VERSION,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The SSH_MSG_EXT_INFO a server sends after its first SSH_MSG_NEWKEYS
+pub fn server_ext_info(signature_algorithms: &[&str]) -> ExtInfo {
+    ExtInfo {
+        extensions: vec![
+            (SERVER_SIG_ALGS.to_string(), signature_algorithms.join(",").into_bytes()),
+            (PUBLICKEY_HOSTBOUND.to_string(), OPENSSH_EXTENSION_VERSION.to_vec()),
+            (PING.to_string(), OPENSSH_EXTENSION_VERSION.to_vec()),
+        ],
+    }
+}
+
+/// Names of the extensions in a message, for logs
+pub fn extension_names(ext_info: &ExtInfo) -> String {
+    ext_info.extensions.iter()
+        .map(|(name, _)| name.as_str())
+        .collect::<Vec<_>>()
+        .join(",")
+}
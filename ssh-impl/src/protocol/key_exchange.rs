@@ -1,121 +1,762 @@
 use anyhow::{Result, Context};
-use crate::crypto::dh::{EphemeralKeyPair, derive_session_keys, SessionKeys};
-use crate::utils::packet::Packet;
-use crate::utils::stream::ReadWrite;
+use ring::digest;
+use crate::crypto::dh::{derive_session_keys, SessionKeys};
+use crate::crypto::encryption::{key_lengths, new_cipher};
+use std::path::{Path, PathBuf};
+use num_bigint::BigUint;
+use crate::crypto::ffdh::{modp_groups, FiniteFieldDh};
+use crate::crypto::kex::{group_exchange_hash, new_kex_method, KexMethod};
+use crate::crypto::keys::KeyPair;
+use crate::crypto::moduli::{choose_group, load_moduli, DhGroup, DEFAULT_MODULI_FILE};
+use crate::protocol::client_auth::SIGNATURE_ALGORITHMS;
+use crate::protocol::compression::CompressionAlgorithm;
+use crate::protocol::extensions::{server_ext_info, ExtInfo};
+use crate::protocol::messages::{
+    expect_message, SSH_MSG_KEX_DH_GEX_GROUP, SSH_MSG_KEX_DH_GEX_INIT, SSH_MSG_KEX_DH_GEX_REPLY,
+    SSH_MSG_KEX_DH_GEX_REQUEST, SSH_MSG_KEX_ECDH_INIT, SSH_MSG_KEX_ECDH_REPLY, SSH_MSG_NEWKEYS,
+};
+use crate::protocol::negotiation::{
+    exchange_kexinit, AlgorithmPreferences, KexInit, KexInitExchange, NegotiatedAlgorithms,
+    EXT_INFO_CLIENT, EXT_INFO_SERVER, KEX_STRICT_CLIENT, KEX_STRICT_SERVER,
+};
+use crate::protocol::server_auth::{sign_exchange_hash, verify_host_key};
+use crate::protocol::transport::Transport;
+use crate::utils::wire::{Decode, Encode, Reader, Writer};
 
-/// Perform key exchange (server side)
-pub fn server_key_exchange(
-    stream: &mut dyn ReadWrite,
+/// Client ephemeral public value: Q_C in SSH_MSG_KEX_ECDH_INIT (RFC 5656 §4),
+/// e in SSH_MSG_KEXDH_INIT (same number) or SSH_MSG_KEX_DH_GEX_INIT
+pub struct KexDhInit {
+    pub message_type: u8,
+    pub client_public_key: Vec<u8>,
+}
+
+impl Encode for KexDhInit {
+    fn encode(&self, writer: &mut Writer) {
+        writer.write_byte(self.message_type);
+        writer.write_string(&self.client_public_key);
+    }
+}
+
+impl KexDhInit {
+    /// Decode the message the negotiated method uses
+    pub fn decode_as(payload: &[u8], message_type: u8) -> Result<Self> {
+        let mut reader = Reader::new(payload);
+        expect_message(&mut reader, message_type)?;
+        let message = Self {
+            message_type,
+            client_public_key: reader.read_string()?.to_vec(),
+        };
+        reader.finish()?;
+        Ok(message)
+    }
+}
+
+/// Host key K_S, server ephemeral public value (Q_S or f) and the host key's
+/// signature over the exchange hash: SSH_MSG_KEX_ECDH_REPLY / SSH_MSG_KEXDH_REPLY
+/// or SSH_MSG_KEX_DH_GEX_REPLY
+pub struct KexDhReply {
+    pub message_type: u8,
+    pub host_key: Vec<u8>,
+    pub server_public_key: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+impl Encode for KexDhReply {
+    fn encode(&self, writer: &mut Writer) {
+        writer.write_byte(self.message_type);
+        writer.write_string(&self.host_key);
+        writer.write_string(&self.server_public_key);
+        writer.write_string(&self.signature);
+    }
+}
+
+impl KexDhReply {
+    /// Decode the message the negotiated method uses
+    pub fn decode_as(payload: &[u8], message_type: u8) -> Result<Self> {
+        let mut reader = Reader::new(payload);
+        expect_message(&mut reader, message_type)?;
+        let message = Self {
+            message_type,
+            host_key: reader.read_string()?.to_vec(),
+            server_public_key: reader.read_string()?.to_vec(),
+            signature: reader.read_string()?.to_vec(),
+        };
+        reader.finish()?;
+        Ok(message)
+    }
+}
+
+/// SSH_MSG_KEX_DH_GEX_REQUEST: group sizes in bits the client accepts
+/// (RFC 4419 §3)
+pub struct KexDhGexRequest {
+    pub min: u32,
+    pub preferred: u32,
+    pub max: u32,
+}
+
+impl Encode for KexDhGexRequest {
+    fn encode(&self, writer: &mut Writer) {
+        writer.write_byte(SSH_MSG_KEX_DH_GEX_REQUEST);
+        writer.write_u32(self.min);
+        writer.write_u32(self.preferred);
+        writer.write_u32(self.max);
+    }
+}
+
+impl Decode for KexDhGexRequest {
+    fn decode(reader: &mut Reader<'_>) -> Result<Self> {
+        expect_message(reader, SSH_MSG_KEX_DH_GEX_REQUEST)?;
+        Ok(Self {
+            min: reader.read_u32()?,
+            preferred: reader.read_u32()?,
+            max: reader.read_u32()?,
+        })
+    }
+}
+
+/// SSH_MSG_KEX_DH_GEX_GROUP: safe prime p and generator g chosen by the server
+pub struct KexDhGexGroup {
+    pub prime: Vec<u8>,
+    pub generator: Vec<u8>,
+}
+
+impl Encode for KexDhGexGroup {
+    fn encode(&self, writer: &mut Writer) {
+        writer.write_byte(SSH_MSG_KEX_DH_GEX_GROUP);
+        writer.write_mpint(&self.prime);
+        writer.write_mpint(&self.generator);
+    }
+}
+
+impl Decode for KexDhGexGroup {
+    fn decode(reader: &mut Reader<'_>) -> Result<Self> {
+        expect_message(reader, SSH_MSG_KEX_DH_GEX_GROUP)?;
+        Ok(Self {
+            prime: reader.read_mpint()?,
+            generator: reader.read_mpint()?,
+        })
+    }
+}
+
+/// SSH_MSG_NEWKEYS (RFC 4253 §7.3)
+pub struct NewKeys;
+
+impl Encode for NewKeys {
+    fn encode(&self, writer: &mut Writer) {
+        writer.write_byte(SSH_MSG_NEWKEYS);
+    }
+}
+
+impl Decode for NewKeys {
+    fn decode(reader: &mut Reader<'_>) -> Result<Self> {
+        expect_message(reader, SSH_MSG_NEWKEYS)?;
+        Ok(Self)
+    }
+}
+
+/// Identification strings from Phase 2 (without CR LF), covered by the
+/// exchange hash
+pub struct VersionStrings {
+    pub client: String,
+    pub server: String,
+}
+
+/// Parameters that stay the same for every key exchange of a connection.
+/// The transport keeps them so that either side can start a re-exchange.
+pub struct KexContext {
+    pub is_server: bool,
+    pub versions: VersionStrings,
+    pub preferences: AlgorithmPreferences,
+    /// Host keys, one of which signs the exchange hash (server side)
+    pub host_keys: Vec<KeyPair>,
+    /// Name the server is checked against in known_hosts (client side)
+    pub hostname: String,
+    /// known_hosts file holding the host keys we trust (client side)
+    pub known_hosts_file: PathBuf,
+    /// Safe primes offered in a group exchange (server side)
+    pub moduli_file: PathBuf,
+    /// H of the first key exchange, once it has completed
+    pub session_id: Option<Vec<u8>>,
+}
+
+impl KexContext {
+    /// Server side context signing with one of `host_keys`
+    pub fn server(
+        versions: VersionStrings,
+        preferences: AlgorithmPreferences,
+        host_keys: Vec<KeyPair>,
+    ) -> Self {
+        Self {
+            is_server: true,
+            versions,
+            preferences,
+            host_keys,
+            hostname: String::new(),
+            known_hosts_file: PathBuf::new(),
+            moduli_file: PathBuf::from(DEFAULT_MODULI_FILE),
+            session_id: None,
+        }
+    }
+
+    /// Client side context verifying the host key of `hostname` against
+    /// `known_hosts_file`
+    pub fn client(
+        versions: VersionStrings,
+        preferences: AlgorithmPreferences,
+        hostname: &str,
+        known_hosts_file: PathBuf,
+    ) -> Self {
+        Self {
+            is_server: false,
+            versions,
+            preferences,
+            host_keys: Vec::new(),
+            hostname: hostname.to_string(),
+            known_hosts_file,
+            moduli_file: PathBuf::from(DEFAULT_MODULI_FILE),
+            session_id: None,
+        }
+    }
+}
+
+/// Outcome of a completed key exchange. The session keys themselves are
+/// already installed in the transport.
+pub struct KeyExchangeResult {
+    pub algorithms: NegotiatedAlgorithms,
+    /// Exchange hash H of this key exchange
+    pub exchange_hash: Vec<u8>,
+    /// H of the first key exchange; identifies the connection from now on
+    pub session_id: Vec<u8>,
+    /// Server host key K_S in SSH blob format
+    pub host_key: Vec<u8>,
+}
+
+/// What the key exchange method contributes to the exchange hash
+struct EphemeralValues<'a> {
+    /// Group exchange parameters `min || n || max || p || g`, empty otherwise
+    parameters: &'a [u8],
+    /// Q_C or e
+    client_public_key: &'a [u8],
+    /// Q_S or f
+    server_public_key: &'a [u8],
+}
+
+/// Exchange hash H (RFC 5656 §4, RFC 8731 §3, RFC 4253 §8):
+/// HASH(V_C || V_S || I_C || I_S || K_S || Q_C || Q_S || K). A group exchange
+/// adds its parameters after K_S (RFC 4419 §3).
+fn compute_exchange_hash(
+    hash: &'static digest::Algorithm,
+    versions: &VersionStrings,
+    kexinit: &KexInitExchange,
+    host_key_blob: &[u8],
+    values: &EphemeralValues<'_>,
+    encoded_secret: &[u8],
+) -> Vec<u8> {
+    let mut writer = Writer::new();
+    writer.write_string(versions.client.as_bytes());
+    writer.write_string(versions.server.as_bytes());
+    writer.write_string(&kexinit.client_kexinit);
+    writer.write_string(&kexinit.server_kexinit);
+    writer.write_string(host_key_blob);
+    writer.write_raw(values.parameters);
+    writer.write_string(values.client_public_key);
+    writer.write_string(values.server_public_key);
+    writer.write_raw(encoded_secret);
+    digest::digest(hash, &writer.into_bytes()).as_ref().to_vec()
+}
+
+/// Derive both directions' keys sized for the negotiated ciphers
+fn derive_keys(
+    hash: &'static digest::Algorithm,
+    algorithms: &NegotiatedAlgorithms,
+    encoded_secret: &[u8],
+    exchange_hash: &[u8],
+    session_id: &[u8],
 ) -> Result<SessionKeys> {
+    let session_keys = derive_session_keys(
+        hash,
+        encoded_secret,
+        exchange_hash,
+        session_id,
+        key_lengths(
+            &algorithms.cipher_client_to_server,
+            algorithms.mac_client_to_server.as_deref(),
+        )?,
+        key_lengths(
+            &algorithms.cipher_server_to_client,
+            algorithms.mac_server_to_client.as_deref(),
+        )?,
+    );
+    println!("[Phase 3] Derived session keys (IVs, encryption and integrity keys per direction)");
+    Ok(session_keys)
+}
+
+/// Exchange KEXINIT messages. The initial exchange also offers strict key
+/// exchange, which then holds for the rest of the connection, and extension
+/// negotiation.
+fn negotiate_algorithms(
+    transport: &mut Transport,
+    context: &KexContext,
+    peer_kexinit: Option<Vec<u8>>,
+) -> Result<KexInitExchange> {
+    let initial = context.session_id.is_none();
+    let mut ours = KexInit::from_preferences(&context.preferences)?;
+    if context.is_server {
+        // Only offer host key algorithms we hold a key for
+        ours.server_host_key_algorithms.retain(|algorithm| {
+            context.host_keys.iter().any(|key| key.algorithm().supports(algorithm))
+        });
+        if ours.server_host_key_algorithms.is_empty() {
+            anyhow::bail!("No host key for any configured host key algorithm");
+        }
+    }
+    if initial {
+        let markers = if context.is_server {
+            [EXT_INFO_SERVER, KEX_STRICT_SERVER]
+        } else {
+            [EXT_INFO_CLIENT, KEX_STRICT_CLIENT]
+        };
+        ours.kex_algorithms.extend(markers.iter().map(|marker| marker.to_string()));
+    }
+
+    let kexinit = exchange_kexinit(transport, ours, context.is_server, peer_kexinit)?;
+    if initial && kexinit.strict_kex {
+        transport.enable_strict_kex()?;
+    }
+    Ok(kexinit)
+}
+
+/// Send SSH_MSG_NEWKEYS and wait for the peer's. Each direction switches to
+/// the new keys right after its NEWKEYS (RFC 4253 §7.3). `ext_info` is sent
+/// as the first encrypted packet (RFC 8308 §2.4).
+fn exchange_newkeys(
+    transport: &mut Transport,
+    algorithms: &NegotiatedAlgorithms,
+    session_keys: &SessionKeys,
+    is_server: bool,
+    ext_info: Option<ExtInfo>,
+) -> Result<()> {
+    let client_to_server = new_cipher(
+        &algorithms.cipher_client_to_server,
+        algorithms.mac_client_to_server.as_deref(),
+        &session_keys.client_to_server,
+    )?;
+    let server_to_client = new_cipher(
+        &algorithms.cipher_server_to_client,
+        algorithms.mac_server_to_client.as_deref(),
+        &session_keys.server_to_client,
+    )?;
+    let client_to_server_compression =
+        CompressionAlgorithm::from_name(&algorithms.compression_client_to_server)?;
+    let server_to_client_compression =
+        CompressionAlgorithm::from_name(&algorithms.compression_server_to_client)?;
+    let (outgoing_cipher, incoming_cipher, outgoing_compression, incoming_compression) = if is_server {
+        (server_to_client, client_to_server, server_to_client_compression, client_to_server_compression)
+    } else {
+        (client_to_server, server_to_client, client_to_server_compression, server_to_client_compression)
+    };
+
+    transport.send(&NewKeys)
+        .context("Failed to send NEWKEYS")?;
+    transport.set_outgoing_cipher(outgoing_cipher);
+    transport.set_outgoing_compression(outgoing_compression);
+    if let Some(ext_info) = ext_info {
+        transport.send(&ext_info)
+            .context("Failed to send EXT_INFO")?;
+        println!("[Phase 3] Sent EXT_INFO");
+    }
+
+    transport.recv::<NewKeys>()
+        .context("Failed to receive NEWKEYS")?;
+    transport.set_incoming_cipher(incoming_cipher);
+    transport.set_incoming_compression(incoming_compression);
+
+    println!("[Phase 3] Exchanged NEWKEYS");
+    Ok(())
+}
+
+/// Group sizes in bits the client asks for in a group exchange. The server
+/// keeps to the same bounds.
+const GEX_MIN_BITS: u32 = 2048;
+const GEX_PREFERRED_BITS: u32 = 3072;
+const GEX_MAX_BITS: u32 = 8192;
+
+/// The negotiated key exchange method, ready for the ephemeral exchange
+struct PreparedMethod {
+    method: Box<dyn KexMethod>,
+    /// Group exchange parameters covered by the exchange hash
+    parameters: Vec<u8>,
+    /// Message numbers of the client's public value and the server's reply
+    init_message: u8,
+    reply_message: u8,
+}
+
+impl PreparedMethod {
+    /// A method that needs no negotiation of its own
+    fn fixed(name: &str) -> Result<Self> {
+        Ok(Self {
+            method: new_kex_method(name)?,
+            parameters: Vec::new(),
+            init_message: SSH_MSG_KEX_ECDH_INIT,
+            reply_message: SSH_MSG_KEX_ECDH_REPLY,
+        })
+    }
+
+    /// Diffie-Hellman over the group agreed in a group exchange
+    fn group_exchange(
+        group: DhGroup,
+        hash: &'static digest::Algorithm,
+        request: &KexDhGexRequest,
+    ) -> Self {
+        let mut writer = Writer::new();
+        writer.write_u32(request.min);
+        writer.write_u32(request.preferred);
+        writer.write_u32(request.max);
+        writer.write_mpint(&group.prime.to_bytes_be());
+        writer.write_mpint(&group.generator.to_bytes_be());
+        Self {
+            method: Box::new(FiniteFieldDh::new(group, hash)),
+            parameters: writer.into_bytes(),
+            init_message: SSH_MSG_KEX_DH_GEX_INIT,
+            reply_message: SSH_MSG_KEX_DH_GEX_REPLY,
+        }
+    }
+}
+
+/// Groups the server can offer: the moduli file, or the fixed MODP groups if
+/// there is no usable one
+fn server_groups(moduli_file: &Path) -> Vec<DhGroup> {
+    match load_moduli(moduli_file) {
+        Ok(groups) if !groups.is_empty() => groups,
+        Ok(_) => {
+            println!("[Phase 3] No safe primes in {}, using built-in groups", moduli_file.display());
+            modp_groups()
+        }
+        Err(e) => {
+            println!("[Phase 3] {:#}, using built-in groups", e);
+            modp_groups()
+        }
+    }
+}
+
+/// Set up the negotiated method (server side). A group exchange first
+/// answers the client's size request with a group (RFC 4419 §3).
+fn server_prepare_method(
+    transport: &mut Transport,
+    context: &KexContext,
+    name: &str,
+) -> Result<PreparedMethod> {
+    let Some(hash) = group_exchange_hash(name) else {
+        return PreparedMethod::fixed(name);
+    };
+
+    let request: KexDhGexRequest = transport.recv()
+        .context("Failed to receive group exchange request")?;
+    println!(
+        "[Phase 3] Client asks for a {}-bit group ({}-{} bits)",
+        request.preferred, request.min, request.max
+    );
+    let min = request.min.max(GEX_MIN_BITS);
+    let max = request.max.min(GEX_MAX_BITS);
+    let preferred = request.preferred.clamp(GEX_MIN_BITS, GEX_MAX_BITS);
+    if min > max || preferred < min || preferred > max {
+        anyhow::bail!(
+            "Unacceptable group size request: {}/{}/{}",
+            request.min, request.preferred, request.max
+        );
+    }
+
+    let group = choose_group(&server_groups(&context.moduli_file), min, preferred, max)
+        .with_context(|| format!("No group between {} and {} bits", min, max))?;
+    println!("[Phase 3] Chose a {}-bit group", group.bits());
+    transport.send(&KexDhGexGroup {
+        prime: group.prime.to_bytes_be(),
+        generator: group.generator.to_bytes_be(),
+    }).context("Failed to send group")?;
+
+    Ok(PreparedMethod::group_exchange(group, hash, &request))
+}
+
+/// Set up the negotiated method (client side). A group exchange first asks
+/// the server for a group of suitable size.
+fn client_prepare_method(transport: &mut Transport, name: &str) -> Result<PreparedMethod> {
+    let Some(hash) = group_exchange_hash(name) else {
+        return PreparedMethod::fixed(name);
+    };
+
+    let request = KexDhGexRequest {
+        min: GEX_MIN_BITS,
+        preferred: GEX_PREFERRED_BITS,
+        max: GEX_MAX_BITS,
+    };
+    transport.send(&request)
+        .context("Failed to send group exchange request")?;
+    println!(
+        "[Phase 3] Requested a {}-bit group ({}-{} bits)",
+        request.preferred, request.min, request.max
+    );
+
+    let message: KexDhGexGroup = transport.recv()
+        .context("Failed to receive group")?;
+    let group = DhGroup {
+        prime: BigUint::from_bytes_be(&message.prime),
+        generator: BigUint::from_bytes_be(&message.generator),
+    };
+    // The server picks the group, so at least keep it to what we asked for
+    let one = BigUint::from(1u32);
+    if !(request.min..=request.max).contains(&group.bits()) {
+        anyhow::bail!("Server sent a {}-bit group", group.bits());
+    }
+    if group.generator <= one || group.generator >= &group.prime - &one {
+        anyhow::bail!("Server sent an invalid generator");
+    }
+    println!("[Phase 3] Received a {}-bit group", group.bits());
+
+    Ok(PreparedMethod::group_exchange(group, hash, &request))
+}
+
+/// Run a complete key exchange: the first one of the connection or a
+/// re-exchange. `peer_kexinit` is set when the peer started it.
+pub fn perform_key_exchange(
+    transport: &mut Transport,
+    context: &mut KexContext,
+    peer_kexinit: Option<Vec<u8>>,
+) -> Result<KeyExchangeResult> {
+    let result = if context.is_server {
+        server_key_exchange(transport, context, peer_kexinit)?
+    } else {
+        client_key_exchange(transport, context, peer_kexinit)?
+    };
+    context.session_id = Some(result.session_id.clone());
+    Ok(result)
+}
+
+/// Perform key exchange (server side)
+fn server_key_exchange(
+    transport: &mut Transport,
+    context: &KexContext,
+    peer_kexinit: Option<Vec<u8>>,
+) -> Result<KeyExchangeResult> {
     println!("[Phase 3] Starting key exchange (server side)...");
-    
-    // Generate server ephemeral key pair
-    let server_keypair = EphemeralKeyPair::generate()
-        .context("Failed to generate server key pair")?;
-    println!("[Phase 3] Generated server ephemeral key pair");
-    
-    // Send server public key to client
-    let mut server_pubkey_packet = Vec::new();
-    server_pubkey_packet.extend_from_slice(&(server_keypair.public_key.len() as u32).to_be_bytes());
-    server_pubkey_packet.extend_from_slice(&server_keypair.public_key);
-    
-    let packet = Packet::new(server_pubkey_packet);
-    packet.write(stream)
-        .context("Failed to send server public key")?;
-    println!("[Phase 3] Sent server public key ({} bytes)", server_keypair.public_key.len());
-    
+    let kexinit = negotiate_algorithms(transport, context, peer_kexinit)?;
+    let host_key_algorithm = &kexinit.algorithms.host_key;
+    let host_key = context.host_keys.iter()
+        .find(|key| key.algorithm().supports(host_key_algorithm))
+        .with_context(|| format!("No host key for {}", host_key_algorithm))?;
+    let mut prepared = server_prepare_method(transport, context, &kexinit.algorithms.kex)?;
+    let hash = prepared.method.hash_algorithm();
+
     // Receive client public key
-    let client_pubkey_packet = Packet::read(stream)
+    let payload = transport.recv_payload()
+        .context("Failed to receive client public key")?;
+    let init = KexDhInit::decode_as(&payload, prepared.init_message)
         .context("Failed to receive client public key")?;
-    
-    if client_pubkey_packet.payload.len() < 4 {
-        anyhow::bail!("Invalid client public key packet");
-    }
-    
-    let key_len = u32::from_be_bytes([
-        client_pubkey_packet.payload[0],
-        client_pubkey_packet.payload[1],
-        client_pubkey_packet.payload[2],
-        client_pubkey_packet.payload[3],
-    ]) as usize;
-    
-    if client_pubkey_packet.payload.len() < 4 + key_len {
-        anyhow::bail!("Invalid client public key length");
-    }
-    
-    let client_public_key = &client_pubkey_packet.payload[4..4 + key_len];
+    let client_public_key = &init.client_public_key;
     println!("[Phase 3] Received client public key ({} bytes)", client_public_key.len());
-    
-    // Compute shared secret
-    let shared_secret = EphemeralKeyPair::compute_shared_secret(
-        server_keypair.private_key,
-        client_public_key,
-    ).context("Failed to compute shared secret")?;
+
+    // Generate server ephemeral key pair and compute the shared secret
+    let (server_public_key, shared_secret) = prepared.method.server_exchange(client_public_key)
+        .context("Failed to compute shared secret")?;
+    println!("[Phase 3] Generated server ephemeral key pair");
     println!("[Phase 3] Computed shared secret ({} bytes)", shared_secret.len());
-    
-    // Derive session keys
-    let session_keys = derive_session_keys(&shared_secret)
-        .context("Failed to derive session keys")?;
-    println!("[Phase 3] Derived session keys (encryption, MAC, IV)");
-    
-    Ok(session_keys)
+    let encoded_secret = prepared.method.encode_shared_secret(&shared_secret);
+
+    // Bind the host key to this exchange
+    let host_key_blob = host_key.public_key_blob();
+    let exchange_hash = compute_exchange_hash(
+        hash,
+        &context.versions,
+        &kexinit,
+        &host_key_blob,
+        &EphemeralValues {
+            parameters: &prepared.parameters,
+            client_public_key,
+            server_public_key: &server_public_key,
+        },
+        &encoded_secret,
+    );
+    println!("[Phase 3] Computed exchange hash H ({} bytes)", exchange_hash.len());
+    let signature = sign_exchange_hash(host_key, host_key_algorithm, &exchange_hash)?;
+
+    // Send host key, server public key and signature to client
+    let reply = KexDhReply {
+        message_type: prepared.reply_message,
+        host_key: host_key_blob,
+        server_public_key,
+        signature,
+    };
+    transport.send(&reply)
+        .context("Failed to send key exchange reply")?;
+    println!("[Phase 3] Sent server public key ({} bytes)", reply.server_public_key.len());
+
+    // The first exchange hash becomes the session identifier for good
+    let session_id = context.session_id.clone()
+        .unwrap_or_else(|| exchange_hash.clone());
+    let session_keys = derive_keys(
+        hash,
+        &kexinit.algorithms,
+        &encoded_secret,
+        &exchange_hash,
+        &session_id,
+    ).context("Failed to derive session keys")?;
+
+    // Tell a client that asked which signature algorithms it may use
+    let ext_info = (context.session_id.is_none() && kexinit.peer_ext_info)
+        .then(|| server_ext_info(SIGNATURE_ALGORITHMS));
+    exchange_newkeys(transport, &kexinit.algorithms, &session_keys, true, ext_info)?;
+
+    Ok(KeyExchangeResult {
+        algorithms: kexinit.algorithms,
+        exchange_hash,
+        session_id,
+        host_key: reply.host_key,
+    })
 }
 
 /// Perform key exchange (client side)
-pub fn client_key_exchange(
-    stream: &mut dyn ReadWrite,
-) -> Result<SessionKeys> {
+fn client_key_exchange(
+    transport: &mut Transport,
+    context: &KexContext,
+    peer_kexinit: Option<Vec<u8>>,
+) -> Result<KeyExchangeResult> {
     println!("[Phase 3] Starting key exchange (client side)...");
-    
-    // Receive server public key
-    let server_pubkey_packet = Packet::read(stream)
-        .context("Failed to receive server public key")?;
-    
-    if server_pubkey_packet.payload.len() < 4 {
-        anyhow::bail!("Invalid server public key packet");
-    }
-    
-    let key_len = u32::from_be_bytes([
-        server_pubkey_packet.payload[0],
-        server_pubkey_packet.payload[1],
-        server_pubkey_packet.payload[2],
-        server_pubkey_packet.payload[3],
-    ]) as usize;
-    
-    if server_pubkey_packet.payload.len() < 4 + key_len {
-        anyhow::bail!("Invalid server public key length");
-    }
-    
-    let server_public_key = &server_pubkey_packet.payload[4..4 + key_len];
-    println!("[Phase 3] Received server public key ({} bytes)", server_public_key.len());
-    
+
+    let kexinit = negotiate_algorithms(transport, context, peer_kexinit)?;
+    let mut prepared = client_prepare_method(transport, &kexinit.algorithms.kex)?;
+    let hash = prepared.method.hash_algorithm();
+
     // Generate client ephemeral key pair
-    let client_keypair = EphemeralKeyPair::generate()
-        .context("Failed to generate client key pair")?;
+    let client_public_key = prepared.method.client_public_key()?;
     println!("[Phase 3] Generated client ephemeral key pair");
-    
+
     // Send client public key to server
-    let mut client_pubkey_packet = Vec::new();
-    client_pubkey_packet.extend_from_slice(&(client_keypair.public_key.len() as u32).to_be_bytes());
-    client_pubkey_packet.extend_from_slice(&client_keypair.public_key);
-    
-    let packet = Packet::new(client_pubkey_packet);
-    packet.write(stream)
+    let init = KexDhInit {
+        message_type: prepared.init_message,
+        client_public_key: client_public_key.clone(),
+    };
+    transport.send(&init)
         .context("Failed to send client public key")?;
-    println!("[Phase 3] Sent client public key ({} bytes)", client_keypair.public_key.len());
-    
+    println!("[Phase 3] Sent client public key ({} bytes)", client_public_key.len());
+
+    // Receive host key, server public key and signature
+    let payload = transport.recv_payload()
+        .context("Failed to receive key exchange reply")?;
+    let reply = KexDhReply::decode_as(&payload, prepared.reply_message)
+        .context("Failed to receive key exchange reply")?;
+    println!("[Phase 3] Received server public key ({} bytes)", reply.server_public_key.len());
+
     // Compute shared secret
-    let shared_secret = EphemeralKeyPair::compute_shared_secret(
-        client_keypair.private_key,
-        server_public_key,
-    ).context("Failed to compute shared secret")?;
+    let shared_secret = prepared.method.client_shared_secret(&reply.server_public_key)
+        .context("Failed to compute shared secret")?;
     println!("[Phase 3] Computed shared secret ({} bytes)", shared_secret.len());
-    
-    // Derive session keys
-    let session_keys = derive_session_keys(&shared_secret)
-        .context("Failed to derive session keys")?;
-    println!("[Phase 3] Derived session keys (encryption, MAC, IV)");
-    
-    Ok(session_keys)
+    let encoded_secret = prepared.method.encode_shared_secret(&shared_secret);
+
+    let exchange_hash = compute_exchange_hash(
+        hash,
+        &context.versions,
+        &kexinit,
+        &reply.host_key,
+        &EphemeralValues {
+            parameters: &prepared.parameters,
+            client_public_key: &client_public_key,
+            server_public_key: &reply.server_public_key,
+        },
+        &encoded_secret,
+    );
+    println!("[Phase 3] Computed exchange hash H ({} bytes)", exchange_hash.len());
+
+    // Phase 4 happens here: the keys are only accepted once the server has
+    // proven it holds the host key by signing H
+    verify_host_key(
+        &context.known_hosts_file,
+        &context.hostname,
+        &kexinit.algorithms.host_key,
+        &reply.host_key,
+        &exchange_hash,
+        &reply.signature,
+    )?;
+
+    // The first exchange hash becomes the session identifier for good
+    let session_id = context.session_id.clone()
+        .unwrap_or_else(|| exchange_hash.clone());
+    let session_keys = derive_keys(
+        hash,
+        &kexinit.algorithms,
+        &encoded_secret,
+        &exchange_hash,
+        &session_id,
+    ).context("Failed to derive session keys")?;
+
+    // ext-info-s only says the server accepts EXT_INFO, not that it sends
+    // one (RFC 8308 §2.1). If it does, it comes right after its first
+    // NEWKEYS, so the transport records it while the client waits for
+    // SSH_MSG_SERVICE_ACCEPT, before any key is chosen.
+    exchange_newkeys(transport, &kexinit.algorithms, &session_keys, false, None)?;
+
+    Ok(KeyExchangeResult {
+        algorithms: kexinit.algorithms,
+        exchange_hash,
+        session_id,
+        host_key: reply.host_key,
+    })
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exchange_hash_matches_known_answers() {
+        // Computed independently with Python's hashlib from the field order
+        // of RFC 4253 §8 / RFC 5656 §4 and, with group parameters, RFC 4419 §3
+        let versions = VersionStrings {
+            client: "SSH-2.0-client".to_string(),
+            server: "SSH-2.0-server".to_string(),
+        };
+        let kexinit = KexInitExchange {
+            client_kexinit: b"\x14client kexinit".to_vec(),
+            server_kexinit: b"\x14server kexinit".to_vec(),
+            algorithms: NegotiatedAlgorithms {
+                kex: "curve25519-sha256".to_string(),
+                host_key: "ssh-ed25519".to_string(),
+                cipher_client_to_server: "aes256-gcm@openssh.com".to_string(),
+                cipher_server_to_client: "aes256-gcm@openssh.com".to_string(),
+                mac_client_to_server: None,
+                mac_server_to_client: None,
+                compression_client_to_server: "none".to_string(),
+                compression_server_to_client: "none".to_string(),
+            },
+            strict_kex: false,
+            peer_ext_info: false,
+        };
+        let mut secret = Writer::new();
+        secret.write_mpint(&(0x80..0xa0).collect::<Vec<u8>>());
+        let secret = secret.into_bytes();
+        let hash = |parameters: &[u8]| hex::encode(compute_exchange_hash(
+            &digest::SHA256,
+            &versions,
+            &kexinit,
+            b"host key blob",
+            &EphemeralValues {
+                parameters,
+                client_public_key: &[1; 32],
+                server_public_key: &[2; 32],
+            },
+            &secret,
+        ));
+
+        assert_eq!(hash(&[]), "7de9d7cd55f532d0fd0e5e0712bc9086bb911cba4382c68c7974d7b3cb3c63b9");
+
+        // min || n || max || p || g
+        let mut parameters = Writer::new();
+        parameters.write_u32(2048);
+        parameters.write_u32(3072);
+        parameters.write_u32(8192);
+        parameters.write_mpint(&[0xff, 0xff, 0xff, 0xc5]);
+        parameters.write_mpint(&[2]);
+        assert_eq!(
+            hash(&parameters.into_bytes()),
+            "de4f0c1fca8ec583e6e14190897ca70901f9b5cd7c0f3367a2477338dc22a266"
+        );
+    }
+}
@@ -0,0 +1,319 @@
+use anyhow::Result;
+use crate::utils::wire::{Decode, Encode, Reader, Writer};
+
+// Transport layer generic messages (RFC 4250 §4.1.2)
+pub const SSH_MSG_DISCONNECT: u8 = 1;
+pub const SSH_MSG_IGNORE: u8 = 2;
+pub const SSH_MSG_UNIMPLEMENTED: u8 = 3;
+pub const SSH_MSG_DEBUG: u8 = 4;
+pub const SSH_MSG_SERVICE_REQUEST: u8 = 5;
+pub const SSH_MSG_SERVICE_ACCEPT: u8 = 6;
+
+// Extension negotiation (RFC 8308 §2.3)
+pub const SSH_MSG_EXT_INFO: u8 = 7;
+
+// Algorithm negotiation messages (RFC 4250 §4.1.2)
+pub const SSH_MSG_KEXINIT: u8 = 20;
+pub const SSH_MSG_NEWKEYS: u8 = 21;
+
+// Key exchange method specific messages (RFC 5656 §7.1). The finite field
+// Diffie-Hellman methods use the same numbers (RFC 4253 §8).
+pub const SSH_MSG_KEX_ECDH_INIT: u8 = 30;
+pub const SSH_MSG_KEX_ECDH_REPLY: u8 = 31;
+pub const SSH_MSG_KEXDH_INIT: u8 = SSH_MSG_KEX_ECDH_INIT;
+pub const SSH_MSG_KEXDH_REPLY: u8 = SSH_MSG_KEX_ECDH_REPLY;
+
+// Diffie-Hellman group exchange (RFC 4419 §5). Key exchange methods share
+// numbers 30-49, so 31 is SSH_MSG_KEX_ECDH_REPLY or SSH_MSG_KEX_DH_GEX_GROUP
+// depending on the negotiated method.
+pub const SSH_MSG_KEX_DH_GEX_GROUP: u8 = 31;
+pub const SSH_MSG_KEX_DH_GEX_INIT: u8 = 32;
+pub const SSH_MSG_KEX_DH_GEX_REPLY: u8 = 33;
+pub const SSH_MSG_KEX_DH_GEX_REQUEST: u8 = 34;
+
+// User authentication messages (RFC 4252 §6)
+pub const SSH_MSG_USERAUTH_REQUEST: u8 = 50;
+pub const SSH_MSG_USERAUTH_FAILURE: u8 = 51;
+pub const SSH_MSG_USERAUTH_SUCCESS: u8 = 52;
+
+// Method-specific numbers 60-79: the publickey method's (RFC 4252 §7)
+pub const SSH_MSG_USERAUTH_PK_OK: u8 = 60;
+
+// Connection protocol messages (RFC 4254 §9)
+pub const SSH_MSG_CHANNEL_DATA: u8 = 94;
+
+// Transport-level ping (OpenSSH PROTOCOL §1.9, local extension range)
+pub const SSH_MSG_PING: u8 = 192;
+pub const SSH_MSG_PONG: u8 = 193;
+
+// Disconnection reason codes (RFC 4250 §4.2.2)
+pub const SSH_DISCONNECT_HOST_NOT_ALLOWED_TO_CONNECT: u32 = 1;
+pub const SSH_DISCONNECT_PROTOCOL_ERROR: u32 = 2;
+pub const SSH_DISCONNECT_KEY_EXCHANGE_FAILED: u32 = 3;
+pub const SSH_DISCONNECT_RESERVED: u32 = 4;
+pub const SSH_DISCONNECT_MAC_ERROR: u32 = 5;
+pub const SSH_DISCONNECT_COMPRESSION_ERROR: u32 = 6;
+pub const SSH_DISCONNECT_SERVICE_NOT_AVAILABLE: u32 = 7;
+pub const SSH_DISCONNECT_PROTOCOL_VERSION_NOT_SUPPORTED: u32 = 8;
+pub const SSH_DISCONNECT_HOST_KEY_NOT_VERIFIABLE: u32 = 9;
+pub const SSH_DISCONNECT_CONNECTION_LOST: u32 = 10;
+pub const SSH_DISCONNECT_BY_APPLICATION: u32 = 11;
+pub const SSH_DISCONNECT_TOO_MANY_CONNECTIONS: u32 = 12;
+pub const SSH_DISCONNECT_AUTH_CANCELLED_BY_USER: u32 = 13;
+pub const SSH_DISCONNECT_NO_MORE_AUTH_METHODS_AVAILABLE: u32 = 14;
+pub const SSH_DISCONNECT_ILLEGAL_USER_NAME: u32 = 15;
+
+/// Whether this implementation understands a message number. Anything else
+/// is answered with SSH_MSG_UNIMPLEMENTED.
+pub fn is_implemented(message_type: u8) -> bool {
+    matches!(
+        message_type,
+        SSH_MSG_DISCONNECT
+            | SSH_MSG_IGNORE
+            | SSH_MSG_UNIMPLEMENTED
+            | SSH_MSG_DEBUG
+            | SSH_MSG_SERVICE_REQUEST
+            | SSH_MSG_SERVICE_ACCEPT
+            | SSH_MSG_EXT_INFO
+            | SSH_MSG_KEXINIT
+            | SSH_MSG_NEWKEYS
+            | SSH_MSG_KEX_ECDH_INIT
+            | SSH_MSG_KEX_ECDH_REPLY
+            | SSH_MSG_KEX_DH_GEX_INIT
+            | SSH_MSG_KEX_DH_GEX_REPLY
+            | SSH_MSG_KEX_DH_GEX_REQUEST
+            | SSH_MSG_USERAUTH_REQUEST
+            | SSH_MSG_USERAUTH_FAILURE
+            | SSH_MSG_USERAUTH_SUCCESS
+            | SSH_MSG_USERAUTH_PK_OK
+            | SSH_MSG_CHANNEL_DATA
+            | SSH_MSG_PING
+            | SSH_MSG_PONG
+    )
+}
+
+/// Whether a message number belongs to the transport layer (1-49: generic,
+/// algorithm negotiation and key exchange messages, RFC 4251 §7). Only these
+/// may be sent while a key exchange is in progress, except the service
+/// messages (RFC 4253 §7.1), which wait like higher-layer ones.
+pub fn is_transport_message(message_type: u8) -> bool {
+    (1..=49).contains(&message_type)
+        && !matches!(message_type, SSH_MSG_SERVICE_REQUEST | SSH_MSG_SERVICE_ACCEPT)
+}
+
+/// Whether a message number belongs to algorithm negotiation or a key
+/// exchange method (20-49)
+pub fn is_key_exchange_message(message_type: u8) -> bool {
+    (20..=49).contains(&message_type)
+}
+
+/// Human-readable name of a message number, for logs and errors
+pub fn message_name(message_type: u8) -> String {
+    let name = match message_type {
+        SSH_MSG_DISCONNECT => "SSH_MSG_DISCONNECT",
+        SSH_MSG_IGNORE => "SSH_MSG_IGNORE",
+        SSH_MSG_UNIMPLEMENTED => "SSH_MSG_UNIMPLEMENTED",
+        SSH_MSG_DEBUG => "SSH_MSG_DEBUG",
+        SSH_MSG_SERVICE_REQUEST => "SSH_MSG_SERVICE_REQUEST",
+        SSH_MSG_SERVICE_ACCEPT => "SSH_MSG_SERVICE_ACCEPT",
+        SSH_MSG_EXT_INFO => "SSH_MSG_EXT_INFO",
+        SSH_MSG_KEXINIT => "SSH_MSG_KEXINIT",
+        SSH_MSG_NEWKEYS => "SSH_MSG_NEWKEYS",
+        SSH_MSG_KEX_ECDH_INIT => "SSH_MSG_KEX_ECDH_INIT",
+        SSH_MSG_KEX_ECDH_REPLY => "SSH_MSG_KEX_ECDH_REPLY",
+        SSH_MSG_KEX_DH_GEX_INIT => "SSH_MSG_KEX_DH_GEX_INIT",
+        SSH_MSG_KEX_DH_GEX_REPLY => "SSH_MSG_KEX_DH_GEX_REPLY",
+        SSH_MSG_KEX_DH_GEX_REQUEST => "SSH_MSG_KEX_DH_GEX_REQUEST",
+        SSH_MSG_USERAUTH_REQUEST => "SSH_MSG_USERAUTH_REQUEST",
+        SSH_MSG_USERAUTH_FAILURE => "SSH_MSG_USERAUTH_FAILURE",
+        SSH_MSG_USERAUTH_SUCCESS => "SSH_MSG_USERAUTH_SUCCESS",
+        SSH_MSG_USERAUTH_PK_OK => "SSH_MSG_USERAUTH_PK_OK",
+        SSH_MSG_CHANNEL_DATA => "SSH_MSG_CHANNEL_DATA",
+        SSH_MSG_PING => "SSH_MSG_PING",
+        SSH_MSG_PONG => "SSH_MSG_PONG",
+        other => return format!("message {}", other),
+    };
+    name.to_string()
+}
+
+/// Human-readable name of a disconnection reason code
+pub fn disconnect_reason_name(reason_code: u32) -> &'static str {
+    match reason_code {
+        SSH_DISCONNECT_HOST_NOT_ALLOWED_TO_CONNECT => "host not allowed to connect",
+        SSH_DISCONNECT_PROTOCOL_ERROR => "protocol error",
+        SSH_DISCONNECT_KEY_EXCHANGE_FAILED => "key exchange failed",
+        SSH_DISCONNECT_RESERVED => "reserved",
+        SSH_DISCONNECT_MAC_ERROR => "MAC error",
+        SSH_DISCONNECT_COMPRESSION_ERROR => "compression error",
+        SSH_DISCONNECT_SERVICE_NOT_AVAILABLE => "service not available",
+        SSH_DISCONNECT_PROTOCOL_VERSION_NOT_SUPPORTED => "protocol version not supported",
+        SSH_DISCONNECT_HOST_KEY_NOT_VERIFIABLE => "host key not verifiable",
+        SSH_DISCONNECT_CONNECTION_LOST => "connection lost",
+        SSH_DISCONNECT_BY_APPLICATION => "disconnected by application",
+        SSH_DISCONNECT_TOO_MANY_CONNECTIONS => "too many connections",
+        SSH_DISCONNECT_AUTH_CANCELLED_BY_USER => "authentication cancelled by user",
+        SSH_DISCONNECT_NO_MORE_AUTH_METHODS_AVAILABLE => "no more authentication methods available",
+        SSH_DISCONNECT_ILLEGAL_USER_NAME => "illegal user name",
+        _ => "unknown reason",
+    }
+}
+
+/// Peer-supplied text made safe to print: control characters are escaped
+/// so they cannot drive the terminal (RFC 4251 §9.2)
+pub fn printable(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if c.is_control() {
+            escaped.extend(c.escape_debug());
+        } else {
+            escaped.push(c);
+        }
+    }
+    escaped
+}
+
+/// Read the leading message number and check it is the one expected
+pub fn expect_message(reader: &mut Reader<'_>, expected: u8) -> Result<()> {
+    let actual = reader.read_byte()?;
+    if actual != expected {
+        anyhow::bail!(
+            "Unexpected {} (expected {})",
+            message_name(actual),
+            message_name(expected)
+        );
+    }
+    Ok(())
+}
+
+/// SSH_MSG_DISCONNECT (RFC 4253 §11.1)
+#[derive(Debug, Clone)]
+pub struct Disconnect {
+    pub reason_code: u32,
+    pub description: String,
+    pub language_tag: String,
+}
+
+impl Disconnect {
+    /// Create a disconnect message with an English description
+    pub fn new(reason_code: u32, description: &str) -> Self {
+        Self {
+            reason_code,
+            description: description.to_string(),
+            language_tag: String::new(),
+        }
+    }
+}
+
+impl std::fmt::Display for Disconnect {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} ({}): {}",
+            disconnect_reason_name(self.reason_code),
+            self.reason_code,
+            printable(&self.description)
+        )
+    }
+}
+
+impl Encode for Disconnect {
+    fn encode(&self, writer: &mut Writer) {
+        writer.write_byte(SSH_MSG_DISCONNECT);
+        writer.write_u32(self.reason_code);
+        writer.write_string(self.description.as_bytes());
+        writer.write_string(self.language_tag.as_bytes());
+    }
+}
+
+impl Decode for Disconnect {
+    fn decode(reader: &mut Reader<'_>) -> Result<Self> {
+        expect_message(reader, SSH_MSG_DISCONNECT)?;
+        Ok(Self {
+            reason_code: reader.read_u32()?,
+            description: String::from_utf8_lossy(reader.read_string()?).into_owned(),
+            language_tag: String::from_utf8_lossy(reader.read_string()?).into_owned(),
+        })
+    }
+}
+
+/// SSH_MSG_IGNORE (RFC 4253 §11.2)
+pub struct Ignore {
+    pub data: Vec<u8>,
+}
+
+impl Encode for Ignore {
+    fn encode(&self, writer: &mut Writer) {
+        writer.write_byte(SSH_MSG_IGNORE);
+        writer.write_string(&self.data);
+    }
+}
+
+impl Decode for Ignore {
+    fn decode(reader: &mut Reader<'_>) -> Result<Self> {
+        expect_message(reader, SSH_MSG_IGNORE)?;
+        Ok(Self {
+            data: reader.read_string()?.to_vec(),
+        })
+    }
+}
+
+/// SSH_MSG_DEBUG (RFC 4253 §11.3)
+pub struct Debug {
+    pub always_display: bool,
+    pub message: String,
+    pub language_tag: String,
+}
+
+impl Encode for Debug {
+    fn encode(&self, writer: &mut Writer) {
+        writer.write_byte(SSH_MSG_DEBUG);
+        writer.write_bool(self.always_display);
+        writer.write_string(self.message.as_bytes());
+        writer.write_string(self.language_tag.as_bytes());
+    }
+}
+
+impl Decode for Debug {
+    fn decode(reader: &mut Reader<'_>) -> Result<Self> {
+        expect_message(reader, SSH_MSG_DEBUG)?;
+        Ok(Self {
+            always_display: reader.read_bool()?,
+            message: String::from_utf8_lossy(reader.read_string()?).into_owned(),
+            language_tag: String::from_utf8_lossy(reader.read_string()?).into_owned(),
+        })
+    }
+}
+
+/// SSH_MSG_UNIMPLEMENTED (RFC 4253 §11.4)
+pub struct Unimplemented {
+    pub sequence_number: u32,
+}
+
+impl Encode for Unimplemented {
+    fn encode(&self, writer: &mut Writer) {
+        writer.write_byte(SSH_MSG_UNIMPLEMENTED);
+        writer.write_u32(self.sequence_number);
+    }
+}
+
+impl Decode for Unimplemented {
+    fn decode(reader: &mut Reader<'_>) -> Result<Self> {
+        expect_message(reader, SSH_MSG_UNIMPLEMENTED)?;
+        Ok(Self {
+            sequence_number: reader.read_u32()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn printable_escapes_control_characters() {
+        assert_eq!(printable("plain text, ünïcode"), "plain text, ünïcode");
+        assert_eq!(printable("\x1b[2Jgone\r\n\x07"), "\\u{1b}[2Jgone\\r\\n\\u{7}");
+        let disconnect = Disconnect::new(SSH_DISCONNECT_BY_APPLICATION, "bye\x1b]0;title\x07");
+        assert!(!disconnect.to_string().contains('\x1b'));
+    }
+}
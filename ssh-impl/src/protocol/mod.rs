@@ -2,5 +2,13 @@ pub mod version;
 pub mod key_exchange;
 pub mod server_auth;
 pub mod client_auth;
+pub mod authorized_keys;
+pub mod users;
+pub mod service;
 pub mod session;
 
+pub mod messages;
+pub mod transport;
+pub mod negotiation;
+pub mod extensions;
+pub mod compression;
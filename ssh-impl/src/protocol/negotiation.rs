@@ -0,0 +1,563 @@
+use anyhow::{Result, Context};
+use ring::rand::{SecureRandom, SystemRandom};
+use crate::protocol::messages::{expect_message, SSH_MSG_KEXINIT};
+use crate::protocol::transport::Transport;
+use crate::utils::wire::{Decode, Encode, Reader, Writer};
+
+/// Key exchange methods this implementation supports, in default preference order
+pub const KEX_ALGORITHMS: &[&str] = &[
+    "mlkem768x25519-sha256",
+    "curve25519-sha256",
+    "curve25519-sha256@libssh.org",
+    "ecdh-sha2-nistp256",
+    "ecdh-sha2-nistp384",
+    "diffie-hellman-group-exchange-sha256",
+    "diffie-hellman-group16-sha512",
+    "diffie-hellman-group18-sha512",
+    "diffie-hellman-group14-sha256",
+];
+
+/// Host key algorithms this implementation supports
+pub const HOST_KEY_ALGORITHMS: &[&str] = &[
+    "ssh-ed25519",
+    "ecdsa-sha2-nistp256",
+    "ecdsa-sha2-nistp384",
+    "rsa-sha2-512",
+    "rsa-sha2-256",
+];
+
+/// Ciphers this implementation supports
+pub const CIPHERS: &[&str] = &[
+    "aes256-gcm@openssh.com",
+    "chacha20-poly1305@openssh.com",
+    "aes256-ctr",
+    "aes128-ctr",
+];
+
+/// MAC algorithms this implementation supports, used with the non-AEAD ciphers
+pub const MAC_ALGORITHMS: &[&str] = &[
+    "hmac-sha2-256-etm@openssh.com",
+    "hmac-sha2-512-etm@openssh.com",
+    "hmac-sha2-256",
+    "hmac-sha2-512",
+];
+
+/// Compression algorithms this implementation supports. Compression is
+/// only used if the client puts a zlib method first.
+pub const COMPRESSION_ALGORITHMS: &[&str] = &["none", "zlib@openssh.com", "zlib"];
+
+/// Strict key exchange markers (OpenSSH PROTOCOL §1.10), appended to the
+/// key exchange list of the initial KEXINIT only. They are never negotiated.
+pub const KEX_STRICT_CLIENT: &str = "kex-strict-c-v00@openssh.com";
+pub const KEX_STRICT_SERVER: &str = "kex-strict-s-v00@openssh.com";
+
+/// Extension negotiation markers (RFC 8308 §2.1): the sender accepts
+/// SSH_MSG_EXT_INFO. Also only in the initial KEXINIT.
+pub const EXT_INFO_CLIENT: &str = "ext-info-c";
+pub const EXT_INFO_SERVER: &str = "ext-info-s";
+
+/// Names in the key exchange list that signal a feature rather than name a
+/// key exchange method
+fn is_kex_marker(name: &str) -> bool {
+    matches!(name, KEX_STRICT_CLIENT | KEX_STRICT_SERVER | EXT_INFO_CLIENT | EXT_INFO_SERVER)
+}
+
+/// Ciphers that authenticate packets themselves, so no MAC is negotiated
+const AEAD_CIPHERS: &[&str] = &["aes256-gcm@openssh.com", "chacha20-poly1305@openssh.com"];
+
+/// Ordered algorithm preferences advertised in SSH_MSG_KEXINIT.
+/// The same lists are used for both directions.
+#[derive(Debug, Clone)]
+pub struct AlgorithmPreferences {
+    pub kex: Vec<String>,
+    pub host_key: Vec<String>,
+    pub ciphers: Vec<String>,
+    pub macs: Vec<String>,
+    pub compression: Vec<String>,
+}
+
+impl Default for AlgorithmPreferences {
+    fn default() -> Self {
+        Self {
+            kex: to_strings(KEX_ALGORITHMS),
+            host_key: to_strings(HOST_KEY_ALGORITHMS),
+            ciphers: to_strings(CIPHERS),
+            macs: to_strings(MAC_ALGORITHMS),
+            compression: to_strings(COMPRESSION_ALGORITHMS),
+        }
+    }
+}
+
+impl AlgorithmPreferences {
+    /// Check that every configured name is implemented and that each
+    /// mandatory list is non-empty
+    pub fn validate(&self) -> Result<()> {
+        check_list("key exchange", &self.kex, KEX_ALGORITHMS, true)?;
+        check_list("host key", &self.host_key, HOST_KEY_ALGORITHMS, true)?;
+        check_list("cipher", &self.ciphers, CIPHERS, true)?;
+        check_list("MAC", &self.macs, MAC_ALGORITHMS, false)?;
+        check_list("compression", &self.compression, COMPRESSION_ALGORITHMS, true)?;
+        Ok(())
+    }
+}
+
+fn to_strings(names: &[&str]) -> Vec<String> {
+    names.iter().map(|name| name.to_string()).collect()
+}
+
+fn check_list(kind: &str, configured: &[String], supported: &[&str], required: bool) -> Result<()> {
+    if required && configured.is_empty() {
+        anyhow::bail!("At least one {} algorithm must be configured", kind);
+    }
+    if let Some(unknown) = configured.iter().find(|name| !supported.contains(&name.as_str())) {
+        anyhow::bail!(
+            "Unsupported {} algorithm: {} (supported: {})",
+            kind,
+            unknown,
+            supported.join(",")
+        );
+    }
+    Ok(())
+}
+
+/// SSH_MSG_KEXINIT (RFC 4253 §7.1)
+#[derive(Debug, Clone)]
+pub struct KexInit {
+    pub cookie: [u8; 16],
+    pub kex_algorithms: Vec<String>,
+    pub server_host_key_algorithms: Vec<String>,
+    pub encryption_algorithms_client_to_server: Vec<String>,
+    pub encryption_algorithms_server_to_client: Vec<String>,
+    pub mac_algorithms_client_to_server: Vec<String>,
+    pub mac_algorithms_server_to_client: Vec<String>,
+    pub compression_algorithms_client_to_server: Vec<String>,
+    pub compression_algorithms_server_to_client: Vec<String>,
+    pub languages_client_to_server: Vec<String>,
+    pub languages_server_to_client: Vec<String>,
+    pub first_kex_packet_follows: bool,
+    pub reserved: u32,
+}
+
+impl KexInit {
+    /// Build our KEXINIT from the configured preferences with a fresh cookie
+    pub fn from_preferences(preferences: &AlgorithmPreferences) -> Result<Self> {
+        let mut cookie = [0u8; 16];
+        SystemRandom::new().fill(&mut cookie)
+            .map_err(|_| anyhow::anyhow!("Failed to generate KEXINIT cookie"))?;
+
+        Ok(Self {
+            cookie,
+            kex_algorithms: preferences.kex.clone(),
+            server_host_key_algorithms: preferences.host_key.clone(),
+            encryption_algorithms_client_to_server: preferences.ciphers.clone(),
+            encryption_algorithms_server_to_client: preferences.ciphers.clone(),
+            mac_algorithms_client_to_server: preferences.macs.clone(),
+            mac_algorithms_server_to_client: preferences.macs.clone(),
+            compression_algorithms_client_to_server: preferences.compression.clone(),
+            compression_algorithms_server_to_client: preferences.compression.clone(),
+            languages_client_to_server: Vec::new(),
+            languages_server_to_client: Vec::new(),
+            first_kex_packet_follows: false,
+            reserved: 0,
+        })
+    }
+}
+
+impl Encode for KexInit {
+    fn encode(&self, writer: &mut Writer) {
+        writer.write_byte(SSH_MSG_KEXINIT);
+        writer.write_raw(&self.cookie);
+        writer.write_name_list(&self.kex_algorithms);
+        writer.write_name_list(&self.server_host_key_algorithms);
+        writer.write_name_list(&self.encryption_algorithms_client_to_server);
+        writer.write_name_list(&self.encryption_algorithms_server_to_client);
+        writer.write_name_list(&self.mac_algorithms_client_to_server);
+        writer.write_name_list(&self.mac_algorithms_server_to_client);
+        writer.write_name_list(&self.compression_algorithms_client_to_server);
+        writer.write_name_list(&self.compression_algorithms_server_to_client);
+        writer.write_name_list(&self.languages_client_to_server);
+        writer.write_name_list(&self.languages_server_to_client);
+        writer.write_bool(self.first_kex_packet_follows);
+        writer.write_u32(self.reserved);
+    }
+}
+
+impl Decode for KexInit {
+    fn decode(reader: &mut Reader<'_>) -> Result<Self> {
+        expect_message(reader, SSH_MSG_KEXINIT)?;
+        let mut cookie = [0u8; 16];
+        cookie.copy_from_slice(reader.read_bytes(16)?);
+
+        Ok(Self {
+            cookie,
+            kex_algorithms: reader.read_name_list()?,
+            server_host_key_algorithms: reader.read_name_list()?,
+            encryption_algorithms_client_to_server: reader.read_name_list()?,
+            encryption_algorithms_server_to_client: reader.read_name_list()?,
+            mac_algorithms_client_to_server: reader.read_name_list()?,
+            mac_algorithms_server_to_client: reader.read_name_list()?,
+            compression_algorithms_client_to_server: reader.read_name_list()?,
+            compression_algorithms_server_to_client: reader.read_name_list()?,
+            languages_client_to_server: reader.read_name_list()?,
+            languages_server_to_client: reader.read_name_list()?,
+            first_kex_packet_follows: reader.read_bool()?,
+            reserved: reader.read_u32()?,
+        })
+    }
+}
+
+/// Algorithms agreed for one key exchange
+#[derive(Debug, Clone)]
+pub struct NegotiatedAlgorithms {
+    pub kex: String,
+    pub host_key: String,
+    pub cipher_client_to_server: String,
+    pub cipher_server_to_client: String,
+    /// `None` when the cipher authenticates packets itself
+    pub mac_client_to_server: Option<String>,
+    pub mac_server_to_client: Option<String>,
+    pub compression_client_to_server: String,
+    pub compression_server_to_client: String,
+}
+
+/// Pick the first client algorithm the server also supports (RFC 4253 §7.1)
+fn choose(kind: &str, client: &[String], server: &[String]) -> Result<String> {
+    client.iter()
+        .find(|name| server.contains(name))
+        .cloned()
+        .with_context(|| format!(
+            "No common {} algorithm (client: {}, server: {})",
+            kind,
+            client.join(","),
+            server.join(",")
+        ))
+}
+
+fn without_markers(kex_algorithms: &[String]) -> Vec<String> {
+    kex_algorithms.iter()
+        .filter(|name| !is_kex_marker(name))
+        .cloned()
+        .collect()
+}
+
+/// MAC negotiation is skipped for AEAD ciphers
+fn choose_mac(cipher: &str, client: &[String], server: &[String]) -> Result<Option<String>> {
+    if AEAD_CIPHERS.contains(&cipher) {
+        Ok(None)
+    } else {
+        choose("MAC", client, server).map(Some)
+    }
+}
+
+/// Negotiate every algorithm category from both KEXINIT messages
+pub fn negotiate(client: &KexInit, server: &KexInit) -> Result<NegotiatedAlgorithms> {
+    let cipher_client_to_server = choose(
+        "client-to-server cipher",
+        &client.encryption_algorithms_client_to_server,
+        &server.encryption_algorithms_client_to_server,
+    )?;
+    let cipher_server_to_client = choose(
+        "server-to-client cipher",
+        &client.encryption_algorithms_server_to_client,
+        &server.encryption_algorithms_server_to_client,
+    )?;
+
+    Ok(NegotiatedAlgorithms {
+        kex: choose("key exchange", &without_markers(&client.kex_algorithms), &server.kex_algorithms)?,
+        host_key: choose(
+            "host key",
+            &client.server_host_key_algorithms,
+            &server.server_host_key_algorithms,
+        )?,
+        mac_client_to_server: choose_mac(
+            &cipher_client_to_server,
+            &client.mac_algorithms_client_to_server,
+            &server.mac_algorithms_client_to_server,
+        )?,
+        mac_server_to_client: choose_mac(
+            &cipher_server_to_client,
+            &client.mac_algorithms_server_to_client,
+            &server.mac_algorithms_server_to_client,
+        )?,
+        cipher_client_to_server,
+        cipher_server_to_client,
+        compression_client_to_server: choose(
+            "client-to-server compression",
+            &client.compression_algorithms_client_to_server,
+            &server.compression_algorithms_client_to_server,
+        )?,
+        compression_server_to_client: choose(
+            "server-to-client compression",
+            &client.compression_algorithms_server_to_client,
+            &server.compression_algorithms_server_to_client,
+        )?,
+    })
+}
+
+/// Whether a guessed key exchange packet can be used: both sides must
+/// prefer the same key exchange and host key algorithm (RFC 4253 §7, as
+/// OpenSSH's `proposals_match`). Otherwise the guess is wrong even if the
+/// guesser's first choice is what was negotiated, and the guesser expects
+/// its packet to be ignored.
+fn guess_was_right(client: &KexInit, server: &KexInit) -> bool {
+    without_markers(&client.kex_algorithms).first() == without_markers(&server.kex_algorithms).first()
+        && client.server_host_key_algorithms.first() == server.server_host_key_algorithms.first()
+}
+
+/// Result of the KEXINIT exchange. The raw payloads are kept because the
+/// exchange hash covers them.
+pub struct KexInitExchange {
+    pub client_kexinit: Vec<u8>,
+    pub server_kexinit: Vec<u8>,
+    pub algorithms: NegotiatedAlgorithms,
+    /// Both sides offered strict key exchange
+    pub strict_kex: bool,
+    /// The peer accepts SSH_MSG_EXT_INFO
+    pub peer_ext_info: bool,
+}
+
+/// Send our SSH_MSG_KEXINIT, receive the peer's and negotiate algorithms.
+/// `peer_kexinit` is the peer's KEXINIT payload when the peer started this
+/// (re-)exchange and its KEXINIT has already been read.
+pub fn exchange_kexinit(
+    transport: &mut Transport,
+    ours: KexInit,
+    is_server: bool,
+    peer_kexinit: Option<Vec<u8>>,
+) -> Result<KexInitExchange> {
+    let our_payload = ours.to_bytes();
+    transport.send_payload(&our_payload)
+        .context("Failed to send KEXINIT")?;
+    println!("[Phase 3] Sent KEXINIT");
+
+    let their_payload = match peer_kexinit {
+        Some(payload) => payload,
+        None => transport.recv_payload()
+            .context("Failed to receive KEXINIT")?,
+    };
+    let theirs = KexInit::from_bytes(&their_payload)
+        .context("Invalid KEXINIT")?;
+    println!("[Phase 3] Received KEXINIT");
+
+    let (client, server) = if is_server { (&theirs, &ours) } else { (&ours, &theirs) };
+    let algorithms = negotiate(client, server)?;
+
+    println!("[Phase 3] Key exchange: {}", algorithms.kex);
+    println!("[Phase 3] Host key: {}", algorithms.host_key);
+    println!(
+        "[Phase 3] Ciphers: {} (client->server), {} (server->client)",
+        algorithms.cipher_client_to_server,
+        algorithms.cipher_server_to_client
+    );
+    println!(
+        "[Phase 3] MACs: {} (client->server), {} (server->client)",
+        algorithms.mac_client_to_server.as_deref().unwrap_or("<implicit>"),
+        algorithms.mac_server_to_client.as_deref().unwrap_or("<implicit>")
+    );
+    println!(
+        "[Phase 3] Compression: {} (client->server), {} (server->client)",
+        algorithms.compression_client_to_server,
+        algorithms.compression_server_to_client
+    );
+
+    if theirs.first_kex_packet_follows && !guess_was_right(client, server) {
+        // The peer optimistically sent a key exchange packet for algorithms
+        // we did not pick; it must be ignored (RFC 4253 §7)
+        transport.recv_payload()
+            .context("Failed to receive guessed key exchange packet")?;
+        println!("[Phase 3] Discarded peer's wrongly guessed key exchange packet");
+    }
+
+    let (our_marker, their_marker, their_ext_info) = if is_server {
+        (KEX_STRICT_SERVER, KEX_STRICT_CLIENT, EXT_INFO_CLIENT)
+    } else {
+        (KEX_STRICT_CLIENT, KEX_STRICT_SERVER, EXT_INFO_SERVER)
+    };
+    let strict_kex = ours.kex_algorithms.iter().any(|name| name == our_marker)
+        && theirs.kex_algorithms.iter().any(|name| name == their_marker);
+    let peer_ext_info = theirs.kex_algorithms.iter().any(|name| name == their_ext_info);
+
+    let (client_kexinit, server_kexinit) = if is_server {
+        (their_payload, our_payload)
+    } else {
+        (our_payload, their_payload)
+    };
+
+    Ok(KexInitExchange {
+        client_kexinit,
+        server_kexinit,
+        algorithms,
+        strict_kex,
+        peer_ext_info,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixStream;
+    use crate::utils::packet::Packet;
+
+    fn kexinit(kex: &[&str], host_key: &[&str], ciphers: &[&str], macs: &[&str]) -> KexInit {
+        KexInit::from_preferences(&AlgorithmPreferences {
+            kex: to_strings(kex),
+            host_key: to_strings(host_key),
+            ciphers: to_strings(ciphers),
+            macs: to_strings(macs),
+            compression: to_strings(&["none"]),
+        }).unwrap()
+    }
+
+    #[test]
+    fn picks_first_client_algorithm_the_server_supports() {
+        let client = kexinit(
+            &["curve25519-sha256", "ecdh-sha2-nistp256"],
+            &["rsa-sha2-512", "ssh-ed25519"],
+            &["aes128-ctr", "aes256-gcm@openssh.com"],
+            &["hmac-sha2-512", "hmac-sha2-256"],
+        );
+        // The server's order does not matter
+        let server = kexinit(
+            &["diffie-hellman-group14-sha256", "ecdh-sha2-nistp256", "curve25519-sha256"],
+            &["ssh-ed25519", "rsa-sha2-512"],
+            &["aes256-gcm@openssh.com", "aes128-ctr"],
+            &["hmac-sha2-256", "hmac-sha2-512"],
+        );
+        let algorithms = negotiate(&client, &server).unwrap();
+        assert_eq!(algorithms.kex, "curve25519-sha256");
+        assert_eq!(algorithms.host_key, "rsa-sha2-512");
+        assert_eq!(algorithms.cipher_client_to_server, "aes128-ctr");
+        assert_eq!(algorithms.mac_client_to_server.as_deref(), Some("hmac-sha2-512"));
+        assert_eq!(algorithms.compression_server_to_client, "none");
+
+        // Each direction is negotiated on its own; AEAD ciphers need no MAC
+        let mut client = client;
+        client.encryption_algorithms_server_to_client = to_strings(&["aes256-gcm@openssh.com"]);
+        client.mac_algorithms_server_to_client.clear();
+        let algorithms = negotiate(&client, &server).unwrap();
+        assert_eq!(algorithms.cipher_server_to_client, "aes256-gcm@openssh.com");
+        assert_eq!(algorithms.mac_server_to_client, None);
+        assert_eq!(algorithms.mac_client_to_server.as_deref(), Some("hmac-sha2-512"));
+    }
+
+    #[test]
+    fn markers_are_never_negotiated() {
+        // Even a peer that lists a marker the other side also lists must not
+        // end up "negotiating" it as a key exchange method
+        let client = kexinit(
+            &[EXT_INFO_CLIENT, KEX_STRICT_CLIENT, "ecdh-sha2-nistp384"],
+            &["ssh-ed25519"], &["aes256-ctr"], &["hmac-sha2-256"],
+        );
+        let server = kexinit(
+            &[EXT_INFO_CLIENT, KEX_STRICT_CLIENT, KEX_STRICT_SERVER, "ecdh-sha2-nistp384"],
+            &["ssh-ed25519"], &["aes256-ctr"], &["hmac-sha2-256"],
+        );
+        assert_eq!(negotiate(&client, &server).unwrap().kex, "ecdh-sha2-nistp384");
+
+        let server = kexinit(
+            &[EXT_INFO_CLIENT, KEX_STRICT_CLIENT, "curve25519-sha256"],
+            &["ssh-ed25519"], &["aes256-ctr"], &["hmac-sha2-256"],
+        );
+        assert!(negotiate(&client, &server).is_err());
+    }
+
+    #[test]
+    fn no_common_algorithm_fails() {
+        let client = kexinit(&["curve25519-sha256"], &["ssh-ed25519"], &["aes256-ctr"], &["hmac-sha2-256"]);
+        let server = |kex, host_key, cipher, mac| kexinit(&[kex], &[host_key], &[cipher], &[mac]);
+
+        let error = negotiate(&client, &server("ecdh-sha2-nistp256", "ssh-ed25519", "aes256-ctr", "hmac-sha2-256"))
+            .unwrap_err();
+        assert!(error.to_string().contains("No common key exchange algorithm"), "{}", error);
+        let error = negotiate(&client, &server("curve25519-sha256", "rsa-sha2-256", "aes256-ctr", "hmac-sha2-256"))
+            .unwrap_err();
+        assert!(error.to_string().contains("No common host key algorithm"), "{}", error);
+        let error = negotiate(&client, &server("curve25519-sha256", "ssh-ed25519", "aes128-ctr", "hmac-sha2-256"))
+            .unwrap_err();
+        assert!(error.to_string().contains("cipher"), "{}", error);
+        let error = negotiate(&client, &server("curve25519-sha256", "ssh-ed25519", "aes256-ctr", "hmac-sha2-512"))
+            .unwrap_err();
+        assert!(error.to_string().contains("No common MAC algorithm"), "{}", error);
+    }
+
+    #[test]
+    fn guesses_are_checked_against_both_first_choices() {
+        let client = kexinit(
+            &["curve25519-sha256", "ecdh-sha2-nistp256"],
+            &["ssh-ed25519", "rsa-sha2-256"],
+            &["aes256-ctr"], &["hmac-sha2-256"],
+        );
+        let server = kexinit(
+            &["ecdh-sha2-nistp256", "curve25519-sha256"],
+            &["rsa-sha2-256", "ssh-ed25519"],
+            &["aes256-ctr"], &["hmac-sha2-256"],
+        );
+        // The client's first choices win, but the guess of either side is
+        // wrong because the first choices differ
+        let algorithms = negotiate(&client, &server).unwrap();
+        assert_eq!(algorithms.kex, "curve25519-sha256");
+        assert!(!guess_was_right(&client, &server));
+
+        // Same first key exchange method, different first host key algorithm
+        let same_kex = kexinit(&["curve25519-sha256"], &["rsa-sha2-256"], &["aes256-ctr"], &["hmac-sha2-256"]);
+        assert!(!guess_was_right(&client, &same_kex));
+
+        // Markers in front do not count as a choice
+        let same_choices = kexinit(
+            &[EXT_INFO_SERVER, KEX_STRICT_SERVER, "curve25519-sha256"],
+            &["ssh-ed25519"],
+            &["aes256-ctr"], &["hmac-sha2-256"],
+        );
+        assert!(guess_was_right(&client, &same_choices));
+    }
+
+    /// Run the server side of a KEXINIT exchange against a client that
+    /// sends a guessed packet `[30, 1, 2, 3]` and then `[30, 4, 5, 6]`.
+    /// Returns the negotiated method and the next packet the server reads.
+    fn exchange_with_guessing_client(client_kex: &[&str], server_kex: &[&str]) -> (String, Vec<u8>) {
+        let (mut client_stream, mut server_stream) = UnixStream::pair().unwrap();
+        let preferences = |kex: &[&str]| AlgorithmPreferences {
+            kex: to_strings(kex),
+            ..AlgorithmPreferences::default()
+        };
+
+        let mut client = KexInit::from_preferences(&preferences(client_kex)).unwrap();
+        client.first_kex_packet_follows = true;
+        for payload in [client.to_bytes(), vec![30, 1, 2, 3], vec![30, 4, 5, 6]] {
+            Packet::new(payload).write(&mut client_stream).unwrap();
+        }
+
+        // The server's transport is already in its key exchange and has
+        // read the client's KEXINIT
+        let client_kexinit = Packet::read(&mut server_stream).unwrap().payload;
+        let mut transport = Transport::new(Box::new(server_stream));
+        let server = KexInit::from_preferences(&preferences(server_kex)).unwrap();
+        let exchange = exchange_kexinit(&mut transport, server, true, Some(client_kexinit)).unwrap();
+        assert!(KexInit::from_bytes(&Packet::read(&mut client_stream).unwrap().payload).is_ok());
+        (exchange.algorithms.kex, transport.recv_payload().unwrap())
+    }
+
+    #[test]
+    fn wrongly_guessed_packet_is_discarded() {
+        // The client guesses ecdh-sha2-nistp256, which the server does not do
+        let (kex, next) = exchange_with_guessing_client(
+            &["ecdh-sha2-nistp256", "curve25519-sha256"],
+            &["curve25519-sha256"],
+        );
+        assert_eq!(kex, "curve25519-sha256");
+        assert_eq!(next, [30, 4, 5, 6]);
+
+        // The client's first choice wins, but the server preferred another
+        // method, so the client expects its guess to be ignored too
+        let (kex, next) = exchange_with_guessing_client(
+            &["curve25519-sha256"],
+            &["ecdh-sha2-nistp256", "curve25519-sha256"],
+        );
+        assert_eq!(kex, "curve25519-sha256");
+        assert_eq!(next, [30, 4, 5, 6]);
+
+        // Same first choices: the guessed packet is the real one
+        let (_, next) = exchange_with_guessing_client(&["curve25519-sha256"], &["curve25519-sha256"]);
+        assert_eq!(next, [30, 1, 2, 3]);
+    }
+}
@@ -1,116 +1,166 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use anyhow::{Result, Context};
-use crate::crypto::keys::{HostKeyPair, get_ssh_edu_dir};
-use crate::utils::packet::Packet;
-use crate::utils::stream::ReadWrite;
-
-/// Send host key to client (server side)
-pub fn send_host_key(stream: &mut dyn ReadWrite, host_key: &HostKeyPair) -> Result<()> {
-    println!("[Phase 4] Sending host key to client...");
-    
-    let public_key_bytes = host_key.public_key_bytes();
-    let mut host_key_packet = Vec::new();
-    host_key_packet.extend_from_slice(&(public_key_bytes.len() as u32).to_be_bytes());
-    host_key_packet.extend_from_slice(&public_key_bytes);
-    
-    let packet = Packet::new(host_key_packet);
-    packet.write(stream)
-        .context("Failed to send host key")?;
-    
-    println!("[Phase 4] Sent host key ({} bytes)", public_key_bytes.len());
-    Ok(())
+use crate::crypto::keys::{get_ssh_edu_dir, KeyAlgorithm, KeyPair, PublicKey};
+
+/// Where the client remembers host keys: `~/.ssh_edu/known_hosts`
+pub fn known_hosts_path() -> Result<PathBuf> {
+    Ok(get_ssh_edu_dir()?.join("known_hosts"))
 }
 
-/// Receive and verify host key (client side)
-pub fn receive_and_verify_host_key(
-    stream: &mut dyn ReadWrite,
-    hostname: &str,
+/// Sign the exchange hash H with the host key using the negotiated host key
+/// algorithm (server side). Returns the signature in SSH blob format.
+pub fn sign_exchange_hash(
+    host_key: &KeyPair,
+    algorithm: &str,
+    exchange_hash: &[u8],
 ) -> Result<Vec<u8>> {
-    println!("[Phase 4] Receiving host key from server...");
-    
-    let host_key_packet = Packet::read(stream)
-        .context("Failed to receive host key")?;
-    
-    if host_key_packet.payload.len() < 4 {
-        anyhow::bail!("Invalid host key packet");
-    }
-    
-    let key_len = u32::from_be_bytes([
-        host_key_packet.payload[0],
-        host_key_packet.payload[1],
-        host_key_packet.payload[2],
-        host_key_packet.payload[3],
-    ]) as usize;
-    
-    if host_key_packet.payload.len() < 4 + key_len {
-        anyhow::bail!("Invalid host key length");
-    }
-    
-    let host_key_bytes = host_key_packet.payload[4..4 + key_len].to_vec();
-    println!("[Phase 4] Received host key ({} bytes)", host_key_bytes.len());
-    
-    // Check known_hosts file
-    let ssh_edu_dir = get_ssh_edu_dir()?;
-    let known_hosts_path = ssh_edu_dir.join("known_hosts");
-    
-    if known_hosts_path.exists() {
-        // Verify against known hosts
-        let known_hosts = std::fs::read_to_string(&known_hosts_path)
-            .context("Failed to read known_hosts")?;
-        
-        let expected_key = hex::encode(&host_key_bytes);
-        let host_entry = format!("{} {}", hostname, expected_key);
-        
-        if known_hosts.lines().any(|line| line == host_entry) {
-            println!("[Phase 4] Host key verified against known_hosts");
-        } else {
-            println!("[Phase 4] WARNING: Host key not found in known_hosts!");
-            println!("[Phase 4] This might be a man-in-the-middle attack!");
-            println!("[Phase 4] Adding to known_hosts (first connection)");
-            
-            // Add to known_hosts
-            let mut known_hosts_content = known_hosts;
-            if !known_hosts_content.ends_with('\n') {
-                known_hosts_content.push('\n');
-            }
-            known_hosts_content.push_str(&host_entry);
-            known_hosts_content.push('\n');
-            
-            std::fs::write(&known_hosts_path, known_hosts_content)
-                .context("Failed to write known_hosts")?;
+    println!("[Phase 4] Signing exchange hash with {} host key...", host_key.algorithm().name());
+    host_key.sign(algorithm, exchange_hash)
+}
+
+/// Verify the server's `algorithm` signature over the exchange hash, then
+/// check the host key against the `known_hosts` file (client side)
+pub fn verify_host_key(
+    known_hosts: &Path,
+    hostname: &str,
+    algorithm: &str,
+    host_key_blob: &[u8],
+    exchange_hash: &[u8],
+    signature_blob: &[u8],
+) -> Result<()> {
+    println!("[Phase 4] Verifying server host key...");
+
+    let host_key = PublicKey::from_blob(host_key_blob)
+        .context("Invalid host key")?;
+    println!(
+        "[Phase 4] Received {} host key {}",
+        host_key.algorithm().name(),
+        host_key.fingerprint()
+    );
+
+    // The signature proves the server owns the host key *and* took part in
+    // this very key exchange, since H covers both ephemeral public keys
+    host_key.verify(algorithm, exchange_hash, signature_blob)
+        .context("Host key signature over exchange hash is invalid")?;
+    println!("[Phase 4] Exchange hash signature verified ({})", algorithm);
+
+    check_known_hosts(known_hosts, hostname, &host_key)?;
+
+    println!("[Phase 4] Server authentication complete");
+    Ok(())
+}
+
+/// Host key remembered on a known_hosts line for `hostname`, written like
+/// OpenSSH as `hostname key-type base64(blob)`. Older lines hold the hex of
+/// the blob instead, or just the hex of a raw Ed25519 key.
+fn known_host_key(line: &str, hostname: &str) -> Option<PublicKey> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    match fields[..] {
+        [host, key_type, blob, ..] if host == hostname => {
+            let key = match hex::decode(blob) {
+                Ok(blob) => PublicKey::from_blob(&blob).ok()?,
+                Err(_) => PublicKey::from_openssh(&format!("{} {}", key_type, blob)).ok()?.0,
+            };
+            (key.algorithm().name() == key_type).then_some(key)
         }
-    } else {
-        // First connection - create known_hosts
-        println!("[Phase 4] First connection to this host");
-        println!("[Phase 4] Storing host key in known_hosts");
-        
-        std::fs::create_dir_all(&ssh_edu_dir)
-            .context("Failed to create .ssh_edu directory")?;
-        
-        let host_entry = format!("{} {}\n", hostname, hex::encode(&host_key_bytes));
-        std::fs::write(&known_hosts_path, host_entry)
-            .context("Failed to write known_hosts")?;
+        [host, key] if host == hostname => {
+            let key = hex::decode(key).ok()?;
+            (key.len() == 32).then_some(PublicKey::Ed25519(key))
+        }
+        _ => None,
     }
-    
-    // Send acknowledgment
-    let ack_packet = Packet::new(b"OK".to_vec());
-    ack_packet.write(stream)
-        .context("Failed to send host key acknowledgment")?;
-    
-    println!("[Phase 4] Server authentication complete");
-    Ok(host_key_bytes)
 }
 
-/// Receive host key acknowledgment (server side)
-pub fn receive_host_key_ack(stream: &mut dyn ReadWrite) -> Result<()> {
-    let ack_packet = Packet::read(stream)
-        .context("Failed to receive host key acknowledgment")?;
-    
-    if ack_packet.payload != b"OK" {
-        anyhow::bail!("Host key not accepted by client");
+/// Host keys remembered for `hostname`
+fn known_host_keys(known_hosts: &Path, hostname: &str) -> Result<Vec<PublicKey>> {
+    if !known_hosts.exists() {
+        return Ok(Vec::new());
     }
-    
-    println!("[Phase 4] Client accepted host key");
+    let content = std::fs::read_to_string(known_hosts)
+        .context("Failed to read known_hosts")?;
+    Ok(content.lines()
+        .filter_map(|line| known_host_key(line, hostname))
+        .collect())
+}
+
+/// Move the host key algorithms of keys already known for `hostname` to the
+/// front, so the server is asked for a key we can check rather than one we
+/// would have to trust on first use
+pub fn prefer_known_host_keys(
+    known_hosts: &Path,
+    hostname: &str,
+    host_key_algorithms: &mut [String],
+) -> Result<()> {
+    let known_algorithms: Vec<KeyAlgorithm> = known_host_keys(known_hosts, hostname)?
+        .iter()
+        .map(PublicKey::algorithm)
+        .collect();
+    host_key_algorithms.sort_by_key(|signature_algorithm| {
+        !known_algorithms.iter().any(|algorithm| algorithm.supports(signature_algorithm))
+    });
     Ok(())
 }
 
+/// Compare the host key with the ones remembered for `hostname`, storing it
+/// on first contact. A host that is known only by keys of other types must
+/// not get away with presenting a new one.
+fn check_known_hosts(known_hosts: &Path, hostname: &str, host_key: &PublicKey) -> Result<()> {
+    let algorithm = host_key.algorithm();
+    let host_entry = format!("{} {}", hostname, host_key.to_openssh(""));
+
+    let known_keys = known_host_keys(known_hosts, hostname)?;
+    if known_keys.contains(host_key) {
+        println!("[Phase 4] Host key verified against known_hosts");
+        return Ok(());
+    }
+
+    if known_keys.iter().any(|key| key.algorithm() == algorithm) {
+        println!("[Phase 4] WARNING: Host key for {} has changed!", hostname);
+        println!("[Phase 4] This might be a man-in-the-middle attack!");
+        anyhow::bail!(
+            "Host key verification failed: key for {} does not match known_hosts",
+            hostname
+        );
+    }
+
+    if !known_keys.is_empty() {
+        let known_types: Vec<&str> = known_keys.iter()
+            .map(|key| key.algorithm().name())
+            .collect();
+        println!(
+            "[Phase 4] WARNING: {} is known by {} keys but presented a {} key",
+            hostname,
+            known_types.join(", "),
+            algorithm.name()
+        );
+        anyhow::bail!(
+            "Host key verification failed: no {} key for {} in known_hosts",
+            algorithm.name(),
+            hostname
+        );
+    }
+
+    println!("[Phase 4] First connection to this host");
+    println!("[Phase 4] Storing host key in known_hosts");
+
+    if let Some(parent) = known_hosts.parent() {
+        std::fs::create_dir_all(parent)
+            .context("Failed to create .ssh_edu directory")?;
+    }
+    let mut known_hosts_content = if known_hosts.exists() {
+        std::fs::read_to_string(known_hosts)
+            .context("Failed to read known_hosts")?
+    } else {
+        String::new()
+    };
+    if !known_hosts_content.is_empty() && !known_hosts_content.ends_with('\n') {
+        known_hosts_content.push('\n');
+    }
+    known_hosts_content.push_str(&host_entry);
+    known_hosts_content.push('\n');
+
+    std::fs::write(known_hosts, known_hosts_content)
+        .context("Failed to write known_hosts")?;
+
+    Ok(())
+}
@@ -0,0 +1,76 @@
+use anyhow::{Result, Context};
+use crate::protocol::messages::{
+    expect_message, printable, SSH_MSG_SERVICE_ACCEPT, SSH_MSG_SERVICE_REQUEST,
+};
+use crate::protocol::transport::Transport;
+use crate::utils::wire::{Decode, Encode, Reader, Writer};
+
+/// User authentication service, the first one requested after the key
+/// exchange (RFC 4252 §1)
+pub const USERAUTH_SERVICE: &str = "ssh-userauth";
+
+/// SSH_MSG_SERVICE_REQUEST (RFC 4253 §10)
+pub struct ServiceRequest {
+    pub service: String,
+}
+
+impl Encode for ServiceRequest {
+    fn encode(&self, writer: &mut Writer) {
+        writer.write_byte(SSH_MSG_SERVICE_REQUEST);
+        writer.write_string(self.service.as_bytes());
+    }
+}
+
+impl Decode for ServiceRequest {
+    fn decode(reader: &mut Reader<'_>) -> Result<Self> {
+        expect_message(reader, SSH_MSG_SERVICE_REQUEST)?;
+        Ok(Self { service: reader.read_utf8()? })
+    }
+}
+
+/// SSH_MSG_SERVICE_ACCEPT (RFC 4253 §10)
+pub struct ServiceAccept {
+    pub service: String,
+}
+
+impl Encode for ServiceAccept {
+    fn encode(&self, writer: &mut Writer) {
+        writer.write_byte(SSH_MSG_SERVICE_ACCEPT);
+        writer.write_string(self.service.as_bytes());
+    }
+}
+
+impl Decode for ServiceAccept {
+    fn decode(reader: &mut Reader<'_>) -> Result<Self> {
+        expect_message(reader, SSH_MSG_SERVICE_ACCEPT)?;
+        Ok(Self { service: reader.read_utf8()? })
+    }
+}
+
+/// Ask the server for a service and wait until it accepts (client side)
+pub fn request_service(transport: &mut Transport, service: &str) -> Result<()> {
+    println!("[Phase 5] Requesting service {}", service);
+    transport.send(&ServiceRequest { service: service.to_string() })
+        .context("Failed to send service request")?;
+    let accept: ServiceAccept = transport.recv()
+        .context("Failed to receive service accept")?;
+    if accept.service != service {
+        anyhow::bail!("Server accepted service {} instead of {}", printable(&accept.service), service);
+    }
+    println!("[Phase 5] Service {} accepted", service);
+    Ok(())
+}
+
+/// Wait for the client to request `service` and accept it (server side).
+/// Any other service is an error, after which the server disconnects with
+/// SSH_DISCONNECT_SERVICE_NOT_AVAILABLE.
+pub fn accept_service(transport: &mut Transport, service: &str) -> Result<()> {
+    let request: ServiceRequest = transport.recv()
+        .context("Failed to receive service request")?;
+    if request.service != service {
+        anyhow::bail!("Service {} is not available", printable(&request.service));
+    }
+    println!("[Phase 5] Client requested service {}", service);
+    transport.send(&ServiceAccept { service: request.service })
+        .context("Failed to send service accept")
+}
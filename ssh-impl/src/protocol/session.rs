@@ -1,81 +1,206 @@
 use anyhow::{Result, Context};
-use crate::crypto::dh::SessionKeys;
-use crate::crypto::encryption::EncryptionContext;
-use crate::utils::packet::Packet;
-use crate::utils::stream::ReadWrite;
+use crate::protocol::compression::CompressionStats;
+use crate::protocol::messages::{expect_message, SSH_MSG_CHANNEL_DATA};
+use crate::protocol::negotiation::NegotiatedAlgorithms;
+use crate::protocol::transport::Transport;
+use crate::utils::wire::{Decode, Encode, Reader, Writer};
 
-/// Session state after all phases complete
-pub struct Session {
-    pub encryption_context: EncryptionContext,
+/// Channel carrying the echo traffic (no channel open handshake yet)
+const SESSION_CHANNEL: u32 = 0;
+
+/// SSH_MSG_CHANNEL_DATA carrying application data
+pub struct ChannelData {
+    pub recipient_channel: u32,
+    pub data: Vec<u8>,
 }
 
-impl Session {
-    /// Create a new session from session keys
-    pub fn new(session_keys: SessionKeys) -> Result<Self> {
-        let encryption_context = EncryptionContext::new(&session_keys)
-            .context("Failed to create encryption context")?;
-        
+impl Encode for ChannelData {
+    fn encode(&self, writer: &mut Writer) {
+        writer.write_byte(SSH_MSG_CHANNEL_DATA);
+        writer.write_u32(self.recipient_channel);
+        writer.write_string(&self.data);
+    }
+}
+
+impl Decode for ChannelData {
+    fn decode(reader: &mut Reader<'_>) -> Result<Self> {
+        expect_message(reader, SSH_MSG_CHANNEL_DATA)?;
         Ok(Self {
-            encryption_context,
+            recipient_channel: reader.read_u32()?,
+            data: reader.read_string()?.to_vec(),
+        })
+    }
+}
+
+/// Traffic statistics of a session
+#[derive(Debug, Clone, Copy)]
+pub struct SessionStats {
+    /// Compression of the payloads we sent
+    pub sent: CompressionStats,
+    /// Compression of the payloads we received
+    pub received: CompressionStats,
+}
+
+/// Limits on what an authenticated user may do in the session, from the
+/// authorized_keys options of the key they logged in with. The default
+/// restricts nothing.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SessionRestrictions {
+    /// Command run instead of any the client asks for (`command=`)
+    pub forced_command: Option<String>,
+    /// Variables added to the environment, first setting wins (`environment=`)
+    pub environment: Vec<(String, String)>,
+    pub no_pty: bool,
+    pub no_port_forwarding: bool,
+    pub no_agent_forwarding: bool,
+    /// `host:port` destinations local forwarding may reach, any if empty
+    /// (`permitopen=`). The port may be `*`.
+    pub permit_open: Vec<String>,
+}
+
+impl SessionRestrictions {
+    /// Whether the client may allocate a pseudo-terminal
+    pub fn permits_pty(&self) -> bool {
+        !self.no_pty
+    }
+
+    /// Whether the client may forward its authentication agent
+    pub fn permits_agent_forwarding(&self) -> bool {
+        !self.no_agent_forwarding
+    }
+
+    /// Whether the client may open a forwarded connection to `host:port`
+    pub fn permits_open(&self, host: &str, port: u16) -> bool {
+        if self.no_port_forwarding {
+            return false;
+        }
+        self.permit_open.is_empty() || self.permit_open.iter().any(|permitted| {
+            let Some((permitted_host, permitted_port)) = permitted.rsplit_once(':') else {
+                return false;
+            };
+            let permitted_host = permitted_host.trim_start_matches('[').trim_end_matches(']');
+            permitted_host.eq_ignore_ascii_case(host)
+                && (permitted_port == "*" || permitted_port.parse() == Ok(port))
         })
     }
 
-    /// Send encrypted data
-    pub fn send_encrypted(&mut self, stream: &mut dyn ReadWrite, data: &[u8]) -> Result<()> {
-        println!("[Phase 6] Encrypting and sending data ({} bytes)", data.len());
-        
-        let encrypted = self.encryption_context.encrypt(data)
-            .context("Failed to encrypt data")?;
-        
-        // Send encrypted data as packet
-        let mut packet_data = Vec::new();
-        packet_data.extend_from_slice(&(encrypted.len() as u32).to_be_bytes());
-        packet_data.extend_from_slice(&encrypted);
-        
-        let packet = Packet::new(packet_data);
-        packet.write(stream)
-            .context("Failed to send encrypted packet")?;
-        
+    /// Check that the session can honour these restrictions. It only echoes
+    /// data, never allocating a pty or forwarding ports or the agent, so
+    /// those limits always hold and `environment=` has no process to reach.
+    /// It cannot run a forced command instead, so such a key must not log in.
+    pub fn check_enforceable(&self) -> Result<()> {
+        if let Some(command) = &self.forced_command {
+            anyhow::bail!("forced command {:?} cannot be run by this server", command);
+        }
         Ok(())
     }
 
-    /// Receive and decrypt data
-    pub fn receive_encrypted(&mut self, stream: &mut dyn ReadWrite) -> Result<Vec<u8>> {
-        let encrypted_packet = Packet::read(stream)
-            .context("Failed to receive encrypted packet")?;
-        
-        if encrypted_packet.payload.len() < 4 {
-            anyhow::bail!("Invalid encrypted packet");
+    /// Log the restrictions in effect
+    fn print(&self) {
+        if *self == Self::default() {
+            return;
+        }
+        if let Some(command) = &self.forced_command {
+            println!("[Phase 6] Forced command: {}", command);
+        }
+        for (name, value) in &self.environment {
+            println!("[Phase 6] Environment: {}={}", name, value);
+        }
+        let denied: Vec<&str> = [
+            (self.no_pty, "pty"),
+            (self.no_port_forwarding, "port forwarding"),
+            (self.no_agent_forwarding, "agent forwarding"),
+        ]
+        .into_iter()
+        .filter_map(|(denied, name)| denied.then_some(name))
+        .collect();
+        if !denied.is_empty() {
+            println!("[Phase 6] Not permitted: {}", denied.join(", "));
         }
-        
-        let encrypted_len = u32::from_be_bytes([
-            encrypted_packet.payload[0],
-            encrypted_packet.payload[1],
-            encrypted_packet.payload[2],
-            encrypted_packet.payload[3],
-        ]) as usize;
-        
-        if encrypted_packet.payload.len() < 4 + encrypted_len {
-            anyhow::bail!("Invalid encrypted data length");
+        if !self.permit_open.is_empty() && !self.no_port_forwarding {
+            println!("[Phase 6] Forwarding limited to: {}", self.permit_open.join(", "));
         }
-        
-        let encrypted_data = &encrypted_packet.payload[4..4 + encrypted_len];
-        
-        println!("[Phase 6] Receiving and decrypting data ({} bytes)", encrypted_data.len());
-        
-        let decrypted = self.encryption_context.decrypt(encrypted_data)
-            .context("Failed to decrypt data")?;
-        
-        Ok(decrypted)
     }
 }
 
-/// Negotiate algorithms (simplified - just agree on AES-256-GCM)
-pub fn negotiate_algorithms() -> Result<()> {
-    println!("[Phase 6] Negotiating algorithms...");
-    println!("[Phase 6] Encryption: AES-256-GCM");
-    println!("[Phase 6] MAC: Integrated in GCM");
-    println!("[Phase 6] Algorithm negotiation complete");
-    Ok(())
+/// Session state after all phases complete
+pub struct Session {
+    pub recipient_channel: u32,
+    /// What the peer may do; only the server restricts the client
+    pub restrictions: SessionRestrictions,
 }
 
+impl Session {
+    /// Create a new session over a transport that already encrypts every
+    /// packet with the negotiated ciphers
+    pub fn new(algorithms: &NegotiatedAlgorithms) -> Self {
+        println!("[Phase 6] Encryption (client to server): {}", algorithms.cipher_client_to_server);
+        println!("[Phase 6] Encryption (server to client): {}", algorithms.cipher_server_to_client);
+        let implicit = "integrated in the AEAD cipher";
+        println!(
+            "[Phase 6] MAC (client to server): {}",
+            algorithms.mac_client_to_server.as_deref().unwrap_or(implicit)
+        );
+        println!(
+            "[Phase 6] MAC (server to client): {}",
+            algorithms.mac_server_to_client.as_deref().unwrap_or(implicit)
+        );
+
+        println!(
+            "[Phase 6] Compression: {} (client to server), {} (server to client)",
+            algorithms.compression_client_to_server,
+            algorithms.compression_server_to_client
+        );
+
+        Self {
+            recipient_channel: SESSION_CHANNEL,
+            restrictions: SessionRestrictions::default(),
+        }
+    }
+
+    /// Apply the restrictions of the authenticated user (server side)
+    pub fn restrict(&mut self, restrictions: SessionRestrictions) {
+        restrictions.print();
+        self.restrictions = restrictions;
+    }
+
+    /// Statistics of the traffic so far
+    pub fn stats(&self, transport: &Transport) -> SessionStats {
+        let (sent, received) = transport.compression_stats();
+        SessionStats { sent, received }
+    }
+
+    /// Print the statistics when the session ends
+    pub fn print_stats(&self, transport: &Transport) {
+        let stats = self.stats(transport);
+        println!("[Phase 6] Compression of sent data: {}", stats.sent);
+        println!("[Phase 6] Compression of received data: {}", stats.received);
+    }
+
+    /// Send application data
+    pub fn send_data(&mut self, transport: &mut Transport, data: &[u8]) -> Result<()> {
+        println!("[Phase 6] Sending data ({} bytes)", data.len());
+
+        let message = ChannelData {
+            recipient_channel: self.recipient_channel,
+            data: data.to_vec(),
+        };
+        transport.send(&message)
+            .context("Failed to send channel data")?;
+
+        Ok(())
+    }
+
+    /// Receive application data
+    pub fn receive_data(&mut self, transport: &mut Transport) -> Result<Vec<u8>> {
+        let message: ChannelData = transport.recv()
+            .context("Failed to receive channel data")?;
+
+        if message.recipient_channel != self.recipient_channel {
+            anyhow::bail!("Data for unknown channel {}", message.recipient_channel);
+        }
+
+        println!("[Phase 6] Received data ({} bytes)", message.data.len());
+        Ok(message.data)
+    }
+}
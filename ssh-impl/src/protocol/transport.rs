@@ -0,0 +1,628 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use anyhow::{Result, Context};
+use crate::protocol::compression::{
+    CompressionAlgorithm, CompressionStats, Compressor, Decompressor,
+};
+use crate::protocol::extensions::{extension_names, ExtInfo, Extensions, Ping, Pong};
+use crate::protocol::key_exchange::{perform_key_exchange, KexContext, KeyExchangeResult};
+use crate::protocol::messages::{
+    self, Debug, Disconnect, Unimplemented, SSH_MSG_DEBUG, SSH_MSG_DISCONNECT, SSH_MSG_EXT_INFO,
+    SSH_MSG_IGNORE, SSH_MSG_KEXINIT, SSH_MSG_PING, SSH_MSG_PONG, SSH_MSG_UNIMPLEMENTED,
+};
+use crate::crypto::encryption::PacketCipher;
+use crate::utils::packet::Packet;
+use crate::utils::stream::ReadWrite;
+use crate::utils::wire::{Decode, Encode};
+
+/// Error returned when the peer ends the connection with SSH_MSG_DISCONNECT
+#[derive(Debug)]
+pub struct PeerDisconnected(pub Disconnect);
+
+impl std::fmt::Display for PeerDisconnected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Peer disconnected: {}", self.0)
+    }
+}
+
+impl std::error::Error for PeerDisconnected {}
+
+/// When to renew the keys with a key re-exchange. RFC 4253 §9 recommends
+/// after each gigabyte or hour; RFC 4344 §3.1 bounds the packet count.
+#[derive(Debug, Clone, Copy)]
+pub struct RekeyLimits {
+    /// Payload bytes sent and received under the same keys
+    pub bytes: u64,
+    /// Packets sent and received under the same keys
+    pub packets: u64,
+    /// Time since the last key exchange (`None` disables the time limit)
+    pub interval: Option<Duration>,
+}
+
+impl Default for RekeyLimits {
+    fn default() -> Self {
+        Self {
+            bytes: 1 << 30,
+            packets: 1 << 31,
+            interval: Some(Duration::from_secs(3600)),
+        }
+    }
+}
+
+/// SSH transport layer: packet I/O with sequence numbers, packet encryption
+/// once keys are in use, key re-exchanges and dispatch of the
+/// transport-generic messages every phase may receive
+pub struct Transport {
+    stream: Box<dyn ReadWrite>,
+    send_sequence: u32,
+    recv_sequence: u32,
+    outgoing_cipher: Option<Box<dyn PacketCipher>>,
+    incoming_cipher: Option<Box<dyn PacketCipher>>,
+    kex_context: Option<KexContext>,
+    rekey_limits: RekeyLimits,
+    in_key_exchange: bool,
+    /// The initial key exchange has completed
+    keyed: bool,
+    /// Strict key exchange was agreed in the initial KEXINIT
+    strict_kex: bool,
+    /// Messages of higher layers that arrived while a key exchange was running
+    pending: VecDeque<Vec<u8>>,
+    /// Extensions announced by the peer's SSH_MSG_EXT_INFO
+    peer_extensions: Extensions,
+    outgoing_compression: CompressionAlgorithm,
+    incoming_compression: CompressionAlgorithm,
+    /// zlib streams, created when compression starts and kept across key
+    /// re-exchanges
+    compressor: Option<Compressor>,
+    decompressor: Option<Decompressor>,
+    sent_stats: CompressionStats,
+    received_stats: CompressionStats,
+    /// User authentication succeeded (starts delayed compression)
+    authenticated: bool,
+    bytes_since_kex: u64,
+    packets_since_kex: u64,
+    last_kex: Instant,
+}
+
+impl Transport {
+    /// Wrap a stream whose version exchange has already completed
+    pub fn new(stream: Box<dyn ReadWrite>) -> Self {
+        Self {
+            stream,
+            send_sequence: 0,
+            recv_sequence: 0,
+            outgoing_cipher: None,
+            incoming_cipher: None,
+            kex_context: None,
+            rekey_limits: RekeyLimits::default(),
+            in_key_exchange: false,
+            keyed: false,
+            strict_kex: false,
+            pending: VecDeque::new(),
+            peer_extensions: Extensions::default(),
+            outgoing_compression: CompressionAlgorithm::None,
+            incoming_compression: CompressionAlgorithm::None,
+            compressor: None,
+            decompressor: None,
+            sent_stats: CompressionStats::default(),
+            received_stats: CompressionStats::default(),
+            authenticated: false,
+            bytes_since_kex: 0,
+            packets_since_kex: 0,
+            last_kex: Instant::now(),
+        }
+    }
+
+    /// Keep what every key exchange of this connection needs, and the limits
+    /// after which we start a re-exchange ourselves
+    pub fn configure_key_exchange(&mut self, context: KexContext, limits: RekeyLimits) {
+        self.kex_context = Some(context);
+        self.rekey_limits = limits;
+    }
+
+    /// Run a key exchange started by us: the initial one, or a re-exchange
+    pub fn key_exchange(&mut self) -> Result<KeyExchangeResult> {
+        self.run_key_exchange(None)
+    }
+
+    fn run_key_exchange(&mut self, peer_kexinit: Option<Vec<u8>>) -> Result<KeyExchangeResult> {
+        let mut context = self.kex_context.take()
+            .context("Key exchange is not configured")?;
+        self.in_key_exchange = true;
+        let result = perform_key_exchange(self, &mut context, peer_kexinit);
+        self.in_key_exchange = false;
+        self.kex_context = Some(context);
+
+        let result = result?;
+        self.keyed = true;
+        self.bytes_since_kex = 0;
+        self.packets_since_kex = 0;
+        self.last_kex = Instant::now();
+        Ok(result)
+    }
+
+    /// Whether a limit for the current keys has been reached. Only applies
+    /// once the initial key exchange is done.
+    fn rekey_due(&self) -> bool {
+        let limits = &self.rekey_limits;
+        self.keyed
+            && (self.bytes_since_kex >= limits.bytes
+                || self.packets_since_kex >= limits.packets
+                || limits.interval.is_some_and(|interval| self.last_kex.elapsed() >= interval))
+    }
+
+    fn rekey_if_due(&mut self) -> Result<()> {
+        if !self.in_key_exchange && self.rekey_due() {
+            println!("[Transport] Rekey limit reached, starting key re-exchange");
+            self.run_key_exchange(None)
+                .context("Key re-exchange failed")?;
+        }
+        Ok(())
+    }
+
+    /// Turn on strict key exchange (OpenSSH PROTOCOL §1.10, the Terrapin
+    /// mitigation) once both initial KEXINITs offered it. The peer's KEXINIT
+    /// must have been the very first packet it sent.
+    pub fn enable_strict_kex(&mut self) -> Result<()> {
+        if self.recv_sequence != 1 {
+            anyhow::bail!("Strict key exchange: peer's KEXINIT was not its first packet");
+        }
+        self.strict_kex = true;
+        println!("[Transport] Strict key exchange enabled");
+        Ok(())
+    }
+
+    /// Extensions the peer announced so far
+    pub fn peer_extensions(&self) -> &Extensions {
+        &self.peer_extensions
+    }
+
+    /// Record the peer's SSH_MSG_EXT_INFO
+    pub fn record_ext_info(&mut self, ext_info: &ExtInfo) -> Result<()> {
+        println!("[Transport] Peer extensions: {}", extension_names(ext_info));
+        self.peer_extensions.update(ext_info)
+    }
+
+    /// Send SSH_MSG_PING; the peer must have announced ping@openssh.com
+    pub fn ping(&mut self, data: &[u8]) -> Result<()> {
+        if !self.peer_extensions.ping {
+            anyhow::bail!("Peer does not support ping@openssh.com");
+        }
+        self.send(&Ping { data: data.to_vec() })
+    }
+
+    /// Answer SSH_MSG_PING with the same data
+    fn answer_ping(&mut self, payload: &[u8]) -> Result<()> {
+        let ping = Ping::from_bytes(payload)
+            .context("Invalid SSH_MSG_PING")?;
+        self.send(&Pong { data: ping.data })
+    }
+
+    /// Compression of packets sent after our SSH_MSG_NEWKEYS
+    pub fn set_outgoing_compression(&mut self, algorithm: CompressionAlgorithm) {
+        self.outgoing_compression = algorithm;
+        self.update_compression();
+    }
+
+    /// Compression of packets received after the peer's SSH_MSG_NEWKEYS
+    pub fn set_incoming_compression(&mut self, algorithm: CompressionAlgorithm) {
+        self.incoming_compression = algorithm;
+        self.update_compression();
+    }
+
+    /// User authentication succeeded: zlib@openssh.com starts with the next
+    /// packet in each direction
+    pub fn set_authenticated(&mut self) {
+        self.authenticated = true;
+        self.update_compression();
+    }
+
+    /// Start or stop the zlib streams as the negotiated algorithms require
+    fn update_compression(&mut self) {
+        if !self.outgoing_compression.is_active(self.authenticated) {
+            self.compressor = None;
+        } else if self.compressor.is_none() {
+            self.compressor = Some(Compressor::new());
+            println!("[Transport] Outgoing packets are now compressed");
+        }
+        if !self.incoming_compression.is_active(self.authenticated) {
+            self.decompressor = None;
+        } else if self.decompressor.is_none() {
+            self.decompressor = Some(Decompressor::new());
+            println!("[Transport] Incoming packets are now compressed");
+        }
+    }
+
+    /// Payload sizes before and after compression, for what we sent and what
+    /// we received
+    pub fn compression_stats(&self) -> (CompressionStats, CompressionStats) {
+        (self.sent_stats, self.received_stats)
+    }
+
+    /// Encrypt every packet sent from now on (right after our SSH_MSG_NEWKEYS).
+    /// Strict key exchange restarts the sequence number with the new keys.
+    pub fn set_outgoing_cipher(&mut self, cipher: Box<dyn PacketCipher>) {
+        self.outgoing_cipher = Some(cipher);
+        if self.strict_kex {
+            self.send_sequence = 0;
+        }
+        println!("[Transport] Outgoing packets are now encrypted");
+    }
+
+    /// Decrypt every packet received from now on (right after the peer's
+    /// SSH_MSG_NEWKEYS). Strict key exchange restarts the sequence number.
+    pub fn set_incoming_cipher(&mut self, cipher: Box<dyn PacketCipher>) {
+        self.incoming_cipher = Some(cipher);
+        if self.strict_kex {
+            self.recv_sequence = 0;
+        }
+        println!("[Transport] Incoming packets are now encrypted");
+    }
+
+    /// Send a typed message
+    pub fn send<M: Encode>(&mut self, message: &M) -> Result<()> {
+        self.send_payload(&message.to_bytes())
+    }
+
+    /// Send a raw payload whose first byte is the message number. Messages of
+    /// higher layers first trigger a re-exchange if the keys are due for one.
+    pub fn send_payload(&mut self, payload: &[u8]) -> Result<()> {
+        let message_type = *payload.first()
+            .context("Cannot send an empty payload")?;
+        if !messages::is_transport_message(message_type) {
+            self.rekey_if_due()?;
+        }
+
+        // Compression comes before encryption (RFC 4253 §6.2)
+        let payload = match &mut self.compressor {
+            Some(compressor) => compressor.compress(payload, &mut self.sent_stats)?,
+            None => payload.to_vec(),
+        };
+        let packet_length = payload.len();
+        let packet = Packet::new(payload);
+        match &mut self.outgoing_cipher {
+            None => packet.write(&mut *self.stream)?,
+            Some(cipher) => {
+                let mut frame = packet.to_frame_with(cipher.block_size(), cipher.alignment(), cipher.mac_length())?;
+                cipher.seal(self.send_sequence, &mut frame)?;
+                self.stream.write_all(&frame)
+                    .context("Failed to write packet")?;
+                self.stream.flush()
+                    .context("Failed to flush writer")?;
+            }
+        }
+        self.send_sequence = self.send_sequence.wrapping_add(1);
+        self.bytes_since_kex += packet_length as u64;
+        self.packets_since_kex += 1;
+        Ok(())
+    }
+
+    /// Receive and decode a message of the expected type
+    pub fn recv<M: Decode>(&mut self) -> Result<M> {
+        let payload = self.recv_payload()?;
+        M::from_bytes(&payload)
+    }
+
+    /// Receive the next payload meant for the caller.
+    ///
+    /// SSH_MSG_IGNORE is dropped, SSH_MSG_DEBUG is shown, SSH_MSG_DISCONNECT
+    /// ends with a `PeerDisconnected` error and message numbers we do not
+    /// know are answered with SSH_MSG_UNIMPLEMENTED. SSH_MSG_EXT_INFO is
+    /// recorded and SSH_MSG_PING answered. A KEXINIT outside a key exchange
+    /// runs the re-exchange the peer started; higher-layer messages arriving
+    /// during a key exchange are queued and delivered afterwards.
+    pub fn recv_payload(&mut self) -> Result<Vec<u8>> {
+        if !self.in_key_exchange && self.pending.is_empty() {
+            self.rekey_if_due()?;
+        }
+
+        loop {
+            if !self.in_key_exchange {
+                if let Some(payload) = self.pending.pop_front() {
+                    // Pongs are held back until the key exchange is over
+                    if payload.first() == Some(&SSH_MSG_PING) {
+                        self.answer_ping(&payload)?;
+                        continue;
+                    }
+                    return Ok(payload);
+                }
+            }
+
+            let payload = self.read_packet()?;
+            let sequence_number = self.recv_sequence;
+            self.recv_sequence = self.recv_sequence.wrapping_add(1);
+
+            let message_type = *payload.first()
+                .context("Received empty packet payload")?;
+
+            // Strict key exchange: nothing but key exchange messages (or a
+            // disconnect) may arrive before the initial NEWKEYS, so no packet
+            // can be injected to shift the sequence numbers
+            if self.strict_kex
+                && self.incoming_cipher.is_none()
+                && message_type != SSH_MSG_DISCONNECT
+                && !messages::is_key_exchange_message(message_type)
+            {
+                anyhow::bail!(
+                    "Unexpected {} during strict key exchange",
+                    messages::message_name(message_type)
+                );
+            }
+
+            match message_type {
+                SSH_MSG_DISCONNECT => {
+                    let disconnect = Disconnect::from_bytes(&payload)
+                        .context("Invalid SSH_MSG_DISCONNECT")?;
+                    return Err(PeerDisconnected(disconnect).into());
+                }
+                SSH_MSG_IGNORE => continue,
+                SSH_MSG_DEBUG => {
+                    let debug = Debug::from_bytes(&payload)
+                        .context("Invalid SSH_MSG_DEBUG")?;
+                    println!("[Debug] Peer says: {}", messages::printable(&debug.message));
+                }
+                SSH_MSG_UNIMPLEMENTED => {
+                    // A normal reply (RFC 4253 §11.4), e.g. to an optional
+                    // message the peer does not know
+                    let unimplemented = Unimplemented::from_bytes(&payload)
+                        .context("Invalid SSH_MSG_UNIMPLEMENTED")?;
+                    println!(
+                        "[Transport] Peer does not implement our packet #{}",
+                        unimplemented.sequence_number
+                    );
+                }
+                _ if !messages::is_implemented(message_type) => {
+                    println!(
+                        "[Transport] Replying SSH_MSG_UNIMPLEMENTED to {}",
+                        messages::message_name(message_type)
+                    );
+                    self.send(&Unimplemented { sequence_number })?;
+                }
+                SSH_MSG_EXT_INFO if !self.in_key_exchange => {
+                    let ext_info = ExtInfo::from_bytes(&payload)
+                        .context("Invalid SSH_MSG_EXT_INFO")?;
+                    self.record_ext_info(&ext_info)?;
+                }
+                SSH_MSG_PING if !self.in_key_exchange => self.answer_ping(&payload)?,
+                SSH_MSG_PONG => {
+                    let pong = Pong::from_bytes(&payload)
+                        .context("Invalid SSH_MSG_PONG")?;
+                    println!("[Transport] Received pong ({} bytes)", pong.data.len());
+                }
+                SSH_MSG_KEXINIT if !self.in_key_exchange => {
+                    println!("[Transport] Peer started a key re-exchange");
+                    self.run_key_exchange(Some(payload))
+                        .context("Key re-exchange failed")?;
+                }
+                _ if self.in_key_exchange && !messages::is_transport_message(message_type) => {
+                    println!(
+                        "[Transport] Queuing {} received during key exchange",
+                        messages::message_name(message_type)
+                    );
+                    self.pending.push_back(payload);
+                }
+                _ => return Ok(payload),
+            }
+        }
+    }
+
+    /// Read one packet, decrypting it if keys are in use, and return its payload
+    fn read_packet(&mut self) -> Result<Vec<u8>> {
+        let payload = match &mut self.incoming_cipher {
+            None => Packet::read(&mut *self.stream)?.payload,
+            Some(cipher) => {
+                let body = cipher.open(self.recv_sequence, &mut *self.stream)?;
+                Packet::strip_padding(&body)?.to_vec()
+            }
+        };
+        self.bytes_since_kex += payload.len() as u64;
+        self.packets_since_kex += 1;
+        match &mut self.decompressor {
+            Some(decompressor) => decompressor.decompress(&payload, &mut self.received_stats),
+            None => Ok(payload),
+        }
+    }
+
+    /// End the session with SSH_MSG_DISCONNECT
+    pub fn disconnect(&mut self, reason_code: u32, description: &str) -> Result<()> {
+        let disconnect = Disconnect::new(reason_code, description);
+        println!("[Transport] Sending disconnect: {}", disconnect);
+        self.send(&disconnect)
+            .context("Failed to send disconnect")
+    }
+
+    /// Tell the peer why we are giving up, then hand the error back.
+    /// Nothing is sent if the peer already disconnected.
+    pub fn abort<T>(&mut self, reason_code: u32, error: anyhow::Error) -> Result<T> {
+        if error.downcast_ref::<PeerDisconnected>().is_none() {
+            // Best effort: the connection may already be unusable
+            let _ = self.disconnect(reason_code, &error.to_string());
+        }
+        Err(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+    use crate::crypto::keys::{KeyAlgorithm, KeyPair};
+    use crate::protocol::key_exchange::VersionStrings;
+    use crate::protocol::messages::{Ignore, SSH_DISCONNECT_KEY_EXCHANGE_FAILED, SSH_MSG_NEWKEYS};
+    use crate::protocol::service::ServiceRequest;
+    use crate::protocol::negotiation::AlgorithmPreferences;
+    use crate::protocol::version::{CLIENT_VERSION, SERVER_VERSION};
+
+    /// Client stream that slips an SSH_MSG_IGNORE in front of the client's
+    /// cleartext NEWKEYS, as a man in the middle would
+    struct InjectIgnoreBeforeNewKeys {
+        inner: UnixStream,
+    }
+
+    impl Read for InjectIgnoreBeforeNewKeys {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.inner.read(buf)
+        }
+    }
+
+    impl Write for InjectIgnoreBeforeNewKeys {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            // Cleartext frame: packet_length(4) || padding_length(1) || payload
+            // Written together so the server cannot hang up in between
+            if buf.get(5) == Some(&SSH_MSG_NEWKEYS) {
+                let ignore = Ignore { data: b"injected".to_vec() };
+                let mut frames = Packet::new(ignore.to_bytes()).to_frame(8)
+                    .map_err(std::io::Error::other)?;
+                frames.extend_from_slice(buf);
+                self.inner.write_all(&frames)?;
+                return Ok(buf.len());
+            }
+            self.inner.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.inner.flush()
+        }
+    }
+
+    fn versions() -> VersionStrings {
+        VersionStrings {
+            client: CLIENT_VERSION.to_string(),
+            server: SERVER_VERSION.to_string(),
+        }
+    }
+
+    /// Strict mode and sequence numbers of a transport after its key exchange
+    type KexState = (bool, u32, u32);
+
+    fn kex_state(transport: &Transport) -> KexState {
+        (transport.strict_kex, transport.send_sequence, transport.recv_sequence)
+    }
+
+    /// Run the server side of a key exchange the way the connection handler
+    /// does, disconnecting on failure
+    fn spawn_server(stream: UnixStream) -> thread::JoinHandle<(Result<()>, KexState)> {
+        thread::spawn(move || {
+            let mut transport = Transport::new(Box::new(stream));
+            let host_key = KeyPair::generate(KeyAlgorithm::Ed25519).unwrap();
+            let context = KexContext::server(versions(), AlgorithmPreferences::default(), vec![host_key]);
+            transport.configure_key_exchange(context, RekeyLimits::default());
+            let result = transport.key_exchange()
+                .map(|_| ())
+                .or_else(|e| transport.abort(SSH_DISCONNECT_KEY_EXCHANGE_FAILED, e));
+            (result, kex_state(&transport))
+        })
+    }
+
+    /// Client transport recording the server's host key in a known_hosts
+    /// file of `dir`
+    fn client_transport(stream: Box<dyn ReadWrite>, dir: &tempfile::TempDir) -> Transport {
+        let mut transport = Transport::new(stream);
+        let known_hosts = dir.path().join("known_hosts");
+        let context = KexContext::client(versions(), AlgorithmPreferences::default(), "localhost", known_hosts);
+        transport.configure_key_exchange(context, RekeyLimits::default());
+        transport
+    }
+
+    #[test]
+    fn strict_kex_resets_sequence_numbers() {
+        let (server_stream, client_stream) = UnixStream::pair().unwrap();
+        let server = spawn_server(server_stream);
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut client = client_transport(Box::new(client_stream), &dir);
+        client.key_exchange().unwrap();
+        let (server_result, server_state) = server.join().unwrap();
+        server_result.unwrap();
+
+        // The server's EXT_INFO is its only packet after NEWKEYS, and it is
+        // still unread
+        assert_eq!(kex_state(&client), (true, 0, 0));
+        assert_eq!(server_state, (true, 1, 0));
+    }
+
+    #[test]
+    fn strict_kex_rejects_ignore_before_newkeys() {
+        let (server_stream, client_stream) = UnixStream::pair().unwrap();
+        let server = spawn_server(server_stream);
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut client = client_transport(
+            Box::new(InjectIgnoreBeforeNewKeys { inner: client_stream }),
+            &dir,
+        );
+        // The client itself sees nothing wrong: the server only hangs up
+        // once it reads the injected message
+        client.key_exchange().unwrap();
+        let (server_result, _) = server.join().unwrap();
+
+        let error = format!("{:#}", server_result.unwrap_err());
+        assert!(error.contains("Unexpected SSH_MSG_IGNORE during strict key exchange"), "{}", error);
+
+        let disconnect = client.recv_payload().unwrap_err();
+        let PeerDisconnected(disconnect) = disconnect.downcast_ref::<PeerDisconnected>().unwrap();
+        assert_eq!(disconnect.reason_code, SSH_DISCONNECT_KEY_EXCHANGE_FAILED);
+    }
+
+    #[test]
+    fn unimplemented_and_debug_are_not_fatal() {
+        let (server_stream, client_stream) = UnixStream::pair().unwrap();
+        let mut server = Transport::new(Box::new(server_stream));
+        let mut client = Transport::new(Box::new(client_stream));
+
+        client.send(&Unimplemented { sequence_number: 3 }).unwrap();
+        client.send(&Debug {
+            always_display: true,
+            message: "\x1b]0;owned\x07".to_string(),
+            language_tag: String::new(),
+        })
+        .unwrap();
+        client.send(&ServiceRequest { service: "ssh-userauth".to_string() }).unwrap();
+
+        let request: ServiceRequest = server.recv().unwrap();
+        assert_eq!(request.service, "ssh-userauth");
+    }
+
+    #[test]
+    fn rekey_limit_starts_a_re_exchange_and_flushes_queued_messages() {
+        let (server_stream, client_stream) = UnixStream::pair().unwrap();
+        let services = ["one", "two", "three", "four", "five"];
+
+        let server = thread::spawn(move || {
+            let mut transport = Transport::new(Box::new(server_stream));
+            let host_key = KeyPair::generate(KeyAlgorithm::Ed25519).unwrap();
+            let context = KexContext::server(versions(), AlgorithmPreferences::default(), vec![host_key]);
+            let limits = RekeyLimits { bytes: 1 << 30, packets: 2, interval: None };
+            transport.configure_key_exchange(context, limits);
+            transport.key_exchange().unwrap();
+
+            let mut received = Vec::new();
+            for _ in services {
+                let request: ServiceRequest = transport.recv().unwrap();
+                received.push(request.service);
+            }
+            // The third receive started a re-exchange, which read the
+            // client's remaining requests and queued them
+            let counters = (transport.packets_since_kex, transport.bytes_since_kex, transport.pending.len());
+            transport.send(&ServiceRequest { service: "done".to_string() }).unwrap();
+            (received, counters)
+        });
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut client = client_transport(Box::new(client_stream), &dir);
+        client.key_exchange().unwrap();
+        for service in services {
+            client.send(&ServiceRequest { service: service.to_string() }).unwrap();
+        }
+        // Answers the server's KEXINIT on the way to the reply
+        let reply: ServiceRequest = client.recv().unwrap();
+        assert_eq!(reply.service, "done");
+        assert_eq!(client.packets_since_kex, 1);
+
+        let (received, counters) = server.join().unwrap();
+        assert_eq!(received, services);
+        assert_eq!(counters, (0, 0, 0));
+    }
+}
@@ -0,0 +1,130 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use anyhow::{Result, Context};
+use crate::crypto::keys::get_ssh_edu_dir;
+use crate::crypto::password::{hash_password, is_password_hash, verify_password};
+
+/// Hash checked for unknown users, so that a wrong user name takes as long
+/// to refuse as a wrong password (the password is "unknown-user")
+const UNKNOWN_USER_HASH: &str =
+    "$pbkdf2-sha256$i=600000$dW5rbm93bi11c2VyLXNhbHQ$wS+gqIbqcQFJSVGOOFkIiRC6kr3jqrUPvSfOe7DiUWA";
+
+/// Password database: user names mapped to PHC password hashes, stored as a
+/// JSON object in `~/.ssh_edu/users.json`
+#[derive(Debug)]
+pub struct UserDatabase {
+    pub path: PathBuf,
+    pub users: BTreeMap<String, String>,
+}
+
+impl UserDatabase {
+    /// Where the server looks for the database
+    pub fn default_path() -> Result<PathBuf> {
+        Ok(get_ssh_edu_dir()?.join("users.json"))
+    }
+
+    /// An empty database to be saved at `path`
+    pub fn new(path: &Path) -> Self {
+        Self { path: path.to_path_buf(), users: BTreeMap::new() }
+    }
+
+    /// Load the database. Plaintext passwords written by older versions
+    /// are replaced with hashes and the file saved again.
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let mut database = Self {
+            path: path.to_path_buf(),
+            users: serde_json::from_str(&content)
+                .with_context(|| format!("Invalid user database {}", path.display()))?,
+        };
+
+        let mut migrated = 0;
+        for stored in database.users.values_mut() {
+            if !is_password_hash(stored) {
+                *stored = hash_password(stored)?;
+                migrated += 1;
+            }
+        }
+        if migrated > 0 {
+            database.save()?;
+            println!("Hashed {} plaintext password(s) in {}", migrated, path.display());
+        }
+        Ok(database)
+    }
+
+    /// Write the database, readable only by its owner. Files created by
+    /// older versions are world-readable, so the mode is set every time.
+    pub fn save(&self) -> Result<()> {
+        let path = &self.path;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .context("Failed to create .ssh_edu directory")?;
+        }
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(path)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        #[cfg(unix)]
+        file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))
+            .with_context(|| format!("Failed to restrict {}", path.display()))?;
+        file.write_all(serde_json::to_string_pretty(&self.users)?.as_bytes())
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    /// Add a user or change their password
+    pub fn set_password(&mut self, username: &str, password: &str) -> Result<()> {
+        if username.is_empty() || username.contains(|c: char| c.is_whitespace() || c == '/') {
+            anyhow::bail!("Invalid user name: {:?}", username);
+        }
+        self.users.insert(username.to_string(), hash_password(password)?);
+        Ok(())
+    }
+
+    /// Remove a user; false if there was none
+    pub fn remove(&mut self, username: &str) -> bool {
+        self.users.remove(username).is_some()
+    }
+
+    /// Check a user's password
+    pub fn verify(&self, username: &str, password: &str) -> Result<bool> {
+        match self.users.get(username) {
+            Some(hash) => verify_password(password, hash),
+            None => {
+                verify_password(password, UNKNOWN_USER_HASH)?;
+                Ok(false)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn load_hashes_plaintext_passwords() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("users.json");
+        fs::write(&path, r#"{"testuser": "testpass"}"#).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        let users = UserDatabase::load(&path).unwrap();
+        assert!(is_password_hash(&users.users["testuser"]));
+        assert!(users.verify("testuser", "testpass").unwrap());
+        assert!(!users.verify("testuser", "wrong").unwrap());
+        assert!(!users.verify("nobody", "testpass").unwrap());
+
+        // The file now holds the hash, and only its owner may read it
+        let saved = fs::read_to_string(&path).unwrap();
+        assert!(!saved.contains("\"testpass\""), "{}", saved);
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        let reloaded = UserDatabase::load(&path).unwrap();
+        assert_eq!(reloaded.users, users.users);
+    }
+}
@@ -1,10 +1,11 @@
 use std::io::{Read, Write};
 use anyhow::{Result, Context};
+use crate::protocol::messages::printable;
 use crate::utils::io;
 
 /// SSH protocol version string
-const SERVER_VERSION: &str = "SSH-2.0-EduSSH-1.0";
-const CLIENT_VERSION: &str = "SSH-2.0-EduSSH-Client-1.0";
+pub const SERVER_VERSION: &str = "SSH-2.0-EduSSH-1.0";
+pub const CLIENT_VERSION: &str = "SSH-2.0-EduSSH-Client-1.0";
 
 /// Send protocol version string
 pub fn send_version_string(stream: &mut dyn Write, is_server: bool) -> Result<()> {
@@ -27,7 +28,7 @@ pub fn receive_version_string(stream: &mut dyn Read) -> Result<String> {
     let version_line = io::read_line_crlf(stream)
         .context("Failed to receive version string")?;
     
-    println!("[Phase 2] Received version: {}", version_line);
+    println!("[Phase 2] Received version: {}", printable(&version_line));
     Ok(version_line)
 }
 
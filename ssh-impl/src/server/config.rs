@@ -0,0 +1,30 @@
+use std::path::PathBuf;
+use crate::crypto::moduli::DEFAULT_MODULI_FILE;
+use crate::protocol::client_auth::AuthPolicy;
+use crate::protocol::negotiation::AlgorithmPreferences;
+use crate::protocol::transport::RekeyLimits;
+
+/// Server settings shared by every connection
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub port: u16,
+    pub algorithms: AlgorithmPreferences,
+    pub rekey: RekeyLimits,
+    /// Groups offered in diffie-hellman-group-exchange
+    pub moduli_file: PathBuf,
+    /// Methods users must complete and how many attempts they get
+    pub auth: AuthPolicy,
+}
+
+impl ServerConfig {
+    /// Default settings listening on `port`
+    pub fn new(port: u16) -> Self {
+        Self {
+            port,
+            algorithms: AlgorithmPreferences::default(),
+            rekey: RekeyLimits::default(),
+            moduli_file: PathBuf::from(DEFAULT_MODULI_FILE),
+            auth: AuthPolicy::default(),
+        }
+    }
+}
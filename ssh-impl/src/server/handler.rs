@@ -1,75 +1,112 @@
 use std::net::TcpStream;
 use anyhow::{Result, Context};
-use crate::protocol::version::{send_version_string, receive_version_string, negotiate_version};
-use crate::protocol::key_exchange::server_key_exchange;
-use crate::protocol::server_auth::{send_host_key, receive_host_key_ack};
+use crate::protocol::version::{
+    send_version_string, receive_version_string, negotiate_version, SERVER_VERSION,
+};
+use crate::protocol::key_exchange::{KexContext, VersionStrings};
 use crate::protocol::client_auth::handle_auth_request;
-use crate::protocol::session::{Session, negotiate_algorithms};
-use crate::crypto::keys::{HostKeyPair, get_ssh_edu_dir};
+use crate::protocol::messages::{
+    SSH_DISCONNECT_BY_APPLICATION, SSH_DISCONNECT_KEY_EXCHANGE_FAILED,
+    SSH_DISCONNECT_NO_MORE_AUTH_METHODS_AVAILABLE, SSH_DISCONNECT_PROTOCOL_ERROR,
+    SSH_DISCONNECT_PROTOCOL_VERSION_NOT_SUPPORTED, SSH_DISCONNECT_SERVICE_NOT_AVAILABLE,
+};
+use crate::protocol::service::{accept_service, USERAUTH_SERVICE};
+use crate::protocol::session::Session;
+use crate::protocol::transport::{PeerDisconnected, Transport};
+use crate::crypto::keys::{KeyAlgorithm, KeyPair, KeyRole};
+use crate::server::config::ServerConfig;
 use crate::utils::stream::ReadWrite;
 
 /// Handle a single SSH connection
-pub fn handle_connection(stream: TcpStream) -> Result<()> {
+pub fn handle_connection(stream: TcpStream, config: &ServerConfig) -> Result<()> {
+    let client_address = stream.peer_addr()
+        .context("Failed to get client address")?
+        .ip();
     let mut stream_ref: Box<dyn ReadWrite> = Box::new(stream);
-    
+
     println!("\n[Phase 1] TCP connection established");
-    
+
     // Phase 2: Protocol Version Exchange
     println!("\n=== Phase 2: Protocol Version Exchange ===");
     send_version_string(&mut *stream_ref, true)?;
     let client_version = receive_version_string(&mut *stream_ref)?;
-    let server_version = "SSH-2.0-EduSSH-1.0";
-    negotiate_version(&client_version, server_version)?;
-    
-    // Phase 3: Key Exchange
-    println!("\n=== Phase 3: Key Exchange ===");
-    let session_keys = server_key_exchange(&mut *stream_ref)?;
-    
-    // Phase 4: Server Authentication
-    println!("\n=== Phase 4: Server Authentication ===");
-    let ssh_edu_dir = get_ssh_edu_dir()?;
-    let host_key_path = ssh_edu_dir.join("host_key");
-    let host_key = HostKeyPair::load_or_generate(&host_key_path)?;
-    send_host_key(&mut *stream_ref, &host_key)?;
-    receive_host_key_ack(&mut *stream_ref)?;
-    
+    let mut transport = Transport::new(stream_ref);
+    negotiate_version(&client_version, SERVER_VERSION)
+        .or_else(|e| transport.abort(SSH_DISCONNECT_PROTOCOL_VERSION_NOT_SUPPORTED, e))?;
+    let versions = VersionStrings {
+        client: client_version,
+        server: SERVER_VERSION.to_string(),
+    };
+
+    // Phase 3 & 4: Key Exchange and Server Authentication. The host key signs
+    // the exchange hash, so it must be loaded before the exchange starts.
+    println!("\n=== Phase 3 & 4: Key Exchange and Server Authentication ===");
+    let host_keys = load_host_keys(&config.algorithms.host_key)
+        .or_else(|e| transport.abort(SSH_DISCONNECT_KEY_EXCHANGE_FAILED, e))?;
+    let mut context = KexContext::server(versions, config.algorithms.clone(), host_keys);
+    context.moduli_file = config.moduli_file.clone();
+    transport.configure_key_exchange(context, config.rekey);
+    let kex = transport.key_exchange()
+        .or_else(|e| transport.abort(SSH_DISCONNECT_KEY_EXCHANGE_FAILED, e))?;
+
     // Phase 5: Client Authentication
     println!("\n=== Phase 5: Client Authentication ===");
-    let username = handle_auth_request(&mut *stream_ref)?;
-    
+    accept_service(&mut transport, USERAUTH_SERVICE)
+        .or_else(|e| transport.abort(SSH_DISCONNECT_SERVICE_NOT_AVAILABLE, e))?;
+    let user = handle_auth_request(&mut transport, &kex.host_key, &kex.session_id, client_address, &config.auth)
+        .or_else(|e| transport.abort(SSH_DISCONNECT_NO_MORE_AUTH_METHODS_AVAILABLE, e))?;
+
     // Phase 6: Session Establishment
     println!("\n=== Phase 6: Session Establishment ===");
-    negotiate_algorithms()?;
-    let mut session = Session::new(session_keys)?;
-    
+    let mut session = Session::new(&kex.algorithms);
+    session.restrict(user.restrictions);
+
     println!("\n=== SSH Session Established ===");
-    println!("Authenticated user: {}", username);
+    println!("Authenticated user: {}", user.username);
     println!("Secure channel ready for data transmission");
-    
+
     // Simple echo server for demonstration
     println!("\nEcho server mode - type messages to echo back");
     loop {
-        match session.receive_encrypted(&mut *stream_ref) {
+        match session.receive_data(&mut transport) {
             Ok(data) => {
                 let message = String::from_utf8_lossy(&data);
                 println!("Received: {}", message);
-                
+
                 if message.trim() == "exit" {
                     println!("Client requested disconnect");
+                    transport.disconnect(SSH_DISCONNECT_BY_APPLICATION, "Session closed")?;
                     break;
                 }
-                
+
                 let response = format!("Echo: {}", message);
-                session.send_encrypted(&mut *stream_ref, response.as_bytes())?;
+                session.send_data(&mut transport, response.as_bytes())?;
             }
             Err(e) => {
-                println!("Connection closed or error: {}", e);
+                if let Some(PeerDisconnected(disconnect)) = e.downcast_ref::<PeerDisconnected>() {
+                    println!("Client disconnected: {}", disconnect);
+                } else {
+                    println!("Connection closed or error: {}", e);
+                    let _ = transport.disconnect(SSH_DISCONNECT_PROTOCOL_ERROR, &e.to_string());
+                }
                 break;
             }
         }
     }
-    
+
+    session.print_stats(&transport);
     println!("Connection closed");
     Ok(())
 }
 
+/// Load the host key of every type some configured host key algorithm
+/// needs, generating missing ones
+fn load_host_keys(host_key_algorithms: &[String]) -> Result<Vec<KeyPair>> {
+    KeyAlgorithm::ALL.into_iter()
+        .filter(|algorithm| host_key_algorithms.iter().any(|name| algorithm.supports(name)))
+        .map(|algorithm| {
+            KeyPair::load_or_generate(&KeyRole::Host.path(algorithm)?, algorithm)
+                .with_context(|| format!("Failed to load {} host key", algorithm.name()))
+        })
+        .collect()
+}
@@ -1,5 +1,6 @@
+pub mod config;
 pub mod tcp;
 pub mod handler;
 
-pub use tcp::run;
-
+pub use config::ServerConfig;
+pub use tcp::{run, run_with_config};
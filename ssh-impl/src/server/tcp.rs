@@ -1,11 +1,19 @@
-use std::net::{TcpListener, TcpStream};
-use std::io;
+use std::net::TcpListener;
 use anyhow::{Result, Context};
+use crate::server::config::ServerConfig;
 use crate::server::handler::handle_connection;
 
-/// Run SSH server on specified port
+/// Run SSH server on specified port with default settings
 pub fn run(port: u16) -> Result<()> {
-    let address = format!("0.0.0.0:{}", port);
+    run_with_config(&ServerConfig::new(port))
+}
+
+/// Run SSH server with the given settings
+pub fn run_with_config(config: &ServerConfig) -> Result<()> {
+    config.algorithms.validate()
+        .context("Invalid algorithm configuration")?;
+
+    let address = format!("0.0.0.0:{}", config.port);
     let listener = TcpListener::bind(&address)
         .context(format!("Failed to bind to {}", address))?;
     
@@ -21,7 +29,7 @@ pub fn run(port: u16) -> Result<()> {
                 println!("\n=== New connection from {} ===", peer_addr);
                 
                 // Handle each connection (in production, spawn a thread)
-                if let Err(e) = handle_connection(stream) {
+                if let Err(e) = handle_connection(stream, config) {
                     eprintln!("Connection error: {}", e);
                 }
             }
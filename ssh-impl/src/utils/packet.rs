@@ -1,8 +1,46 @@
 use std::io::{Read, Write};
 use anyhow::{Result, Context};
+use ring::rand::{SecureRandom, SystemRandom};
 
-/// SSH packet structure (simplified)
-/// Format: [packet_length(4)][padding_length(1)][payload][padding][mac]
+/// Largest packet we send or accept, counting the length field, payload,
+/// padding and MAC. RFC 4253 §6.1 requires every implementation to handle
+/// at least this size.
+pub const MAX_PACKET_SIZE: usize = 35000;
+
+/// Minimum number of random padding bytes (RFC 4253 §6)
+pub const MIN_PADDING_LENGTH: usize = 4;
+
+/// Block size used while no cipher is active; also the minimum for any cipher
+pub const MIN_BLOCK_SIZE: usize = 8;
+
+/// Smallest complete packet on the wire, excluding the MAC
+const MIN_PACKET_SIZE: usize = 16;
+
+/// Size of the `packet_length` field
+const LENGTH_FIELD_SIZE: usize = 4;
+
+/// Which part of a packet must be a multiple of the cipher block size
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Alignment {
+    /// The whole packet including `packet_length` (RFC 4253 §6)
+    WithLength,
+    /// Everything after `packet_length`, for modes that authenticate the
+    /// length instead of encrypting it with the rest (AEAD, encrypt-then-MAC)
+    AfterLength,
+}
+
+impl Alignment {
+    /// Bytes before `padding_length` that count towards the alignment
+    fn prefix_length(self) -> usize {
+        match self {
+            Alignment::WithLength => LENGTH_FIELD_SIZE,
+            Alignment::AfterLength => 0,
+        }
+    }
+}
+
+/// SSH binary packet (RFC 4253 §6)
+/// Format: [packet_length(4)][padding_length(1)][payload][random padding][mac]
 pub struct Packet {
     pub payload: Vec<u8>,
 }
@@ -15,60 +53,216 @@ impl Packet {
 
     /// Read a packet from a reader (unencrypted, for initial phases)
     pub fn read(reader: &mut dyn Read) -> Result<Self> {
+        Self::read_aligned(reader, MIN_BLOCK_SIZE)
+    }
+
+    /// Read a packet whose total length must be a multiple of `block_size`
+    pub fn read_aligned(reader: &mut dyn Read, block_size: usize) -> Result<Self> {
         // Read packet length (4 bytes, big-endian)
-        let mut len_buf = [0u8; 4];
+        let mut len_buf = [0u8; LENGTH_FIELD_SIZE];
         reader.read_exact(&mut len_buf)
             .context("Failed to read packet length")?;
         let packet_length = u32::from_be_bytes(len_buf) as usize;
+        check_packet_length(packet_length, block_size, Alignment::WithLength, 0)?;
 
-        // Read padding length (1 byte)
-        let mut pad_len_buf = [0u8; 1];
-        reader.read_exact(&mut pad_len_buf)
-            .context("Failed to read padding length")?;
-        let padding_length = pad_len_buf[0] as usize;
-
-        // Read payload
-        let payload_length = packet_length - padding_length - 1;
-        let mut payload = vec![0u8; payload_length];
-        reader.read_exact(&mut payload)
-            .context("Failed to read payload")?;
+        // Read the rest of the packet in one go now that its size is trusted
+        let mut body = vec![0u8; packet_length];
+        reader.read_exact(&mut body)
+            .context("Failed to read packet body")?;
 
-        // Read and discard padding
-        let mut padding = vec![0u8; padding_length];
-        reader.read_exact(&mut padding)
-            .context("Failed to read padding")?;
-
-        Ok(Self { payload })
+        let payload = Self::strip_padding(&body)?;
+        Ok(Self { payload: payload.to_vec() })
     }
 
     /// Write a packet to a writer (unencrypted, for initial phases)
     pub fn write(&self, writer: &mut dyn Write) -> Result<()> {
-        // Calculate padding length (4-8 bytes for simplicity)
-        let padding_length = 8 - (self.payload.len() % 8);
-        let padding: Vec<u8> = vec![0u8; padding_length];
-        
-        let packet_length = (self.payload.len() + padding_length + 1) as u32;
-        
-        // Write packet length
-        writer.write_all(&packet_length.to_be_bytes())
-            .context("Failed to write packet length")?;
-        
-        // Write padding length
-        writer.write_all(&[padding_length as u8])
-            .context("Failed to write padding length")?;
-        
-        // Write payload
-        writer.write_all(&self.payload)
-            .context("Failed to write payload")?;
-        
-        // Write padding
-        writer.write_all(&padding)
-            .context("Failed to write padding")?;
-        
+        self.write_aligned(writer, MIN_BLOCK_SIZE)
+    }
+
+    /// Write a packet padded to a multiple of `block_size`
+    pub fn write_aligned(&self, writer: &mut dyn Write, block_size: usize) -> Result<()> {
+        let frame = self.to_frame(block_size)?;
+
+        writer.write_all(&frame)
+            .context("Failed to write packet")?;
         writer.flush()
             .context("Failed to flush writer")?;
-        
+
         Ok(())
     }
+
+    /// Build `packet_length || padding_length || payload || random padding`
+    /// for a packet sent without a MAC
+    pub fn to_frame(&self, block_size: usize) -> Result<Vec<u8>> {
+        self.to_frame_with(block_size, Alignment::WithLength, 0)
+    }
+
+    /// Build a frame whose `alignment` part is a multiple of `block_size`;
+    /// `mac_length` bytes of MAC or tag will follow it on the wire
+    pub fn to_frame_with(&self, block_size: usize, alignment: Alignment, mac_length: usize) -> Result<Vec<u8>> {
+        let block_size = block_size.max(MIN_BLOCK_SIZE);
+        let padding_length = padding_length_for(self.payload.len(), block_size, alignment);
+        let packet_length = 1 + self.payload.len() + padding_length;
+
+        if LENGTH_FIELD_SIZE + packet_length + mac_length > MAX_PACKET_SIZE {
+            anyhow::bail!(
+                "Payload too large: {} bytes exceeds maximum packet size of {}",
+                self.payload.len(),
+                MAX_PACKET_SIZE
+            );
+        }
+
+        let mut padding = vec![0u8; padding_length];
+        SystemRandom::new().fill(&mut padding)
+            .map_err(|_| anyhow::anyhow!("Failed to generate random padding"))?;
+
+        let mut frame = Vec::with_capacity(LENGTH_FIELD_SIZE + packet_length);
+        frame.extend_from_slice(&(packet_length as u32).to_be_bytes());
+        frame.push(padding_length as u8);
+        frame.extend_from_slice(&self.payload);
+        frame.extend_from_slice(&padding);
+        Ok(frame)
+    }
+
+    /// Extract the payload from `padding_length || payload || random padding`
+    pub fn strip_padding(body: &[u8]) -> Result<&[u8]> {
+        let (&padding_length, rest) = body.split_first()
+            .context("Packet has no padding length")?;
+        let padding_length = padding_length as usize;
+
+        if padding_length < MIN_PADDING_LENGTH {
+            anyhow::bail!("Invalid padding length {}: minimum is {}", padding_length, MIN_PADDING_LENGTH);
+        }
+        if padding_length > rest.len() {
+            anyhow::bail!(
+                "Invalid padding length {} for packet of {} bytes",
+                padding_length,
+                body.len()
+            );
+        }
+
+        Ok(&rest[..rest.len() - padding_length])
+    }
+}
+
+/// Number of padding bytes needed so that the aligned part of the packet is a
+/// multiple of `block_size`, with at least `MIN_PADDING_LENGTH` bytes and room
+/// for the minimum packet size
+fn padding_length_for(payload_length: usize, block_size: usize, alignment: Alignment) -> usize {
+    let aligned = alignment.prefix_length() + 1 + payload_length;
+    let mut padding_length = block_size - aligned % block_size;
+    if padding_length < MIN_PADDING_LENGTH {
+        padding_length += block_size;
+    }
+    let unpadded = LENGTH_FIELD_SIZE + 1 + payload_length;
+    while unpadded + padding_length < MIN_PACKET_SIZE.max(block_size) {
+        padding_length += block_size;
+    }
+    padding_length
+}
+
+/// Reject `packet_length` values that cannot belong to a well-formed packet
+/// followed by `mac_length` bytes of MAC, before allocating a buffer for them
+pub fn check_packet_length(
+    packet_length: usize,
+    block_size: usize,
+    alignment: Alignment,
+    mac_length: usize,
+) -> Result<()> {
+    let block_size = block_size.max(MIN_BLOCK_SIZE);
+    let unauthenticated = LENGTH_FIELD_SIZE + packet_length;
+    let total = unauthenticated + mac_length;
+
+    if total > MAX_PACKET_SIZE {
+        anyhow::bail!("Packet too large: {} bytes (maximum {})", total, MAX_PACKET_SIZE);
+    }
+    if unauthenticated < MIN_PACKET_SIZE {
+        anyhow::bail!("Packet too small: {} bytes (minimum {})", unauthenticated, MIN_PACKET_SIZE);
+    }
+    let aligned = alignment.prefix_length() + packet_length;
+    if !aligned.is_multiple_of(block_size) {
+        anyhow::bail!(
+            "Packet length {} is not a multiple of the block size {}",
+            aligned,
+            block_size
+        );
+    }
+    Ok(())
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_are_padded_and_aligned() {
+        for (block_size, alignment) in [
+            (8, Alignment::WithLength),
+            (16, Alignment::WithLength),
+            (8, Alignment::AfterLength),
+            (16, Alignment::AfterLength),
+        ] {
+            for payload_length in 0..80 {
+                let payload = vec![0xab; payload_length];
+                let frame = Packet::new(payload.clone()).to_frame_with(block_size, alignment, 0).unwrap();
+
+                let packet_length = u32::from_be_bytes(frame[..4].try_into().unwrap()) as usize;
+                assert_eq!(packet_length, frame.len() - 4);
+                let padding_length = frame[4] as usize;
+                assert!((MIN_PADDING_LENGTH..=255).contains(&padding_length), "{}", padding_length);
+                assert!(frame.len() >= MIN_PACKET_SIZE);
+                let aligned = match alignment {
+                    Alignment::WithLength => frame.len(),
+                    Alignment::AfterLength => frame.len() - 4,
+                };
+                assert_eq!(aligned % block_size, 0, "{:?} {} {}", alignment, block_size, payload_length);
+
+                check_packet_length(packet_length, block_size, alignment, 0).unwrap();
+                assert_eq!(Packet::strip_padding(&frame[4..]).unwrap(), payload);
+            }
+        }
+    }
+
+    #[test]
+    fn packets_round_trip_through_a_stream() {
+        let mut wire = Vec::new();
+        Packet::new(b"hello".to_vec()).write(&mut wire).unwrap();
+        Packet::new(Vec::new()).write(&mut wire).unwrap();
+        let mut reader = wire.as_slice();
+        assert_eq!(Packet::read(&mut reader).unwrap().payload, b"hello");
+        assert!(Packet::read(&mut reader).unwrap().payload.is_empty());
+        assert!(reader.is_empty());
+    }
+
+    #[test]
+    fn packet_lengths_are_checked() {
+        // 16 bytes is the smallest packet, and it must be block aligned
+        assert!(check_packet_length(8, 8, Alignment::WithLength, 0).is_err());
+        check_packet_length(12, 8, Alignment::WithLength, 0).unwrap();
+        assert!(check_packet_length(13, 8, Alignment::WithLength, 0).is_err());
+        check_packet_length(16, 16, Alignment::AfterLength, 16).unwrap();
+        assert!(check_packet_length(20, 16, Alignment::AfterLength, 16).is_err());
+        assert!(check_packet_length(20, 16, Alignment::WithLength, 0).is_err());
+
+        // The limit counts the MAC
+        check_packet_length(MAX_PACKET_SIZE - 4, 8, Alignment::WithLength, 0).unwrap();
+        assert!(check_packet_length(MAX_PACKET_SIZE - 4, 8, Alignment::WithLength, 16).is_err());
+        assert!(check_packet_length(MAX_PACKET_SIZE + 4, 8, Alignment::WithLength, 0).is_err());
+        assert!(check_packet_length(u32::MAX as usize, 8, Alignment::WithLength, 0).is_err());
+
+        let large = Packet::new(vec![0; 34980]);
+        assert_eq!(large.to_frame_with(8, Alignment::WithLength, 0).unwrap().len(), 34992);
+        assert!(large.to_frame_with(8, Alignment::WithLength, 16).is_err());
+        assert!(Packet::new(vec![0; MAX_PACKET_SIZE]).to_frame(8).is_err());
+    }
+
+    #[test]
+    fn padding_is_checked() {
+        assert!(Packet::strip_padding(&[]).is_err());
+        // Fewer than 4 padding bytes
+        assert!(Packet::strip_padding(&[3, 1, 2, 3, 4, 5, 6, 7]).is_err());
+        // More padding than packet
+        assert!(Packet::strip_padding(&[8, 1, 2, 3, 4, 5, 6, 7]).is_err());
+        assert_eq!(Packet::strip_padding(&[4, 1, 2, 3, 0, 0, 0, 0]).unwrap(), [1, 2, 3]);
+    }
+}
@@ -0,0 +1,314 @@
+use anyhow::{Result, Context};
+
+/// Encoding of a message into SSH wire format (RFC 4251 §5)
+pub trait Encode {
+    /// Append the wire representation of `self` to `writer`
+    fn encode(&self, writer: &mut Writer);
+
+    /// Encode into a fresh buffer
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = Writer::new();
+        self.encode(&mut writer);
+        writer.into_bytes()
+    }
+}
+
+/// Decoding of a message from SSH wire format (RFC 4251 §5)
+pub trait Decode: Sized {
+    /// Read a value from the current position of `reader`
+    fn decode(reader: &mut Reader<'_>) -> Result<Self>;
+
+    /// Decode a complete buffer, rejecting trailing bytes
+    fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let mut reader = Reader::new(bytes);
+        let value = Self::decode(&mut reader)?;
+        reader.finish()?;
+        Ok(value)
+    }
+}
+
+/// Bounds-checked cursor over an SSH-encoded buffer
+pub struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    /// Create a reader positioned at the start of `buf`
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    /// Number of bytes not yet consumed
+    pub fn remaining(&self) -> usize {
+        self.buf.len() - self.pos
+    }
+
+    /// Whether the whole buffer has been consumed
+    pub fn is_empty(&self) -> bool {
+        self.remaining() == 0
+    }
+
+    /// Fail if any bytes are left unread
+    pub fn finish(&self) -> Result<()> {
+        if !self.is_empty() {
+            anyhow::bail!("Unexpected {} trailing bytes in message", self.remaining());
+        }
+        Ok(())
+    }
+
+    /// Read `n` raw bytes
+    pub fn read_bytes(&mut self, n: usize) -> Result<&'a [u8]> {
+        if n > self.remaining() {
+            anyhow::bail!(
+                "Message truncated: need {} bytes, only {} remaining",
+                n,
+                self.remaining()
+            );
+        }
+        let bytes = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    /// Read the rest of the buffer
+    pub fn read_rest(&mut self) -> &'a [u8] {
+        let bytes = &self.buf[self.pos..];
+        self.pos = self.buf.len();
+        bytes
+    }
+
+    /// Read a `byte`
+    pub fn read_byte(&mut self) -> Result<u8> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    /// Read a `boolean` (any non-zero value is true)
+    pub fn read_bool(&mut self) -> Result<bool> {
+        Ok(self.read_byte()? != 0)
+    }
+
+    /// Read a `uint32`
+    pub fn read_u32(&mut self) -> Result<u32> {
+        let bytes = self.read_bytes(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// Read a `uint64`
+    pub fn read_u64(&mut self) -> Result<u64> {
+        let bytes = self.read_bytes(8)?;
+        let mut array = [0u8; 8];
+        array.copy_from_slice(bytes);
+        Ok(u64::from_be_bytes(array))
+    }
+
+    /// Read a `string` as raw bytes
+    pub fn read_string(&mut self) -> Result<&'a [u8]> {
+        let length = self.read_u32()? as usize;
+        self.read_bytes(length)
+            .context("Invalid string length")
+    }
+
+    /// Read a `string` that must be valid UTF-8
+    pub fn read_utf8(&mut self) -> Result<String> {
+        let bytes = self.read_string()?;
+        String::from_utf8(bytes.to_vec())
+            .context("Invalid UTF-8 in string")
+    }
+
+    /// Read a non-negative `mpint`, returning its big-endian magnitude
+    /// without leading zero bytes
+    pub fn read_mpint(&mut self) -> Result<Vec<u8>> {
+        let bytes = self.read_string()?;
+        if bytes.first().is_some_and(|&b| b & 0x80 != 0) {
+            anyhow::bail!("Negative mpint not supported");
+        }
+        let start = bytes.iter().position(|&b| b != 0).unwrap_or(bytes.len());
+        Ok(bytes[start..].to_vec())
+    }
+
+    /// Read a `name-list`
+    pub fn read_name_list(&mut self) -> Result<Vec<String>> {
+        let bytes = self.read_string()?;
+        if bytes.is_empty() {
+            return Ok(Vec::new());
+        }
+        if !bytes.is_ascii() {
+            anyhow::bail!("Non-ASCII characters in name-list");
+        }
+        let list = std::str::from_utf8(bytes)
+            .context("Invalid name-list")?;
+        let names: Vec<String> = list.split(',').map(str::to_string).collect();
+        if names.iter().any(|name| name.is_empty()) {
+            anyhow::bail!("Empty name in name-list: {:?}", list);
+        }
+        Ok(names)
+    }
+}
+
+/// Growable buffer for building SSH-encoded data
+#[derive(Default)]
+pub struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    /// Create an empty writer
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Consume the writer and return the encoded bytes
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+
+    /// Append raw bytes without a length prefix
+    pub fn write_raw(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Append a `byte`
+    pub fn write_byte(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+
+    /// Append a `boolean`
+    pub fn write_bool(&mut self, value: bool) {
+        self.buf.push(value as u8);
+    }
+
+    /// Append a `uint32`
+    pub fn write_u32(&mut self, value: u32) {
+        self.buf.extend_from_slice(&value.to_be_bytes());
+    }
+
+    /// Append a `uint64`
+    pub fn write_u64(&mut self, value: u64) {
+        self.buf.extend_from_slice(&value.to_be_bytes());
+    }
+
+    /// Append a `string`
+    pub fn write_string(&mut self, value: &[u8]) {
+        self.write_u32(value.len() as u32);
+        self.buf.extend_from_slice(value);
+    }
+
+    /// Append a non-negative `mpint` given its big-endian magnitude
+    pub fn write_mpint(&mut self, magnitude: &[u8]) {
+        let start = magnitude.iter().position(|&b| b != 0).unwrap_or(magnitude.len());
+        let magnitude = &magnitude[start..];
+        if magnitude.first().is_some_and(|&b| b & 0x80 != 0) {
+            // Leading zero keeps the value positive in two's complement
+            self.write_u32(magnitude.len() as u32 + 1);
+            self.buf.push(0);
+            self.buf.extend_from_slice(magnitude);
+        } else {
+            self.write_string(magnitude);
+        }
+    }
+
+    /// Append a `name-list`
+    pub fn write_name_list<S: AsRef<str>>(&mut self, names: &[S]) {
+        let list = names.iter()
+            .map(|name| name.as_ref())
+            .collect::<Vec<_>>()
+            .join(",");
+        self.write_string(list.as_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(write: impl FnOnce(&mut Writer)) -> Vec<u8> {
+        let mut writer = Writer::new();
+        write(&mut writer);
+        writer.into_bytes()
+    }
+
+    #[test]
+    fn mpints_match_rfc4251_examples() {
+        // RFC 4251 §5
+        let examples: [(&[u8], &[u8]); 4] = [
+            (&[], &[0, 0, 0, 0]),
+            (
+                &[0x09, 0xa3, 0x78, 0xf9, 0xb2, 0xe3, 0x32, 0xa7],
+                &[0, 0, 0, 8, 0x09, 0xa3, 0x78, 0xf9, 0xb2, 0xe3, 0x32, 0xa7],
+            ),
+            (&[0x80], &[0, 0, 0, 2, 0, 0x80]),
+            // Leading zero bytes of the magnitude are not encoded
+            (&[0, 0, 0x7f], &[0, 0, 0, 1, 0x7f]),
+        ];
+        for (magnitude, encoded) in examples {
+            assert_eq!(encode(|writer| writer.write_mpint(magnitude)), encoded);
+            let mut reader = Reader::new(encoded);
+            let stripped: Vec<u8> = magnitude.iter().copied().skip_while(|&b| b == 0).collect();
+            assert_eq!(reader.read_mpint().unwrap(), stripped);
+            reader.finish().unwrap();
+        }
+        assert_eq!(encode(|writer| writer.write_mpint(&[0, 0])), [0, 0, 0, 0]);
+
+        // -1234 and -deadbeef: negative values never occur in SSH key exchange
+        assert!(Reader::new(&[0, 0, 0, 2, 0xed, 0xcc]).read_mpint().is_err());
+        assert!(Reader::new(&[0, 0, 0, 5, 0xff, 0x21, 0x52, 0x41, 0x11]).read_mpint().is_err());
+        // A redundant leading zero is tolerated on input
+        assert_eq!(Reader::new(&[0, 0, 0, 2, 0, 0x7f]).read_mpint().unwrap(), [0x7f]);
+    }
+
+    #[test]
+    fn strings_and_name_lists_match_rfc4251_examples() {
+        let testing = encode(|writer| writer.write_string(b"testing"));
+        assert_eq!(testing, b"\0\0\0\x07testing");
+        assert_eq!(Reader::new(&testing).read_utf8().unwrap(), "testing");
+
+        let examples: [(&[&str], &[u8]); 3] = [
+            (&[], b"\0\0\0\0"),
+            (&["zlib"], b"\0\0\0\x04zlib"),
+            (&["zlib", "none"], b"\0\0\0\x09zlib,none"),
+        ];
+        for (names, encoded) in examples {
+            assert_eq!(encode(|writer| writer.write_name_list(names)), encoded);
+            assert_eq!(Reader::new(encoded).read_name_list().unwrap(), names);
+        }
+        assert!(Reader::new(b"\0\0\0\x05zlib,").read_name_list().is_err());
+        assert!(Reader::new(b"\0\0\0\x02\xc3\xa9").read_name_list().is_err());
+    }
+
+    #[test]
+    fn integers_round_trip() {
+        let bytes = encode(|writer| {
+            writer.write_byte(7);
+            writer.write_bool(true);
+            writer.write_u32(0x2993_44d8);
+            writer.write_u64(u64::MAX - 1);
+        });
+        assert_eq!(bytes[..6], [7, 1, 0x29, 0x93, 0x44, 0xd8]);
+        let mut reader = Reader::new(&bytes);
+        assert_eq!(reader.read_byte().unwrap(), 7);
+        assert!(reader.read_bool().unwrap());
+        assert_eq!(reader.read_u32().unwrap(), 0x2993_44d8);
+        assert_eq!(reader.read_u64().unwrap(), u64::MAX - 1);
+        reader.finish().unwrap();
+        // Any non-zero boolean is true
+        assert!(Reader::new(&[2]).read_bool().unwrap());
+    }
+
+    #[test]
+    fn truncated_input_is_an_error() {
+        assert!(Reader::new(&[]).read_byte().is_err());
+        assert!(Reader::new(&[0, 0, 1]).read_u32().is_err());
+        assert!(Reader::new(&[0; 7]).read_u64().is_err());
+        // The length promises more bytes than there are
+        assert!(Reader::new(&[0, 0, 0, 5, b'a', b'b']).read_string().is_err());
+        assert!(Reader::new(&[0xff, 0xff, 0xff, 0xff]).read_string().is_err());
+        assert!(Reader::new(&[0, 0, 0, 2, 0]).read_mpint().is_err());
+
+        let mut reader = Reader::new(&[0, 0, 0, 1, b'x', 9]);
+        assert_eq!(reader.read_string().unwrap(), b"x");
+        assert!(reader.finish().is_err());
+        assert_eq!(reader.read_rest(), [9]);
+        reader.finish().unwrap();
+    }
+}